@@ -1,75 +1,381 @@
 use mvccstore::mvcc::storage::create_storage_cf;
 use mvccstore::mvcc::{StorageType, MvccStorage};
-use clap::{App, Arg};
-use rocksdb::{DBOptions, ColumnFamilyOptions};
+use mvccstore::mvcc::history::HistoryRecorder;
+use mvccstore::mvcc::checker;
+use mvccstore::mvcc::backup::{self, BackupMeta};
+use mvccstore::workload::TimestampOracle;
+use mvccstore::workload::ycsb::{self, CoreWorkload};
+use mvccstore::workload::bank::{self, BankConfig};
+use mvccstore::workload::stats::{OpStats, Report, ThroughputMeter, REPORT_INTERVAL};
+use mvccstore::workload::result::{self, BenchResult, Format};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use mvccstore::config::Config;
 use std::sync::Arc;
-use rand::random;
+use std::sync::atomic::Ordering;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
 use std::thread;
-use rocksdb::rocksdb_options::u64_to_bytes;
+use std::process;
 
-const PREPARE_THREAD_NUM: usize = 4;
+// Keys written by `prepare` are the records of the YCSB workloads, so that `run` uses
+// them instead of loading its own. They are committed by a single transaction.
+const PREPARE_START_TS: u64 = 1;
+const PREPARE_COMMIT_TS: u64 = 2;
 
-fn prepare(storage: &Arc<dyn MvccStorage>, key_num: usize, seq: bool, value_size: usize) {
-    let mut sorted_kv = Vec::new();
-    for i in 0..key_num {
-        sorted_kv.push(i);
+// Split `keys` into `threads` parts of about the same size.
+fn split(keys: Vec<u64>, threads: usize) -> Vec<Vec<u64>> {
+    let threads = threads.max(1);
+    let data_size = keys.len() / threads;
+    let mut parts = Vec::new();
+    for i in 0..threads {
+        let cursor = i * data_size;
+        if i + 1 == threads {
+            parts.push(keys[cursor..].to_vec());
+        } else {
+            parts.push(keys[cursor..(i + 1) * data_size].to_vec());
+        }
     }
+    parts
+}
+
+fn prepare(storage: &Arc<dyn MvccStorage>, key_num: u64, seq: bool, value_size: usize, threads: usize) -> Report {
+    let mut sorted_kv: Vec<u64> = (0..key_num).collect();
     if !seq {
-        for j in 0..key_num {
-            let i = random();
-            let j = random();
-            if i != j {
-                sorted_kv.swap(i, j);
-            }
-        }
+        sorted_kv.shuffle(&mut thread_rng());
     }
+    let meter = ThroughputMeter::start(REPORT_INTERVAL);
+    let begin = Instant::now();
     let mut handlers = Vec::default();
-    for i in 0..PREPARE_THREAD_NUM {
-        let data_size = (sorted_kv.len() / PREPARE_THREAD_NUM);
-        let cursor = i * data_size;
-        let data = if i + 1 == PREPARE_THREAD_NUM {
-            sorted_kv[cursor..].to_vec()
-        } else {
-            sorted_kv[cursor..(i + 1) * data_size].to_vec()
-        };
+    for (i, data) in split(sorted_kv, threads).into_iter().enumerate() {
         let store = storage.clone();
         let value = vec![1 as u8; value_size];
+        let done = meter.counter();
         let handle = thread::spawn(move || {
             println!("{} begin write {} keys", i, data.len());
+            let mut stats = OpStats::default();
             for j in data {
-                let key = u64_to_bytes(j as u64);
-                if store.prewrite(&key, &value, 1).is_ok() {
-                    store.commit(&key, 1, 2);
-                }
+                let key = ycsb::build_key(j);
+                let write_begin = Instant::now();
+                let ret = store
+                    .prewrite(&key, &value, PREPARE_START_TS)
+                    .and_then(|_| store.commit(&key, PREPARE_START_TS, PREPARE_COMMIT_TS));
+                stats.record(write_begin.elapsed(), &ret);
+                done.fetch_add(1, Ordering::Relaxed);
             }
             println!("{} end write keys", i);
+            stats
         });
         handlers.push(handle);
     }
+    let mut stats = OpStats::default();
     for h in handlers.into_iter() {
-        h.join();
+        stats.merge(&h.join().unwrap());
     }
+    let elapsed = begin.elapsed();
+    let (interval, windows) = meter.finish();
     println!("end prepare keys");
+    Report {
+        name: String::from("prepare"),
+        threads: threads.max(1),
+        elapsed,
+        ops: vec![("INSERT", stats)],
+        interval,
+        windows,
+    }
 }
 
-fn main() {
-   let matches = App::new("MvccStore")
+// Write the keys of `prepare` into SST files of `batch_size` keys and ingest them,
+// every ingested file counts as an operation.
+fn bulk_load(storage: &Arc<dyn MvccStorage>, key_num: u64, value_size: usize, threads: usize, batch_size: usize) -> Report {
+    // Keys are ordered as their numbers, so that the keys of every file are sorted
+    // and files of different threads do not overlap.
+    let sorted_kv: Vec<u64> = (0..key_num).collect();
+    let meter = ThroughputMeter::start(REPORT_INTERVAL);
+    let begin = Instant::now();
+    let mut handlers = Vec::default();
+    for (i, data) in split(sorted_kv, threads).into_iter().enumerate() {
+        let store = storage.clone();
+        let value = vec![1 as u8; value_size];
+        let done = meter.counter();
+        let handle = thread::spawn(move || {
+            println!("{} begin load {} keys", i, data.len());
+            let mut stats = OpStats::default();
+            for batch in data.chunks(batch_size.max(1)) {
+                let kvs: Vec<(Vec<u8>, Vec<u8>)> = batch.iter().map(|j| (ycsb::build_key(*j), value.clone())).collect();
+                let load_begin = Instant::now();
+                let ret = store.bulk_load(&kvs, PREPARE_START_TS, PREPARE_COMMIT_TS);
+                stats.record(load_begin.elapsed(), &ret);
+                done.fetch_add(1, Ordering::Relaxed);
+            }
+            println!("{} end load keys", i);
+            stats
+        });
+        handlers.push(handle);
+    }
+    let mut stats = OpStats::default();
+    for h in handlers.into_iter() {
+        stats.merge(&h.join().unwrap());
+    }
+    let elapsed = begin.elapsed();
+    let (interval, windows) = meter.finish();
+    println!("end load {} keys in {:?}", key_num, elapsed);
+    Report {
+        name: String::from("prepare"),
+        threads: threads.max(1),
+        elapsed,
+        ops: vec![("BULK_LOAD", stats)],
+        interval,
+        windows,
+    }
+}
+
+// Return the number of keys written by `prepare` which are missing or have a wrong value.
+fn verify(storage: &Arc<dyn MvccStorage>, key_num: u64, value_size: usize, threads: usize) -> u64 {
+    let mut handlers = Vec::default();
+    for data in split((0..key_num).collect(), threads).into_iter() {
+        let store = storage.clone();
+        let handle = thread::spawn(move || {
+            let mut bad = 0;
+            for j in data {
+                let key = ycsb::build_key(j);
+                let ret = store.get(&key, PREPARE_COMMIT_TS);
+                let ok = match ret {
+                    Ok(Some(ref v)) => v.len() == value_size && v.iter().all(|b| *b == 1),
+                    _ => false,
+                };
+                if !ok {
+                    if bad < 10 {
+                        println!("key {} is wrong: {:?}", j, ret.map(|v| v.map(|v| v.len())));
+                    }
+                    bad += 1;
+                }
+            }
+            bad
+        });
+        handlers.push(handle);
+    }
+    handlers.into_iter().map(|h| h.join().unwrap()).sum()
+}
+
+// Returns false if the recorded history is not snapshot isolated.
+fn check_history(recorder: &Option<Arc<HistoryRecorder>>) -> bool {
+    match recorder {
+        Some(r) => {
+            let report = checker::check(&r.history());
+            report.print();
+            report.is_snapshot_isolation()
+        }
+        None => true,
+    }
+}
+
+fn value_of<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    let v = matches.value_of(name).unwrap();
+    match v.parse() {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("invalid value {} of --{}", v, name);
+            process::exit(1);
+        }
+    }
+}
+
+// Print the space and write amplification of a run, and save its result if `--output` is given.
+fn save_result(matches: &ArgMatches, storage: &Arc<dyn MvccStorage>, storage_type: &str, path: &str, report: &Report, params: BTreeMap<String, String>, rocksdb_options: &BTreeMap<String, String>) {
+    let stats = match storage.stats() {
+        Ok(stats) => {
+            stats.print();
+            Some(stats)
+        }
+        Err(e) => {
+            eprintln!("failed to collect storage stats: {}", e);
+            None
+        }
+    };
+    let output = match matches.value_of("output") {
+        Some(output) => output,
+        None => return,
+    };
+    let format = Format::from_name(matches.value_of("format").unwrap()).unwrap();
+    let space = result::dir_size(Path::new(path));
+    let result = BenchResult::new(storage_type, report, params, rocksdb_options.clone(), space, stats);
+    if let Err(e) = result.save(output, format) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    println!("result saved to {}", output);
+}
+
+fn compare(matches: &ArgMatches) {
+    let mut results = vec![];
+    for file in matches.values_of("files").unwrap() {
+        match BenchResult::load(file) {
+            Ok(r) => results.push(r),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    print!("{}", result::compare(&results));
+}
+
+fn load_config(matches: &ArgMatches) -> Config {
+    let config = match matches.value_of("config") {
+        Some(file) => Config::from_file(file),
+        None => Ok(Config::default()),
+    };
+    match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Restore a backup into the db path, which must be empty, as the type which saved it.
+fn restore(matches: &ArgMatches, m: &ArgMatches) {
+    let path = match matches.value_of("path") {
+        Some(path) => path,
+        None => {
+            eprintln!("--path is required\n{}", matches.usage());
+            process::exit(1);
+        }
+    };
+    let dir = m.value_of("dir").unwrap();
+    if let Some(t) = matches.value_of("type") {
+        match BackupMeta::load(Path::new(dir)) {
+            Ok(ref meta) if meta.storage_type != t => {
+                eprintln!("{} is a backup of {}, not {}", dir, meta.storage_type, t);
+                process::exit(1);
+            }
+            _ => (),
+        }
+    }
+    let begin = Instant::now();
+    match backup::restore(Path::new(dir), path, &load_config(matches)) {
+        Ok((storage_type, _)) => {
+            println!("restored {} storage from {} in {:?}", storage_type.name(), dir, begin.elapsed());
+        }
+        Err(e) => {
+            eprintln!("restore failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Returns the report of the workload, its parameters and whether no anomaly was found.
+fn run(storage: Arc<dyn MvccStorage>, matches: &ArgMatches) -> (Report, BTreeMap<String, String>, bool) {
+    let recorder = if matches.is_present("check-history") {
+        Some(Arc::new(HistoryRecorder::new(storage.clone())))
+    } else {
+        None
+    };
+    let storage: Arc<dyn MvccStorage> = match recorder {
+        Some(ref r) => r.clone(),
+        None => storage,
+    };
+    let name = matches.value_of("workload").unwrap();
+    let threads: usize = value_of(matches, "threads");
+    let duration = matches
+        .value_of("duration")
+        .map(|_| Duration::from_secs(value_of(matches, "duration")));
+    // Start after the data of earlier runs, or of `prepare`, so that no write goes
+    // below a committed version.
+    let tso = match storage.max_ts() {
+        Ok(ts) => Arc::new(TimestampOracle::new(ts)),
+        Err(e) => {
+            eprintln!("failed to read the max ts of the storage: {}", e);
+            process::exit(1);
+        }
+    };
+    if name == "bank" {
+        let config = BankConfig {
+            accounts: value_of(matches, "accounts"),
+            threads,
+            duration: duration.unwrap_or(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        println!("========begin load bank accounts");
+        if let Err(e) = bank::load(&storage, &config, &tso) {
+            eprintln!("load failed: {}", e);
+            process::exit(1);
+        }
+        println!("========begin run bank workload");
+        let report = match bank::run(&storage, &config, &tso) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("bank workload failed: {}", e);
+                process::exit(1);
+            }
+        };
+        report.print();
+        let ok = report.anomalies.is_empty() && check_history(&recorder);
+        return (report.stats, config.params(), ok);
+    }
+    let mut workload = CoreWorkload::from_name(name).unwrap();
+    workload.record_count = value_of(matches, "records");
+    workload.operation_count = value_of(matches, "operations");
+    workload.batch_read_size = value_of(matches, "batch-read-size");
+    workload.max_execution_time = duration;
+    println!("========begin load workload {}", name);
+    match ycsb::load(&storage, &workload, threads, &tso) {
+        Ok(written) => println!("{} of {} records written", written, workload.record_count),
+        Err(e) => {
+            eprintln!("load failed: {}", e);
+            process::exit(1);
+        }
+    }
+    println!("========begin run workload {}", name);
+    let report = ycsb::run(&storage, &workload, threads, &tso);
+    report.print();
+    let ok = check_history(&recorder);
+    (report, workload.params(), ok)
+}
+
+fn app() -> App<'static, 'static> {
+    let keys_arg = Arg::with_name("keys")
+        .long("keys")
+        .takes_value(true)
+        .value_name("N")
+        .default_value("100000")
+        .help("Set the number of keys written by prepare");
+    let value_size_arg = Arg::with_name("value-size")
+        .long("value-size")
+        .takes_value(true)
+        .value_name("BYTES")
+        .default_value("128")
+        .help("Set the size of values written by prepare");
+    let threads_arg = Arg::with_name("threads")
+        .long("threads")
+        .takes_value(true)
+        .value_name("N")
+        .default_value("4")
+        .help("Set the number of worker threads");
+    let output_arg = Arg::with_name("output")
+        .long("output")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("Save the result to the file");
+    let format_arg = Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["json", "csv"])
+        .default_value("json")
+        .help("Set the format of the saved result, compare reads json only");
+    App::new("MvccStore")
         .about("A toy storage, used to compare different mvcc storage models.")
         .arg(
             Arg::with_name("path")
                 .short("p")
                 .long("path")
                 .value_name("PATH")
-                .help("set the db path")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("work")
-                .value_name("work")
-                .help("prepare or read")
-                .possible_values(&[
-                    "user_timestamp", "tikv",
-                ])
+                .help("set the db path, required by all subcommands but compare")
                 .takes_value(true),
         )
         .arg(
@@ -81,23 +387,369 @@ fn main() {
                 .possible_values(&[
                     "user_timestamp", "tikv",
                 ])
-                .help("Set the storage type"),
-        ).get_matches();
-    let path = matches.value_of("path").unwrap();
-    let db_type_str = matches.value_of("type").unwrap();
-    let storage_type = match db_type_str {
-        "user_timestamp" => StorageType::UserTimestampStorage,
-        "tikv" => StorageType::TiKVStorage,
-        _ => StorageType::Unknown
+                .help("Set the storage type, required by all subcommands but compare and restore"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("C")
+                .long("config")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Set the TOML config of the storage and rocksdb"),
+        )
+        .subcommand(
+            SubCommand::with_name("prepare")
+                .about("Write keys with values of a fixed size")
+                .arg(keys_arg.clone())
+                .arg(value_size_arg.clone())
+                .arg(threads_arg.clone())
+                .arg(
+                    Arg::with_name("random")
+                        .long("random")
+                        .help("Write keys in random order instead of sequentially"),
+                )
+                .arg(
+                    Arg::with_name("bulk-load")
+                        .long("bulk-load")
+                        .conflicts_with("random")
+                        .help("Ingest keys as committed versions in SST files instead of writing them by transactions"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("100000")
+                        .help("Set the number of keys of every SST file ingested by --bulk-load"),
+                )
+                .arg(output_arg.clone())
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run a YCSB core workload or the bank transfer workload")
+                .arg(
+                    Arg::with_name("workload")
+                        .value_name("WORKLOAD")
+                        .possible_values(&["a", "b", "c", "d", "e", "f", "bank"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("records")
+                        .long("records")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("100000")
+                        .help("Set the number of records loaded before the workload"),
+                )
+                .arg(
+                    Arg::with_name("operations")
+                        .long("operations")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("100000")
+                        .help("Set the number of operations of the workload"),
+                )
+                .arg(
+                    Arg::with_name("batch-read-size")
+                        .long("batch-read-size")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1")
+                        .help("Set the number of keys read by one read operation of a YCSB workload"),
+                )
+                .arg(threads_arg.clone())
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .help("Stop the workload after the duration, the bank workload runs 60 seconds by default"),
+                )
+                .arg(
+                    Arg::with_name("accounts")
+                        .long("accounts")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1000")
+                        .help("Set the number of accounts of the bank workload"),
+                )
+                .arg(
+                    Arg::with_name("check-history")
+                        .long("check-history")
+                        .help("Record the history of the workload and check it for snapshot isolation anomalies"),
+                )
+                .arg(output_arg)
+                .arg(format_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that all keys written by prepare can be read")
+                .arg(keys_arg)
+                .arg(value_size_arg)
+                .arg(threads_arg),
+        )
+        .subcommand(SubCommand::with_name("compact").about("Compact all data"))
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Save a consistent copy of the storage into a directory, which must not exist")
+                .arg(Arg::with_name("dir").value_name("DIR").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Copy a backup into the db path, which must be empty, and open it as the type which saved it")
+                .arg(Arg::with_name("dir").value_name("DIR").required(true)),
+        )
+        .subcommand(SubCommand::with_name("stats").about("Print statistics of the engine, space and write amplification"))
+        .subcommand(
+            SubCommand::with_name("versions")
+                .about("Print the committed versions of a key, the newest first, and its lock")
+                .arg(Arg::with_name("key").value_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("num")
+                        .long("num")
+                        .help("Read KEY as a number, whose key is the one written by prepare"),
+                )
+                .arg(
+                    Arg::with_name("from-ts")
+                        .long("from-ts")
+                        .takes_value(true)
+                        .value_name("TS")
+                        .default_value("0")
+                        .help("Set the smallest commit ts of the versions"),
+                )
+                .arg(
+                    Arg::with_name("to-ts")
+                        .long("to-ts")
+                        .takes_value(true)
+                        .value_name("TS")
+                        .default_value("18446744073709551615")
+                        .help("Set the largest commit ts of the versions"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("100")
+                        .help("Set the max number of versions"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("flashback")
+                .about("Write a new version at COMMIT_TS for every key in START..END which changed after TARGET_TS, restoring its version at TARGET_TS")
+                .arg(Arg::with_name("start").value_name("START").required(true))
+                .arg(Arg::with_name("end").value_name("END").required(true))
+                .arg(
+                    Arg::with_name("num")
+                        .long("num")
+                        .help("Read START and END as numbers, whose keys are the ones written by prepare"),
+                )
+                .arg(
+                    Arg::with_name("target-ts")
+                        .long("target-ts")
+                        .takes_value(true)
+                        .value_name("TS")
+                        .required(true)
+                        .help("Set the ts to flash back to"),
+                )
+                .arg(
+                    Arg::with_name("commit-ts")
+                        .long("commit-ts")
+                        .takes_value(true)
+                        .value_name("TS")
+                        .required(true)
+                        .help("Set the commit ts of the versions written, larger than any ts used before"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("Print throughput, latency and space of results saved in json, relative to the first one of each workload")
+                .arg(
+                    Arg::with_name("files")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("compare", Some(m)) => return compare(m),
+        ("restore", Some(m)) => return restore(&matches, m),
+        _ => (),
+    }
+    let (path, db_type_str) = match (matches.value_of("path"), matches.value_of("type")) {
+        (Some(path), Some(db_type)) => (path, db_type),
+        _ => {
+            eprintln!("--path and --type are required\n{}", matches.usage());
+            process::exit(1);
+        }
     };
-    let mut options = DBOptions::default();
-    options.create_if_missing(true);
-    options.allow_concurrent_memtable_write(true);
-    options.set_writable_file_max_buffer_size(8 * 1024 * 1024);
-    let mut cf = ColumnFamilyOptions::new();
-    cf.set_write_buffer_size(2 * 1024 * 1024);
-    let storage = create_storage_cf(path, storage_type, options, vec![("default", cf),]).unwrap();
-    println!("========begin prepare data");
-    prepare(&storage, 100000, true, 128);
-    println!("========end prepare data");
+    let storage_type = StorageType::from_name(db_type_str);
+    let config = load_config(&matches);
+    let mut rocksdb_options = config.rocksdb_options();
+    if storage_type == StorageType::TiKVStorage {
+        // Saved with the options, it changes how many lookups a read needs.
+        rocksdb_options.insert("tikv.short-value-threshold".to_owned(), config.tikv.short_value_threshold.to_string());
+    }
+    let storage = match create_storage_cf(path, storage_type, &config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("failed to open {} storage at {}: {}", db_type_str, path, e);
+            process::exit(1);
+        }
+    };
+    match matches.subcommand() {
+        ("prepare", Some(m)) => {
+            println!("========begin prepare data");
+            let report = if m.is_present("bulk-load") {
+                bulk_load(
+                    &storage,
+                    value_of(m, "keys"),
+                    value_of(m, "value-size"),
+                    value_of(m, "threads"),
+                    value_of(m, "batch-size"),
+                )
+            } else {
+                prepare(
+                    &storage,
+                    value_of(m, "keys"),
+                    !m.is_present("random"),
+                    value_of(m, "value-size"),
+                    value_of(m, "threads"),
+                )
+            };
+            println!("========end prepare data");
+            report.print();
+            let mut params = BTreeMap::new();
+            params.insert("keys".to_owned(), m.value_of("keys").unwrap().to_owned());
+            params.insert("value_size".to_owned(), m.value_of("value-size").unwrap().to_owned());
+            params.insert("random".to_owned(), m.is_present("random").to_string());
+            if m.is_present("bulk-load") {
+                params.insert("bulk_load_batch_size".to_owned(), m.value_of("batch-size").unwrap().to_owned());
+            }
+            save_result(m, &storage, db_type_str, path, &report, params, &rocksdb_options);
+        }
+        ("run", Some(m)) => {
+            let (report, params, ok) = run(storage.clone(), m);
+            save_result(m, &storage, db_type_str, path, &report, params, &rocksdb_options);
+            if !ok {
+                process::exit(1);
+            }
+        }
+        ("verify", Some(m)) => {
+            let bad = verify(&storage, value_of(m, "keys"), value_of(m, "value-size"), value_of(m, "threads"));
+            if bad > 0 {
+                println!("{} keys are missing or wrong", bad);
+                process::exit(1);
+            }
+            println!("all keys are ok");
+        }
+        ("compact", Some(_)) => {
+            let begin = Instant::now();
+            if let Err(e) = storage.compact() {
+                eprintln!("compact failed: {}", e);
+                process::exit(1);
+            }
+            println!("compact finished in {:?}", begin.elapsed());
+        }
+        ("backup", Some(m)) => {
+            let dir = m.value_of("dir").unwrap();
+            let begin = Instant::now();
+            if let Err(e) = storage.backup(Path::new(dir)) {
+                eprintln!("backup failed: {}", e);
+                process::exit(1);
+            }
+            println!("backup saved to {} in {:?}", dir, begin.elapsed());
+        }
+        ("stats", Some(_)) => {
+            let stats = match storage.stats() {
+                Ok(stats) => stats,
+                Err(e) => {
+                    eprintln!("failed to collect storage stats: {}", e);
+                    process::exit(1);
+                }
+            };
+            if let Some(ref engine) = stats.engine {
+                println!("{}", engine);
+            }
+            stats.print();
+        }
+        ("versions", Some(m)) => {
+            let key = if m.is_present("num") {
+                ycsb::build_key(value_of(m, "key"))
+            } else {
+                m.value_of("key").unwrap().as_bytes().to_vec()
+            };
+            let result = match storage.get_versions(&key, value_of(m, "from-ts"), value_of(m, "to-ts"), value_of(m, "limit")) {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("get versions failed: {}", e);
+                    process::exit(1);
+                }
+            };
+            if let Some(lock) = result.lock {
+                let len = lock.value.map_or(0, |v| v.len());
+                println!("lock start_ts {} {:?} {} bytes", lock.start_ts, lock.lock_type, len);
+            }
+            for version in result.versions {
+                let len = version.value.map_or(0, |v| v.len());
+                println!("commit_ts {} start_ts {} {:?} {} bytes", version.commit_ts, version.start_ts, version.write_type, len);
+            }
+        }
+        ("flashback", Some(m)) => {
+            let (start, end) = if m.is_present("num") {
+                (ycsb::build_key(value_of(m, "start")), ycsb::build_key(value_of(m, "end")))
+            } else {
+                (m.value_of("start").unwrap().as_bytes().to_vec(), m.value_of("end").unwrap().as_bytes().to_vec())
+            };
+            let begin = Instant::now();
+            match storage.flashback(&start, &end, value_of(m, "target-ts"), value_of(m, "commit-ts")) {
+                Ok(n) => println!("flashback wrote {} keys in {:?}", n, begin.elapsed()),
+                Err(e) => {
+                    eprintln!("flashback failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", matches.usage());
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_prepare_then_run() {
+        for storage_type in [StorageType::UserTimestampStorage, StorageType::TiKVStorage].iter() {
+            let dir = TempDir::new("_main_prepare_then_run").unwrap();
+            let path = dir.path().to_str().unwrap();
+            let open = || create_storage_cf(path, *storage_type, &Config::default()).unwrap();
+            let report = prepare(&open(), 100, true, 16, 2);
+            assert_eq!(report.ops[0].1.total_errors(), 0);
+            // A single thread, so that no operation conflicts with another one.
+            let matches = app().get_matches_from(vec!["main", "run", "a", "--records", "100", "--operations", "200", "--threads", "1"]);
+            let m = matches.subcommand_matches("run").unwrap();
+            let tso = TimestampOracle::new(open().max_ts().unwrap());
+            // The records of `prepare` are used, the load writes none of them.
+            let workload = CoreWorkload { record_count: 100, ..CoreWorkload::workload_a() };
+            assert_eq!(ycsb::load(&open(), &workload, 1, &Arc::new(tso)).unwrap(), 0);
+            for _ in 0..2 {
+                let (report, _, ok) = run(open(), m);
+                assert!(ok);
+                for (name, stats) in report.ops.iter() {
+                    assert_eq!(stats.total_errors(), 0, "{} failed", name);
+                }
+            }
+            assert_eq!(verify(&open(), 100, 16, 2), 0);
+        }
+    }
 }
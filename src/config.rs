@@ -0,0 +1,509 @@
+///
+/// Configuration of the storages and of rocksdb, loaded from a TOML file.
+/// Every field has a default, so a file only needs the values it changes.
+///
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use rocksdb::{BlockBasedOptions, ColumnFamilyOptions, DBCompressionType, DBOptions};
+use serde::{Deserialize, Serialize};
+
+use super::util::engine::{
+    get_fastest_supported_compression_type, is_compression_supported, set_cf_compression,
+    FixedPrefixSliceTransform, FixedSuffixSliceTransform,
+};
+
+const KB: u64 = 1024;
+const MB: u64 = 1024 * KB;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemStoreKind {
+    // Locks are kept in a hash table, a scan checks every lock.
+    Hash,
+    // Locks are kept ordered by key, a scan only checks locks in its range.
+    Ordered,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MemStoreConfig {
+    pub kind: MemStoreKind,
+    // Log locks in the db directory so that they survive a restart.
+    pub enable_wal: bool,
+    // Sync the log after every append and commits to rocksdb, so that locks and
+    // committed data survive a power failure. Syncing only the log could keep the
+    // unlock of a commit and lose its data.
+    pub wal_sync: bool,
+}
+
+impl Default for MemStoreConfig {
+    fn default() -> Self {
+        Self {
+            kind: MemStoreKind::Hash,
+            enable_wal: true,
+            wal_sync: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TikvConfig {
+    // Values shorter than this are kept in the write record instead of the default
+    // column family, 0 keeps every value in the default column family.
+    pub short_value_threshold: usize,
+}
+
+impl Default for TikvConfig {
+    fn default() -> Self {
+        Self {
+            short_value_threshold: 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompressionType {
+    // The fastest algorithm supported by rocksdb.
+    Auto,
+    No,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+impl From<CompressionType> for DBCompressionType {
+    fn from(t: CompressionType) -> DBCompressionType {
+        match t {
+            CompressionType::Auto => get_fastest_supported_compression_type(),
+            CompressionType::No => DBCompressionType::No,
+            CompressionType::Snappy => DBCompressionType::Snappy,
+            CompressionType::Zlib => DBCompressionType::Zlib,
+            CompressionType::Bz2 => DBCompressionType::Bz2,
+            CompressionType::Lz4 => DBCompressionType::Lz4,
+            CompressionType::Lz4hc => DBCompressionType::Lz4hc,
+            CompressionType::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefixExtractor {
+    None,
+    // The first `prefix-extractor-len` bytes of a key.
+    FixedPrefix,
+    // A key without its last `prefix-extractor-len` bytes, such as a timestamp.
+    FixedSuffix,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CfConfig {
+    pub block_size: u64,
+    pub block_cache_size: u64,
+    pub cache_index_and_filter_blocks: bool,
+    // No bloom filter is built if it is 0.
+    pub bloom_filter_bits_per_key: i32,
+    pub block_based_bloom_filter: bool,
+    // Ignored with a prefix extractor.
+    pub whole_key_filtering: bool,
+    // Size of the prefix bloom filter of a memtable as a ratio of the write buffer size,
+    // only built with a prefix extractor. 0 disables it.
+    pub memtable_prefix_bloom_size_ratio: f64,
+    // Compression of all levels but the first `uncompressed-levels` ones.
+    pub compression: CompressionType,
+    pub num_levels: usize,
+    pub uncompressed_levels: usize,
+    // Compress the last level by zstd with a dictionary of this size, 0 to use `compression`.
+    pub bottommost_zstd_dict_size: i32,
+    pub prefix_extractor: PrefixExtractor,
+    pub prefix_extractor_len: usize,
+    pub write_buffer_size: u64,
+    pub max_write_buffer_number: i32,
+    pub max_bytes_for_level_base: u64,
+    pub target_file_size_base: u64,
+    pub level0_file_num_compaction_trigger: i32,
+    pub disable_auto_compactions: bool,
+}
+
+impl Default for CfConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 4 * KB,
+            block_cache_size: 8 * MB,
+            cache_index_and_filter_blocks: false,
+            bloom_filter_bits_per_key: 10,
+            block_based_bloom_filter: false,
+            whole_key_filtering: true,
+            memtable_prefix_bloom_size_ratio: 0.1,
+            compression: CompressionType::Auto,
+            num_levels: 7,
+            uncompressed_levels: 2,
+            bottommost_zstd_dict_size: 0,
+            prefix_extractor: PrefixExtractor::None,
+            prefix_extractor_len: 0,
+            write_buffer_size: 2 * MB,
+            max_write_buffer_number: 2,
+            max_bytes_for_level_base: 256 * MB,
+            target_file_size_base: 64 * MB,
+            level0_file_num_compaction_trigger: 4,
+            disable_auto_compactions: false,
+        }
+    }
+}
+
+impl CfConfig {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if self.block_size == 0 {
+            return Err(format!("rocksdb.{}.block-size must be positive", name));
+        }
+        if self.bloom_filter_bits_per_key < 0 {
+            return Err(format!("rocksdb.{}.bloom-filter-bits-per-key can not be negative", name));
+        }
+        if self.memtable_prefix_bloom_size_ratio < 0.0 || self.memtable_prefix_bloom_size_ratio > 0.25 {
+            return Err(format!("rocksdb.{}.memtable-prefix-bloom-size-ratio must be in [0, 0.25]", name));
+        }
+        if self.write_buffer_size == 0 {
+            return Err(format!("rocksdb.{}.write-buffer-size must be positive", name));
+        }
+        if self.max_write_buffer_number < 1 {
+            return Err(format!("rocksdb.{}.max-write-buffer-number must be at least 1", name));
+        }
+        if self.prefix_extractor != PrefixExtractor::None && self.prefix_extractor_len == 0 {
+            return Err(format!("rocksdb.{}.prefix-extractor-len must be positive", name));
+        }
+        if self.num_levels == 0 {
+            return Err(format!("rocksdb.{}.num-levels must be positive", name));
+        }
+        if !is_compression_supported(self.compression.into()) {
+            return Err(format!("rocksdb.{}.compression {:?} is not supported", name, self.compression));
+        }
+        if self.bottommost_zstd_dict_size < 0 {
+            return Err(format!("rocksdb.{}.bottommost-zstd-dict-size can not be negative", name));
+        }
+        if self.bottommost_zstd_dict_size > 0 && !is_compression_supported(DBCompressionType::Zstd) {
+            return Err(format!("rocksdb.{}.bottommost-zstd-dict-size needs zstd which is not supported", name));
+        }
+        Ok(())
+    }
+
+    pub fn build_opt(&self) -> Result<ColumnFamilyOptions, String> {
+        let mut block_base_opts = BlockBasedOptions::new();
+        block_base_opts.set_block_size(self.block_size as usize);
+        block_base_opts.set_block_cache_size(self.block_cache_size);
+        block_base_opts.set_cache_index_and_filter_blocks(self.cache_index_and_filter_blocks);
+        if self.bloom_filter_bits_per_key > 0 {
+            block_base_opts.set_bloom_filter(self.bloom_filter_bits_per_key, self.block_based_bloom_filter);
+        }
+        // Keys are filtered by their prefixes when there is a prefix extractor.
+        let whole_key_filtering = self.whole_key_filtering && self.prefix_extractor == PrefixExtractor::None;
+        block_base_opts.set_whole_key_filtering(whole_key_filtering);
+        let mut cf_opts = ColumnFamilyOptions::new();
+        cf_opts.set_block_based_table_factory(&block_base_opts);
+        let bottommost_zstd_dict_size = if self.bottommost_zstd_dict_size > 0 {
+            Some(self.bottommost_zstd_dict_size)
+        } else {
+            None
+        };
+        set_cf_compression(
+            &mut cf_opts,
+            self.compression.into(),
+            self.num_levels,
+            self.uncompressed_levels,
+            bottommost_zstd_dict_size,
+        )?;
+        match self.prefix_extractor {
+            PrefixExtractor::None => (),
+            PrefixExtractor::FixedPrefix => {
+                let f = Box::new(FixedPrefixSliceTransform::new(self.prefix_extractor_len));
+                cf_opts.set_prefix_extractor("FixedPrefixSliceTransform", f).unwrap();
+            }
+            PrefixExtractor::FixedSuffix => {
+                let f = Box::new(FixedSuffixSliceTransform::new(self.prefix_extractor_len));
+                cf_opts.set_prefix_extractor("FixedSuffixSliceTransform", f).unwrap();
+            }
+        }
+        cf_opts.set_memtable_prefix_bloom_size_ratio(self.memtable_prefix_bloom_size_ratio);
+        cf_opts.set_write_buffer_size(self.write_buffer_size);
+        cf_opts.set_max_write_buffer_number(self.max_write_buffer_number);
+        cf_opts.set_max_bytes_for_level_base(self.max_bytes_for_level_base);
+        cf_opts.set_target_file_size_base(self.target_file_size_base);
+        cf_opts.set_level_zero_file_num_compaction_trigger(self.level0_file_num_compaction_trigger);
+        cf_opts.set_disable_auto_compactions(self.disable_auto_compactions);
+        Ok(cf_opts)
+    }
+}
+
+// How models read rocksdb, each can be turned off to measure what it saves.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReadConfig {
+    // Iterators reading versions of a single key stay within the prefix of the key,
+    // so that prefix bloom filters can skip files and memtables without it.
+    pub prefix_seek: bool,
+    // Bound iterators by the end of the keys they read, so that they do not walk
+    // over tombstones past it.
+    pub iterate_upper_bound: bool,
+}
+
+impl Default for ReadConfig {
+    fn default() -> Self {
+        Self {
+            prefix_seek: true,
+            iterate_upper_bound: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DbConfig {
+    pub max_background_jobs: i32,
+    pub max_sub_compactions: u32,
+    pub max_open_files: i32,
+    pub allow_concurrent_memtable_write: bool,
+    pub enable_pipelined_write: bool,
+    pub writable_file_max_buffer_size: u64,
+    pub bytes_per_sync: u64,
+    pub wal_bytes_per_sync: u64,
+    // Needed by the space and write amplification of `MvccStorage::stats`.
+    pub enable_statistics: bool,
+    pub read: ReadConfig,
+    // Options of a column family which is not listed in `model` or `cf`.
+    pub defaultcf: CfConfig,
+    // Options of column families by name, e.g. `[rocksdb.cf.write]`.
+    pub cf: BTreeMap<String, CfConfig>,
+    // Options of column families of a single storage model, e.g. `[rocksdb.model.tikv.write]`,
+    // so that one file can set up each model differently.
+    pub model: BTreeMap<String, BTreeMap<String, CfConfig>>,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            max_background_jobs: 4,
+            max_sub_compactions: 1,
+            max_open_files: 40960,
+            allow_concurrent_memtable_write: true,
+            enable_pipelined_write: false,
+            writable_file_max_buffer_size: 8 * MB,
+            bytes_per_sync: MB,
+            wal_bytes_per_sync: 512 * KB,
+            enable_statistics: true,
+            read: ReadConfig::default(),
+            defaultcf: CfConfig::default(),
+            cf: BTreeMap::new(),
+            model: BTreeMap::new(),
+        }
+    }
+}
+
+impl DbConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_background_jobs < 1 {
+            return Err(String::from("rocksdb.max-background-jobs must be at least 1"));
+        }
+        if self.allow_concurrent_memtable_write && self.enable_pipelined_write {
+            return Err(String::from(
+                "rocksdb.allow-concurrent-memtable-write and rocksdb.enable-pipelined-write can not be both enabled",
+            ));
+        }
+        if self.writable_file_max_buffer_size > i32::max_value() as u64 {
+            return Err(String::from("rocksdb.writable-file-max-buffer-size is too large"));
+        }
+        self.defaultcf.validate("defaultcf")?;
+        for (name, cf) in self.cf.iter() {
+            cf.validate(&format!("cf.{}", name))?;
+        }
+        for (model, cfs) in self.model.iter() {
+            for (name, cf) in cfs.iter() {
+                cf.validate(&format!("model.{}.{}", model, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build_opt(&self) -> DBOptions {
+        let mut opts = DBOptions::new();
+        opts.create_if_missing(true);
+        opts.set_max_background_jobs(self.max_background_jobs);
+        opts.set_max_subcompactions(self.max_sub_compactions);
+        opts.set_max_open_files(self.max_open_files);
+        opts.allow_concurrent_memtable_write(self.allow_concurrent_memtable_write);
+        opts.enable_pipelined_write(self.enable_pipelined_write);
+        opts.set_writable_file_max_buffer_size(self.writable_file_max_buffer_size as i32);
+        opts.set_bytes_per_sync(self.bytes_per_sync);
+        opts.set_wal_bytes_per_sync(self.wal_bytes_per_sync);
+        opts.enable_statistics(self.enable_statistics);
+        opts
+    }
+
+    // The options of column family `name` of storage model `model`.
+    pub fn cf_config(&self, model: &str, name: &str) -> &CfConfig {
+        self.model
+            .get(model)
+            .and_then(|cfs| cfs.get(name))
+            .or_else(|| self.cf.get(name))
+            .unwrap_or(&self.defaultcf)
+    }
+
+    pub fn build_cf_opts<'a>(&self, model: &str, cfs: &[&'a str]) -> Result<Vec<(&'a str, ColumnFamilyOptions)>, String> {
+        let mut opts = vec![];
+        for cf in cfs.iter() {
+            opts.push((*cf, self.cf_config(model, cf).build_opt()?));
+        }
+        Ok(opts)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub memstore: MemStoreConfig,
+    pub tikv: TikvConfig,
+    pub rocksdb: DbConfig,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let data = fs::read_to_string(path).map_err(|e| format!("read {} failed: {}", path, e))?;
+        let config: Config = toml::from_str(&data).map_err(|e| format!("parse {} failed: {}", path, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memstore.wal_sync && !self.memstore.enable_wal {
+            return Err(String::from("memstore.wal-sync needs memstore.enable-wal"));
+        }
+        self.rocksdb.validate()
+    }
+
+    /// All rocksdb options as flat `section.name` keys, as saved with benchmark results.
+    pub fn rocksdb_options(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        if let Ok(value) = toml::Value::try_from(&self.rocksdb) {
+            flatten("", &value, &mut options);
+        }
+        options
+    }
+}
+
+fn flatten(prefix: &str, value: &toml::Value, out: &mut BTreeMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (k, v) in table.iter() {
+                let key = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+                flatten(&key, v, out);
+            }
+        }
+        toml::Value::String(s) => {
+            out.insert(prefix.to_owned(), s.clone());
+        }
+        v => {
+            out.insert(prefix.to_owned(), v.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config, Config::default());
+        let config: Config = toml::from_str(include_str!("../etc/config-template.toml")).unwrap();
+        assert_eq!(config, Config::default());
+
+        let data = r#"
+            [memstore]
+            kind = "ordered"
+            wal-sync = true
+
+            [tikv]
+            short-value-threshold = 0
+
+            [rocksdb]
+            max-background-jobs = 8
+
+            [rocksdb.read]
+            prefix-seek = false
+
+            [rocksdb.defaultcf]
+            write-buffer-size = 1048576
+
+            [rocksdb.cf.write]
+            compression = "no"
+            prefix-extractor = "fixed-suffix"
+            prefix-extractor-len = 8
+
+            [rocksdb.model.user_timestamp.write]
+            compression = "auto"
+            uncompressed-levels = 0
+        "#;
+        let config: Config = toml::from_str(data).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.memstore.kind, MemStoreKind::Ordered);
+        assert!(config.memstore.enable_wal && config.memstore.wal_sync);
+        assert_eq!(config.tikv.short_value_threshold, 0);
+        assert_eq!(config.rocksdb.max_background_jobs, 8);
+        assert!(!config.rocksdb.read.prefix_seek && config.rocksdb.read.iterate_upper_bound);
+        assert_eq!(config.rocksdb.writable_file_max_buffer_size, 8 * MB);
+        assert_eq!(config.rocksdb.cf_config("tikv", "default").write_buffer_size, MB);
+        let write = config.rocksdb.cf_config("tikv", "write");
+        assert_eq!(write.compression, CompressionType::No);
+        assert_eq!(write.prefix_extractor, PrefixExtractor::FixedSuffix);
+        assert_eq!(write.write_buffer_size, 2 * MB);
+        let write = config.rocksdb.cf_config("user_timestamp", "write");
+        assert_eq!(write.compression, CompressionType::Auto);
+        assert_eq!(write.uncompressed_levels, 0);
+        assert_eq!(write.prefix_extractor, PrefixExtractor::None);
+        assert_eq!(config.rocksdb.cf_config("user_timestamp", "default").write_buffer_size, MB);
+
+        let options = config.rocksdb_options();
+        assert_eq!(options["max-background-jobs"], "8");
+        assert_eq!(options["read.prefix-seek"], "false");
+        assert_eq!(options["cf.write.compression"], "no");
+        assert_eq!(options["model.user_timestamp.write.uncompressed-levels"], "0");
+        assert_eq!(options["defaultcf.write-buffer-size"], "1048576");
+
+        assert!(toml::from_str::<Config>("[memstore]\nkind = \"list\"").is_err());
+        assert!(toml::from_str::<Config>("[rocksdb]\nwal-sync = true").is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = Config::default();
+        config.validate().unwrap();
+        config.memstore.enable_wal = false;
+        config.memstore.wal_sync = true;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.rocksdb.enable_pipelined_write = true;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        let mut cf = CfConfig::default();
+        cf.prefix_extractor = PrefixExtractor::FixedPrefix;
+        config.rocksdb.cf.insert(String::from("default"), cf);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        let mut cf = CfConfig::default();
+        cf.num_levels = 0;
+        let mut cfs = BTreeMap::new();
+        cfs.insert(String::from("default"), cf);
+        config.rocksdb.model.insert(String::from("tikv"), cfs);
+        assert!(config.validate().is_err());
+    }
+}
@@ -6,3 +6,4 @@ extern crate rand;
 pub mod mvcc;
 pub mod util;
 pub mod config;
+pub mod workload;
@@ -0,0 +1,142 @@
+///
+/// Backups of a storage: a checkpoint of rocksdb, the locks of the mem-store and
+/// a meta file recording the model which wrote them.
+///
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+
+use super::super::config::Config;
+use super::super::util::engine::db_exist;
+use super::memstore::MemStore;
+use super::storage::create_storage_cf;
+use super::{MvccStorage, StorageType};
+
+pub const BACKUP_META_FILE: &str = "BACKUP_META";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupMeta {
+    pub storage_type: String,
+    pub encoding_version: u32,
+    // Seconds since the unix epoch.
+    pub created_at: u64,
+    // Locks of the mem-store saved with the data.
+    pub locks: u64,
+}
+
+impl BackupMeta {
+    pub fn load(dir: &Path) -> Result<BackupMeta, String> {
+        let path = dir.join(BACKUP_META_FILE);
+        let data = fs::read_to_string(&path).map_err(|e| format!("read {:?} failed: {}", path, e))?;
+        serde_json::from_str(&data).map_err(|e| format!("parse {:?} failed: {}", path, e))
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(BACKUP_META_FILE);
+        let data = serde_json::to_string_pretty(self).map_err(|e| format!("{}", e))?;
+        fs::write(&path, data).map_err(|e| format!("write {:?} failed: {}", path, e))
+    }
+
+    /// The storage type which saved the backup, if its data can be read by this build.
+    pub fn check(&self) -> Result<StorageType, String> {
+        let storage_type = StorageType::from_name(&self.storage_type);
+        if storage_type == StorageType::Unknown {
+            return Err(format!("unknown storage type {}", self.storage_type));
+        }
+        if self.encoding_version != storage_type.encoding_version() {
+            return Err(format!(
+                "backup of {} is encoded in version {}, but version {} is required",
+                self.storage_type,
+                self.encoding_version,
+                storage_type.encoding_version()
+            ));
+        }
+        Ok(storage_type)
+    }
+}
+
+/// Save a checkpoint of `db` and the locks of `mem_store` into `dir`. Locks must not
+/// change meanwhile, which callers ensure by holding the lock of `mem_store`.
+pub fn checkpoint(db: &DB, mem_store: &MemStore, storage_type: StorageType, dir: &Path) -> Result<(), String> {
+    // Memtables are always flushed, so that the checkpoint needs no WAL.
+    db.new_checkpointer()?.create_at(dir, None, 0)?;
+    mem_store.save(dir)?;
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let meta = BackupMeta {
+        storage_type: storage_type.name().to_owned(),
+        encoding_version: storage_type.encoding_version(),
+        created_at,
+        locks: mem_store.iter().count() as u64,
+    };
+    meta.save(dir)
+}
+
+/// Copy the backup in `dir` to `path`, which must be empty, and open it as the
+/// storage type which saved it.
+pub fn restore(dir: &Path, path: &str, config: &Config) -> Result<(StorageType, Arc<dyn MvccStorage>), String> {
+    let storage_type = BackupMeta::load(dir)?.check()?;
+    if db_exist(path) {
+        return Err(format!("{} is not empty", path));
+    }
+    copy_dir(dir, Path::new(path))?;
+    let storage = create_storage_cf(path, storage_type, config)?;
+    Ok((storage_type, storage))
+}
+
+// SST files are never modified, they are linked instead of copied when possible.
+fn copy_dir(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("create {:?} failed: {}", to, e))?;
+    let entries = fs::read_dir(from).map_err(|e| format!("read {:?} failed: {}", from, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("read {:?} failed: {}", from, e))?;
+        if entry.file_name() == BACKUP_META_FILE {
+            continue;
+        }
+        let src = entry.path();
+        let dst = to.join(entry.file_name());
+        if src.is_dir() {
+            copy_dir(&src, &dst)?;
+            continue;
+        }
+        let linked = src.extension().map_or(false, |ext| ext == "sst") && fs::hard_link(&src, &dst).is_ok();
+        if !linked {
+            fs::copy(&src, &dst).map_err(|e| format!("copy {:?} failed: {}", src, e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_backup_meta() {
+        let dir = TempDir::new("_backup_meta").unwrap();
+        assert!(BackupMeta::load(dir.path()).is_err());
+        let mut meta = BackupMeta {
+            storage_type: String::from("tikv"),
+            encoding_version: StorageType::TiKVStorage.encoding_version(),
+            created_at: 1,
+            locks: 2,
+        };
+        meta.save(dir.path()).unwrap();
+        assert_eq!(BackupMeta::load(dir.path()).unwrap(), meta);
+        assert_eq!(meta.check().unwrap(), StorageType::TiKVStorage);
+
+        meta.encoding_version += 1;
+        meta.save(dir.path()).unwrap();
+        assert!(restore(dir.path(), dir.path().join("db").to_str().unwrap(), &Config::default()).is_err());
+        meta.storage_type = String::from("unknown");
+        assert!(meta.check().is_err());
+    }
+}
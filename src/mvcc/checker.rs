@@ -0,0 +1,434 @@
+///
+/// Offline snapshot isolation checker for histories recorded by `HistoryRecorder`.
+///
+/// A transaction is identified by its start_ts, and a `get` at `ts` belongs to the
+/// transaction started at `ts`. Under snapshot isolation every read must return
+/// the latest version committed at or before its timestamp, and no two
+/// concurrent transactions may both commit a write to the same key.
+///
+
+use std::fmt;
+
+use super::super::util::collection::{HashMap, HashSet};
+use super::history::{operations, Event, Op, OpResult};
+use super::{Key, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    // Two concurrent transactions both committed a write to one key.
+    LostUpdate,
+    // A read returned a value which was not committed at its timestamp.
+    DirtyRead,
+    // A read missed a version committed before its timestamp.
+    StaleRead,
+    // One transaction read a key twice and got different values.
+    NonRepeatableRead,
+    // Two concurrent transactions each wrote a key the other one read.
+    WriteSkew,
+}
+
+impl AnomalyKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AnomalyKind::LostUpdate => "lost update",
+            AnomalyKind::DirtyRead => "dirty read",
+            AnomalyKind::StaleRead => "stale read",
+            AnomalyKind::NonRepeatableRead => "non-repeatable read",
+            AnomalyKind::WriteSkew => "write skew",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub key: Key,
+    // Start ts of the transactions involved.
+    pub txns: Vec<u64>,
+    // Timestamp of the read which observed the anomaly.
+    pub read_ts: Option<u64>,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} on key {:?}, txns {:?}",
+            self.kind.name(),
+            String::from_utf8_lossy(&self.key),
+            self.txns
+        )?;
+        if let Some(ts) = self.read_ts {
+            write!(f, ", read ts {}", ts)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+    pub transactions: usize,
+    pub reads: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl CheckReport {
+    /// Write skew is allowed by snapshot isolation, the other anomalies are not.
+    pub fn is_snapshot_isolation(&self) -> bool {
+        self.anomalies.iter().all(|a| a.kind == AnomalyKind::WriteSkew)
+    }
+
+    pub fn count(&self, kind: AnomalyKind) -> usize {
+        self.anomalies.iter().filter(|a| a.kind == kind).count()
+    }
+
+    pub fn print(&self) {
+        println!(
+            "[HISTORY] transactions: {}, reads: {}, anomalies: {}, snapshot isolation: {}",
+            self.transactions,
+            self.reads,
+            self.anomalies.len(),
+            self.is_snapshot_isolation()
+        );
+        for anomaly in self.anomalies.iter() {
+            println!("[ANOMALY] {}", anomaly);
+        }
+    }
+}
+
+struct Version {
+    start_ts: u64,
+    commit_ts: u64,
+    // None if the version is a deletion.
+    value: Option<Value>,
+}
+
+#[derive(Default)]
+struct Txn {
+    writes: HashMap<Key, Option<Value>>,
+    // key -> commit_ts
+    committed: HashMap<Key, u64>,
+    reads: Vec<(Key, Option<Value>)>,
+}
+
+impl Txn {
+    fn commit_ts(&self) -> Option<u64> {
+        self.committed.values().cloned().max()
+    }
+}
+
+pub fn check(events: &[Event]) -> CheckReport {
+    let mut txns: HashMap<u64, Txn> = HashMap::default();
+    let mut report = CheckReport::default();
+    for op in operations(events).into_iter() {
+        match (op.op, op.result) {
+            (Op::Prewrite { key, value, start_ts }, Some(OpResult::Ok)) => {
+                txns.entry(start_ts).or_default().writes.insert(key, Some(value));
+            }
+            (Op::Delete { key, start_ts }, Some(OpResult::Ok)) => {
+                txns.entry(start_ts).or_default().writes.insert(key, None);
+            }
+            (Op::Commit { key, start_ts, commit_ts }, Some(OpResult::Ok)) => {
+                txns.entry(start_ts).or_default().committed.insert(key, commit_ts);
+            }
+            (Op::Get { key, ts }, Some(OpResult::Value(value))) => {
+                report.reads += 1;
+                txns.entry(ts).or_default().reads.push((key, value));
+            }
+            _ => (),
+        }
+    }
+    report.transactions = txns.len();
+
+    let mut versions: HashMap<Key, Vec<Version>> = HashMap::default();
+    for (start_ts, txn) in txns.iter() {
+        for (key, commit_ts) in txn.committed.iter() {
+            if let Some(value) = txn.writes.get(key) {
+                versions.entry(key.clone()).or_default().push(Version {
+                    start_ts: *start_ts,
+                    commit_ts: *commit_ts,
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+    for list in versions.values_mut() {
+        list.sort_by_key(|v| v.commit_ts);
+    }
+
+    check_reads(&txns, &versions, &mut report.anomalies);
+    check_lost_updates(&versions, &mut report.anomalies);
+    check_write_skews(&txns, &mut report.anomalies);
+    report
+}
+
+fn check_reads(txns: &HashMap<u64, Txn>, versions: &HashMap<Key, Vec<Version>>, anomalies: &mut Vec<Anomaly>) {
+    let empty = Vec::new();
+    for (ts, txn) in txns.iter() {
+        let mut seen: HashMap<&Key, &Option<Value>> = HashMap::default();
+        for (key, observed) in txn.reads.iter() {
+            if let Some(prev) = seen.insert(key, observed) {
+                if prev != observed {
+                    anomalies.push(Anomaly {
+                        kind: AnomalyKind::NonRepeatableRead,
+                        key: key.clone(),
+                        txns: vec![*ts],
+                        read_ts: Some(*ts),
+                    });
+                }
+            }
+            if txn.writes.contains_key(key) {
+                continue;
+            }
+            let list = versions.get(key).unwrap_or(&empty);
+            let visible = &list[..visible_count(list, *ts)];
+            let expected = visible.last().and_then(|v| v.value.as_ref());
+            if observed.as_ref() == expected {
+                continue;
+            }
+            let latest = visible.last().map(|v| v.start_ts);
+            let value = match observed {
+                Some(value) => value,
+                None => {
+                    anomalies.push(Anomaly {
+                        kind: AnomalyKind::StaleRead,
+                        key: key.clone(),
+                        txns: latest.into_iter().collect(),
+                        read_ts: Some(*ts),
+                    });
+                    continue;
+                }
+            };
+            if let Some(v) = visible.iter().find(|v| v.value.as_ref() == Some(value)) {
+                let mut involved = vec![v.start_ts];
+                involved.extend(latest);
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::StaleRead,
+                    key: key.clone(),
+                    txns: involved,
+                    read_ts: Some(*ts),
+                });
+                continue;
+            }
+            // Written by a transaction which was not committed at `ts`.
+            let writer = txns.iter().find(|(_, w)| {
+                w.writes.get(key).map_or(false, |v| v.as_ref() == Some(value))
+                    && w.committed.get(key).map_or(true, |commit_ts| commit_ts > ts)
+            });
+            if let Some((start_ts, _)) = writer {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::DirtyRead,
+                    key: key.clone(),
+                    txns: vec![*start_ts],
+                    read_ts: Some(*ts),
+                });
+            }
+            // Otherwise the value was written before the history was recorded.
+        }
+    }
+}
+
+// Number of versions committed at or before `ts`, `list` is sorted by commit_ts.
+fn visible_count(list: &[Version], ts: u64) -> usize {
+    match list.binary_search_by(|v| v.commit_ts.cmp(&ts)) {
+        Ok(mut i) => {
+            while i + 1 < list.len() && list[i + 1].commit_ts == ts {
+                i += 1;
+            }
+            i + 1
+        }
+        Err(i) => i,
+    }
+}
+
+fn check_lost_updates(versions: &HashMap<Key, Vec<Version>>, anomalies: &mut Vec<Anomaly>) {
+    for (key, list) in versions.iter() {
+        for i in 0..list.len() {
+            // `list` is sorted by commit_ts, so only earlier versions can overlap.
+            for j in (0..i).rev() {
+                if list[j].commit_ts <= list[i].start_ts {
+                    break;
+                }
+                if list[j].start_ts != list[i].start_ts {
+                    anomalies.push(Anomaly {
+                        kind: AnomalyKind::LostUpdate,
+                        key: key.clone(),
+                        txns: vec![list[j].start_ts, list[i].start_ts],
+                        read_ts: None,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn check_write_skews(txns: &HashMap<u64, Txn>, anomalies: &mut Vec<Anomaly>) {
+    // (commit_ts, start_ts) of the committed writers of each key, sorted by commit_ts.
+    let mut writers: HashMap<&Key, Vec<(u64, u64)>> = HashMap::default();
+    let mut max_span = 0;
+    for (start_ts, txn) in txns.iter() {
+        for (key, commit_ts) in txn.committed.iter() {
+            writers.entry(key).or_default().push((*commit_ts, *start_ts));
+            max_span = max_span.max(commit_ts.saturating_sub(*start_ts));
+        }
+    }
+    for list in writers.values_mut() {
+        list.sort();
+    }
+    let mut reported: HashSet<(u64, u64)> = HashSet::default();
+    for (t1_ts, t1) in txns.iter() {
+        let t1_commit = match t1.commit_ts() {
+            Some(ts) => ts,
+            None => continue,
+        };
+        for (x, _) in t1.reads.iter() {
+            let list = match writers.get(x) {
+                Some(list) => list,
+                None => continue,
+            };
+            // Writers committed after t1 started, a writer committed later than
+            // `t1_commit + max_span` started after t1 committed.
+            let begin = match list.binary_search(&(*t1_ts + 1, 0)) {
+                Ok(i) | Err(i) => i,
+            };
+            for (t2_commit, t2_ts) in list[begin..].iter() {
+                if *t2_commit >= t1_commit.saturating_add(max_span) {
+                    break;
+                }
+                if t2_ts == t1_ts || *t2_ts >= t1_commit {
+                    continue;
+                }
+                let t2 = &txns[t2_ts];
+                if t1.committed.keys().any(|k| t2.committed.contains_key(k)) {
+                    continue;
+                }
+                if !t2.reads.iter().any(|(y, _)| t1.committed.contains_key(y)) {
+                    continue;
+                }
+                let pair = (*t1_ts.min(t2_ts), *t1_ts.max(t2_ts));
+                if reported.insert(pair) {
+                    anomalies.push(Anomaly {
+                        kind: AnomalyKind::WriteSkew,
+                        key: x.clone(),
+                        txns: vec![pair.0, pair.1],
+                        read_ts: None,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::history::HistoryRecorder;
+    use super::super::storage::create_storage;
+    use super::super::{MvccStorage, StorageType};
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    struct Builder {
+        events: Vec<Event>,
+        time: u64,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Self {
+                events: vec![],
+                time: 0,
+            }
+        }
+
+        fn op(&mut self, op: Op, result: OpResult) -> &mut Self {
+            let id = self.time;
+            self.events.push(Event::Invoke { id, time: self.time, op });
+            self.events.push(Event::Complete {
+                id,
+                time: self.time + 1,
+                result,
+            });
+            self.time += 2;
+            self
+        }
+
+        fn write(&mut self, key: &str, value: &str, start_ts: u64, commit_ts: u64) -> &mut Self {
+            let k = key.as_bytes().to_vec();
+            self.op(
+                Op::Prewrite {
+                    key: k.clone(),
+                    value: value.as_bytes().to_vec(),
+                    start_ts,
+                },
+                OpResult::Ok,
+            )
+            .op(Op::Commit { key: k, start_ts, commit_ts }, OpResult::Ok)
+        }
+
+        fn read(&mut self, key: &str, ts: u64, value: Option<&str>) -> &mut Self {
+            self.op(
+                Op::Get {
+                    key: key.as_bytes().to_vec(),
+                    ts,
+                },
+                OpResult::Value(value.map(|v| v.as_bytes().to_vec())),
+            )
+        }
+    }
+
+    #[test]
+    fn test_check_anomalies() {
+        let report = check(&Builder::new().write("a", "1", 1, 2).read("a", 3, Some("1")).events);
+        assert!(report.anomalies.is_empty());
+
+        let report = check(&Builder::new().write("a", "1", 1, 5).write("a", "2", 3, 6).events);
+        assert_eq!(report.count(AnomalyKind::LostUpdate), 1);
+
+        let report = check(&Builder::new().write("a", "1", 1, 5).read("a", 3, Some("1")).events);
+        assert_eq!(report.count(AnomalyKind::DirtyRead), 1);
+
+        let report = check(
+            &Builder::new()
+                .write("a", "1", 1, 2)
+                .write("a", "2", 3, 4)
+                .read("a", 5, Some("1"))
+                .events,
+        );
+        assert_eq!(report.count(AnomalyKind::StaleRead), 1);
+
+        let report = check(&Builder::new().read("a", 3, Some("1")).read("a", 3, Some("2")).events);
+        assert_eq!(report.count(AnomalyKind::NonRepeatableRead), 1);
+
+        let report = check(
+            &Builder::new()
+                .read("x", 10, None)
+                .read("y", 10, None)
+                .read("x", 11, None)
+                .read("y", 11, None)
+                .write("y", "1", 10, 12)
+                .write("x", "1", 11, 13)
+                .events,
+        );
+        assert_eq!(report.count(AnomalyKind::WriteSkew), 1);
+        assert!(report.is_snapshot_isolation());
+    }
+
+    #[test]
+    fn test_record_history() {
+        let path = TempDir::new("_mvcc_history").expect("");
+        let storage = create_storage(path.path().to_str().unwrap(), StorageType::UserTimestampStorage).unwrap();
+        let recorder = Arc::new(HistoryRecorder::new(storage));
+        let k = b"k".to_vec();
+        recorder.prewrite(&k, &b"v1".to_vec(), 1).unwrap();
+        assert!(recorder.get(&k, 2).is_err());
+        recorder.commit(&k, 1, 3).unwrap();
+        assert_eq!(recorder.get(&k, 4).unwrap().unwrap(), b"v1".to_vec());
+        assert!(recorder.get(&k, 2).unwrap().is_none());
+        let history = recorder.history();
+        assert_eq!(history.len(), 10);
+        let report = check(&history);
+        assert_eq!(report.reads, 2);
+        assert!(report.anomalies.is_empty());
+    }
+}
@@ -0,0 +1,608 @@
+///
+/// Semantics every `MvccStorage` model must follow. Each case creates its own
+/// storage of the given type, a new model only needs one more test at the bottom.
+///
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::u64;
+use tempdir::TempDir;
+
+use super::super::config::Config;
+use super::backup::restore;
+use super::max_ts::MAX_TS_FILE_NAME;
+use super::pinned::PinnedValue;
+use super::storage::create_storage;
+use super::txn_types::{LockType, WriteType};
+use super::{LockInfo, MvccStorage, ReadMode, StorageType, Value, Version};
+use super::{ERR_KEY_LOCKED, ERR_KEY_VERSION, ERR_TS_TOO_OLD, FLASHBACK_BATCH_SIZE};
+
+fn open(path: &TempDir, storage_type: StorageType) -> Arc<dyn MvccStorage> {
+    create_storage(path.path().to_str().unwrap(), storage_type).unwrap()
+}
+
+fn put(storage: &Arc<dyn MvccStorage>, key: &str, value: &str, start_ts: u64, commit_ts: u64) {
+    let k = key.as_bytes().to_vec();
+    storage.prewrite(&k, &value.as_bytes().to_vec(), start_ts).unwrap();
+    storage.commit(&k, start_ts, commit_ts).unwrap();
+}
+
+fn delete(storage: &Arc<dyn MvccStorage>, key: &str, start_ts: u64, commit_ts: u64) {
+    let k = key.as_bytes().to_vec();
+    storage.delete(&k, start_ts).unwrap();
+    storage.commit(&k, start_ts, commit_ts).unwrap();
+}
+
+fn get(storage: &Arc<dyn MvccStorage>, key: &str, ts: u64) -> Result<Option<String>, String> {
+    let value = storage.get(&key.as_bytes().to_vec(), ts)?;
+    Ok(value.map(|v| String::from_utf8(v.into_vec()).unwrap()))
+}
+
+fn scan(storage: &Arc<dyn MvccStorage>, start: &str, end: &str, ts: u64) -> Result<Vec<String>, String> {
+    let values = storage.scan(&start.as_bytes().to_vec(), &end.as_bytes().to_vec(), ts)?;
+    Ok(values.into_iter().map(|v| String::from_utf8(v.into_vec()).unwrap()).collect())
+}
+
+// Copies of the values read by a batch get, to compare them with expected ones.
+fn owned(results: Vec<Result<Option<PinnedValue>, String>>) -> Vec<Result<Option<Value>, String>> {
+    results.into_iter().map(|ret| ret.map(|v| v.map(PinnedValue::into_vec))).collect()
+}
+
+fn test_lock(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_lock").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "k", "v1", 1, 2);
+    storage.prewrite(&b"k".to_vec(), &b"v2".to_vec(), 5).unwrap();
+    // The lock only blocks reads which may see it once committed.
+    assert_eq!(get(&storage, "k", 4).unwrap(), Some("v1".to_string()));
+    assert_eq!(get(&storage, "k", 5).unwrap_err(), ERR_KEY_LOCKED);
+    assert_eq!(get(&storage, "k", u64::MAX).unwrap_err(), ERR_KEY_LOCKED);
+    assert_eq!(
+        storage.prewrite(&b"k".to_vec(), &b"v3".to_vec(), 6).unwrap_err(),
+        ERR_KEY_LOCKED
+    );
+    assert_eq!(storage.delete(&b"k".to_vec(), 6).unwrap_err(), ERR_KEY_LOCKED);
+    storage.commit(&b"k".to_vec(), 5, 7).unwrap();
+    assert_eq!(get(&storage, "k", 6).unwrap(), Some("v1".to_string()));
+    assert_eq!(get(&storage, "k", 7).unwrap(), Some("v2".to_string()));
+}
+
+fn test_conflict(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_conflict").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "k", "v1", 1, 5);
+    // A transaction started before the latest commit must not overwrite it.
+    for start_ts in [3, 5].iter() {
+        assert_eq!(
+            storage.prewrite(&b"k".to_vec(), &b"v2".to_vec(), *start_ts).unwrap_err(),
+            ERR_KEY_VERSION
+        );
+        assert_eq!(storage.delete(&b"k".to_vec(), *start_ts).unwrap_err(), ERR_KEY_VERSION);
+    }
+    put(&storage, "k", "v2", 6, 7);
+    assert_eq!(get(&storage, "k", 7).unwrap(), Some("v2".to_string()));
+}
+
+fn test_rollback(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_rollback").unwrap();
+    let storage = open(&path, storage_type);
+    let k = b"k".to_vec();
+    put(&storage, "k", "v1", 1, 2);
+    storage.prewrite(&k, &b"v2".to_vec(), 3).unwrap();
+    // Only the owner of the lock can roll it back.
+    assert!(storage.rollback(&k, 4).is_err());
+    storage.rollback(&k, 3).unwrap();
+    assert_eq!(get(&storage, "k", 10).unwrap(), Some("v1".to_string()));
+    assert_eq!(scan(&storage, "k", "l", 10).unwrap(), vec!["v1"]);
+    assert!(storage.commit(&k, 3, 4).is_err());
+    assert_eq!(get(&storage, "k", 10).unwrap(), Some("v1".to_string()));
+    // A late prewrite of the rolled back transaction must not lock the key again.
+    assert_eq!(storage.prewrite(&k, &b"v2".to_vec(), 3).unwrap_err(), ERR_KEY_VERSION);
+    assert_eq!(storage.delete(&k, 3).unwrap_err(), ERR_KEY_VERSION);
+    // A committed transaction can not be rolled back.
+    assert!(storage.rollback(&k, 1).is_err());
+    assert_eq!(get(&storage, "k", 10).unwrap(), Some("v1".to_string()));
+    put(&storage, "k", "v3", 5, 6);
+    assert_eq!(get(&storage, "k", 10).unwrap(), Some("v3".to_string()));
+}
+
+fn test_resolve_lock(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_resolve").unwrap();
+    let storage = open(&path, storage_type);
+    let (k1, k2) = (b"k1".to_vec(), b"k2".to_vec());
+    storage.prewrite(&k1, &b"v1".to_vec(), 1).unwrap();
+    storage.prewrite(&k2, &b"v2".to_vec(), 1).unwrap();
+    storage.commit(&k1, 1, 2).unwrap();
+    // Commit is idempotent, a retried commit of the primary key must succeed.
+    storage.commit(&k1, 1, 2).unwrap();
+    storage.resolve_lock(&k2, 1, 2).unwrap();
+    storage.resolve_lock(&k2, 1, 2).unwrap();
+    assert_eq!(get(&storage, "k1", 2).unwrap(), Some("v1".to_string()));
+    assert_eq!(get(&storage, "k2", 2).unwrap(), Some("v2".to_string()));
+
+    storage.prewrite(&k1, &b"v3".to_vec(), 3).unwrap();
+    storage.prewrite(&k2, &b"v4".to_vec(), 3).unwrap();
+    storage.rollback(&k1, 3).unwrap();
+    storage.resolve_lock(&k2, 3, 0).unwrap();
+    assert!(storage.resolve_lock(&k2, 3, 4).is_err());
+    assert_eq!(get(&storage, "k1", 10).unwrap(), Some("v1".to_string()));
+    assert_eq!(get(&storage, "k2", 10).unwrap(), Some("v2".to_string()));
+}
+
+fn test_commit_rollback_race(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_commit_rollback").unwrap();
+    let storage = open(&path, storage_type);
+    for i in 0..100 {
+        let key = format!("k{}", i);
+        let k = key.as_bytes().to_vec();
+        storage.prewrite(&k, &b"v".to_vec(), 1).unwrap();
+        let handle = {
+            let (storage, k) = (storage.clone(), k.clone());
+            thread::spawn(move || storage.rollback(&k, 1).is_ok())
+        };
+        let committed = storage.commit(&k, 1, 2).is_ok();
+        let rolled_back = handle.join().unwrap();
+        // Exactly one of them wins, and reads agree with the winner.
+        assert_ne!(committed, rolled_back, "{}", key);
+        let expected = if committed { Some("v".to_string()) } else { None };
+        assert_eq!(get(&storage, &key, 2).unwrap(), expected, "{}", key);
+    }
+}
+
+fn test_delete(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_delete").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "k", "v1", 1, 2);
+    delete(&storage, "k", 3, 4);
+    assert_eq!(get(&storage, "k", 3).unwrap(), Some("v1".to_string()));
+    assert_eq!(get(&storage, "k", 4).unwrap(), None);
+    assert_eq!(get(&storage, "k", u64::MAX).unwrap(), None);
+    // Deleting a missing key is allowed.
+    delete(&storage, "missing", 5, 6);
+    assert_eq!(get(&storage, "missing", 6).unwrap(), None);
+    put(&storage, "k", "v2", 7, 8);
+    assert_eq!(get(&storage, "k", 8).unwrap(), Some("v2".to_string()));
+    // A rolled back deletion leaves the key untouched.
+    storage.delete(&b"k".to_vec(), 9).unwrap();
+    storage.rollback(&b"k".to_vec(), 9).unwrap();
+    assert_eq!(get(&storage, "k", 10).unwrap(), Some("v2".to_string()));
+}
+
+fn test_scan(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_scan").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "b", "b1", 1, 2);
+    put(&storage, "c", "c1", 1, 2);
+    put(&storage, "d", "d1", 1, 2);
+    put(&storage, "b", "b2", 3, 4);
+    delete(&storage, "c", 3, 4);
+    assert!(scan(&storage, "a", "d", 1).unwrap().is_empty());
+    assert_eq!(scan(&storage, "a", "d", 2).unwrap(), vec!["a1", "b1", "c1"]);
+    assert_eq!(scan(&storage, "a", "e", 4).unwrap(), vec!["a1", "b2", "d1"]);
+    assert_eq!(scan(&storage, "b", "c", u64::MAX).unwrap(), vec!["b2"]);
+    assert!(scan(&storage, "e", "z", u64::MAX).unwrap().is_empty());
+
+    storage.prewrite(&b"c".to_vec(), &b"c2".to_vec(), 5).unwrap();
+    assert_eq!(scan(&storage, "a", "e", 5).unwrap_err(), ERR_KEY_LOCKED);
+    // Locks outside of the range or newer than the read do not block the scan.
+    assert_eq!(scan(&storage, "a", "c", 5).unwrap(), vec!["a1", "b2"]);
+    assert_eq!(scan(&storage, "a", "e", 4).unwrap(), vec!["a1", "b2", "d1"]);
+    storage.commit(&b"c".to_vec(), 5, 6).unwrap();
+    assert_eq!(scan(&storage, "a", "e", 6).unwrap(), vec!["a1", "b2", "c2", "d1"]);
+}
+
+// Versions of a key must not be confused with those of keys it is a prefix of.
+fn test_prefix_keys(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_prefix_keys").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "ab", "ab1", 3, 4);
+    put(&storage, "a", "a2", 5, 6);
+    delete(&storage, "a\x00", 7, 8);
+    assert_eq!(get(&storage, "a", 4).unwrap(), Some("a1".to_string()));
+    assert_eq!(get(&storage, "a", 6).unwrap(), Some("a2".to_string()));
+    assert_eq!(get(&storage, "ab", 5).unwrap(), Some("ab1".to_string()));
+    assert_eq!(get(&storage, "ab", 3).unwrap(), None);
+    assert_eq!(get(&storage, "a\x00", 8).unwrap(), None);
+    assert_eq!(scan(&storage, "a", "b", 6).unwrap(), vec!["a2", "ab1"]);
+    assert_eq!(scan(&storage, "a", "ab", 4).unwrap(), vec!["a1"]);
+    // A newer version of "ab" does not conflict with "a".
+    put(&storage, "ab", "ab2", 9, 10);
+    put(&storage, "a", "a3", 9, 11);
+    assert_eq!(scan(&storage, "a", "b", 11).unwrap(), vec!["a3", "ab2"]);
+}
+
+fn test_gc(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_gc").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "a", "a2", 3, 4);
+    put(&storage, "a", "a3", 7, 8);
+    put(&storage, "b", "b1", 1, 2);
+    delete(&storage, "b", 3, 4);
+    storage.prewrite(&b"c".to_vec(), &b"c1".to_vec(), 5).unwrap();
+    // A lock older than the safe point must be resolved first.
+    assert!(storage.gc(6).is_err());
+    storage.rollback(&b"c".to_vec(), 5).unwrap();
+    // A rollback newer than the kept version must not hide it.
+    put(&storage, "d", "d1", 1, 3);
+    storage.prewrite(&b"d".to_vec(), &b"d2".to_vec(), 5).unwrap();
+    storage.rollback(&b"d".to_vec(), 5).unwrap();
+    assert_eq!(storage.stats().unwrap().total_versions, 6);
+    storage.gc(6).unwrap();
+    // Only the versions of "a" at 4 and 8 and of "d" at 3 are still visible to a read.
+    assert_eq!(storage.stats().unwrap().total_versions, 3);
+
+    for ts in [0, 2, 5].iter() {
+        assert_eq!(get(&storage, "a", *ts).unwrap_err(), ERR_TS_TOO_OLD);
+        assert_eq!(scan(&storage, "a", "z", *ts).unwrap_err(), ERR_TS_TOO_OLD);
+    }
+    assert_eq!(get(&storage, "a", 6).unwrap(), Some("a2".to_string()));
+    assert_eq!(get(&storage, "a", 8).unwrap(), Some("a3".to_string()));
+    assert_eq!(get(&storage, "b", 6).unwrap(), None);
+    assert_eq!(get(&storage, "d", 6).unwrap(), Some("d1".to_string()));
+    assert_eq!(scan(&storage, "a", "z", 6).unwrap(), vec!["a2", "d1"]);
+    assert_eq!(scan(&storage, "a", "z", 8).unwrap(), vec!["a3", "d1"]);
+
+    // The safe point never goes backwards.
+    storage.gc(3).unwrap();
+    assert_eq!(get(&storage, "a", 5).unwrap_err(), ERR_TS_TOO_OLD);
+    put(&storage, "b", "b2", 9, 10);
+    assert_eq!(get(&storage, "b", 10).unwrap(), Some("b2".to_string()));
+}
+
+fn test_restart(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_restart").unwrap();
+    {
+        let storage = open(&path, storage_type);
+        put(&storage, "a", "a1", 1, 2);
+        put(&storage, "a", "a2", 3, 4);
+        put(&storage, "b", "b1", 1, 2);
+        delete(&storage, "b", 5, 6);
+        storage.prewrite(&b"c".to_vec(), &b"c1".to_vec(), 7).unwrap();
+    }
+    let storage = open(&path, storage_type);
+    assert_eq!(get(&storage, "a", 2).unwrap(), Some("a1".to_string()));
+    assert_eq!(get(&storage, "a", 4).unwrap(), Some("a2".to_string()));
+    assert_eq!(get(&storage, "b", 5).unwrap(), Some("b1".to_string()));
+    assert_eq!(get(&storage, "b", 6).unwrap(), None);
+    assert_eq!(scan(&storage, "a", "z", 4).unwrap(), vec!["a2", "b1"]);
+    // Committed versions still conflict with older transactions.
+    assert_eq!(
+        storage.prewrite(&b"a".to_vec(), &b"a3".to_vec(), 3).unwrap_err(),
+        ERR_KEY_VERSION
+    );
+    put(&storage, "a", "a3", 7, 8);
+    assert_eq!(get(&storage, "a", 8).unwrap(), Some("a3".to_string()));
+    // Locks survive the restart and can still be committed.
+    assert_eq!(get(&storage, "c", 7).unwrap_err(), ERR_KEY_LOCKED);
+    storage.commit(&b"c".to_vec(), 7, 9).unwrap();
+    assert_eq!(get(&storage, "c", 9).unwrap(), Some("c1".to_string()));
+}
+
+fn test_stats(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_stats").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "v1", 1, 2);
+    put(&storage, "a", "v2", 3, 4);
+    put(&storage, "b", "v1", 5, 6);
+    delete(&storage, "b", 7, 8);
+    storage.prewrite(&b"c".to_vec(), &b"v1".to_vec(), 9).unwrap();
+    let stats = storage.stats().unwrap();
+    // Uncommitted writes count as logical writes but not as versions.
+    assert_eq!(stats.logical_bytes_written, 3 * 3 + 1 + 3);
+    assert_eq!(stats.live_versions, 1);
+    assert_eq!(stats.live_bytes, 3);
+    assert_eq!(stats.total_versions, 4);
+}
+
+fn test_bulk_load(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_bulk_load").unwrap();
+    let storage = open(&path, storage_type);
+    let kvs = |keys: &[&str]| -> Vec<(Value, Value)> {
+        keys.iter().map(|k| (k.as_bytes().to_vec(), format!("{}1", k).into_bytes())).collect()
+    };
+    storage.prewrite(&b"d".to_vec(), &b"d1".to_vec(), 1).unwrap();
+    assert_eq!(storage.bulk_load(&kvs(&["c", "d"]), 1, 2).unwrap_err(), ERR_KEY_LOCKED);
+    storage.rollback(&b"d".to_vec(), 1).unwrap();
+    assert!(storage.bulk_load(&kvs(&["b", "a"]), 1, 2).is_err());
+    assert!(storage.bulk_load(&kvs(&["a", "a"]), 1, 2).is_err());
+    storage.bulk_load(&kvs(&["a", "b", "c"]), 1, 2).unwrap();
+    storage.bulk_load(&[], 1, 2).unwrap();
+    assert_eq!(get(&storage, "a", 1).unwrap(), None);
+    assert_eq!(get(&storage, "b", 2).unwrap(), Some("b1".to_string()));
+    assert_eq!(scan(&storage, "a", "z", 2).unwrap(), vec!["a1", "b1", "c1"]);
+    // Loaded versions behave as committed ones.
+    assert_eq!(
+        storage.prewrite(&b"a".to_vec(), &b"a2".to_vec(), 2).unwrap_err(),
+        ERR_KEY_VERSION
+    );
+    put(&storage, "a", "a2", 3, 4);
+    assert_eq!(get(&storage, "a", 3).unwrap(), Some("a1".to_string()));
+    assert_eq!(get(&storage, "a", 4).unwrap(), Some("a2".to_string()));
+    // The rolled back prewrite of "d" counts as well.
+    assert_eq!(storage.stats().unwrap().logical_bytes_written, 3 + 3 * 3 + 3);
+}
+
+fn test_backup(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_backup").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    storage.prewrite(&b"b".to_vec(), &b"b1".to_vec(), 3).unwrap();
+    let dir = path.path().join("backup");
+    storage.backup(&dir).unwrap();
+    // The backup directory must not exist.
+    assert!(storage.backup(&dir).is_err());
+    put(&storage, "a", "a2", 4, 5);
+
+    let restored = path.path().join("restored");
+    let (restored_type, restored) = restore(&dir, restored.to_str().unwrap(), &Config::default()).unwrap();
+    assert_eq!(restored_type, storage_type);
+    assert_eq!(get(&restored, "a", 5).unwrap(), Some("a1".to_string()));
+    assert_eq!(get(&restored, "b", 3).unwrap_err(), ERR_KEY_LOCKED);
+    restored.commit(&b"b".to_vec(), 3, 6).unwrap();
+    assert_eq!(get(&restored, "b", 6).unwrap(), Some("b1".to_string()));
+    // The backup can be restored again, and the source is left untouched.
+    let again = path.path().join("again");
+    let (_, again) = restore(&dir, again.to_str().unwrap(), &Config::default()).unwrap();
+    assert_eq!(get(&again, "b", 6).unwrap_err(), ERR_KEY_LOCKED);
+    assert_eq!(get(&storage, "a", 5).unwrap(), Some("a2".to_string()));
+}
+
+fn test_batch_get(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_batch_get").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "b", "b1", 1, 2);
+    put(&storage, "b", "b2", 5, 6);
+    delete(&storage, "c", 3, 4);
+    storage.prewrite(&b"d".to_vec(), &b"d1".to_vec(), 7).unwrap();
+    // Keys need not be sorted nor distinct.
+    let keys: Vec<&[u8]> = ["d", "b", "x", "a", "c", "b"].iter().map(|k| k.as_bytes()).collect();
+    let values = owned(storage.batch_get(&keys, 8).unwrap());
+    assert_eq!(values[0], Err(String::from(ERR_KEY_LOCKED)));
+    assert_eq!(values[1], Ok(Some(b"b2".to_vec())));
+    assert_eq!(values[2], Ok(None));
+    assert_eq!(values[3], Ok(Some(b"a1".to_vec())));
+    assert_eq!(values[4], Ok(None));
+    assert_eq!(values[5], Ok(Some(b"b2".to_vec())));
+    // The lock is not seen by reads before it.
+    let values = owned(storage.batch_get(&keys, 5).unwrap());
+    assert_eq!(values[0], Ok(None));
+    assert_eq!(values[1], Ok(Some(b"b1".to_vec())));
+    assert!(storage.batch_get(&[], 5).unwrap().is_empty());
+    storage.rollback(&b"d".to_vec(), 7).unwrap();
+    storage.gc(6).unwrap();
+    assert_eq!(storage.batch_get(&keys, 5).unwrap_err(), ERR_TS_TOO_OLD);
+}
+
+fn test_snapshot(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_snapshot").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "b", "b1", 1, 2);
+    storage.prewrite(&b"c".to_vec(), &b"c1".to_vec(), 3).unwrap();
+    let snap = storage.snapshot(4).unwrap();
+    let get_snap = |key: &str| snap.get(key.as_bytes()).map(|v| v.map(PinnedValue::into_vec));
+    assert_eq!(get_snap("a").unwrap(), Some(b"a1".to_vec()));
+    assert_eq!(get_snap("c").unwrap_err(), ERR_KEY_LOCKED);
+    // The lock is still seen after it is committed, as the version is not.
+    storage.commit(&b"c".to_vec(), 3, 4).unwrap();
+    assert_eq!(get_snap("c").unwrap_err(), ERR_KEY_LOCKED);
+    assert_eq!(get(&storage, "c", 4).unwrap(), Some("c1".to_string()));
+    let keys: Vec<&[u8]> = vec![b"a", b"c", b"d", b"b"];
+    let values = owned(snap.batch_get(&keys));
+    assert_eq!(values[0], Ok(Some(b"a1".to_vec())));
+    assert_eq!(values[1], Err(String::from(ERR_KEY_LOCKED)));
+    assert_eq!(values[2], Ok(None));
+    assert_eq!(values[3], Ok(Some(b"b1".to_vec())));
+    assert_eq!(snap.scan(&b"a".to_vec(), &b"c".to_vec()).unwrap(), vec![b"a1".to_vec(), b"b1".to_vec()]);
+    assert_eq!(snap.scan(&b"a".to_vec(), &b"z".to_vec()).unwrap_err(), ERR_KEY_LOCKED);
+    // Versions removed by gc are still read by the snapshot.
+    put(&storage, "a", "a2", 5, 6);
+    storage.gc(6).unwrap();
+    assert_eq!(get_snap("a").unwrap(), Some(b"a1".to_vec()));
+    assert_eq!(storage.snapshot(5).err().unwrap(), ERR_TS_TOO_OLD);
+    let snap = storage.snapshot(6).unwrap();
+    assert_eq!(snap.get(b"c").unwrap().unwrap(), b"c1".to_vec());
+    assert_eq!(snap.scan(&b"a".to_vec(), &b"z".to_vec()).unwrap(), vec![b"a2".to_vec(), b"b1".to_vec(), b"c1".to_vec()]);
+}
+
+fn test_read_mode(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_read_mode").unwrap();
+    let storage = open(&path, storage_type);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "b", "b1", 1, 2);
+    storage.prewrite(b"a", b"a2", 3).unwrap();
+    storage.delete(b"b", 4).unwrap();
+    let get_opt = |key: &str, ts, mode: &ReadMode| {
+        let value = storage.get_opt(key.as_bytes(), ts, mode)?;
+        Ok(value.map(|v| String::from_utf8(v.into_vec()).unwrap()))
+    };
+    let rc = ReadMode::read_committed();
+    // Read committed sees the committed versions through the locks.
+    assert_eq!(get(&storage, "a", 5).unwrap_err(), ERR_KEY_LOCKED);
+    assert_eq!(get_opt("a", 5, &rc), Ok(Some("a1".to_string())));
+    assert_eq!(get_opt("b", 5, &rc), Ok(Some("b1".to_string())));
+    let values = owned(storage.batch_get_opt(&[b"a", b"b", b"c"], 5, &rc).unwrap());
+    assert_eq!(values, vec![Ok(Some(b"a1".to_vec())), Ok(Some(b"b1".to_vec())), Ok(None)]);
+    assert_eq!(storage.scan_opt(b"a", b"z", 5, &rc).unwrap(), vec![b"a1".to_vec(), b"b1".to_vec()]);
+    // Only the locks of the given transactions are bypassed.
+    let bypass = ReadMode::bypass_locks(vec![3]);
+    assert_eq!(get_opt("a", 5, &bypass), Ok(Some("a1".to_string())));
+    assert_eq!(get_opt("b", 5, &bypass), Err(String::from(ERR_KEY_LOCKED)));
+    assert_eq!(storage.scan_opt(b"a", b"b", 5, &bypass).unwrap(), vec![b"a1".to_vec()]);
+    assert_eq!(storage.scan_opt(b"a", b"z", 5, &bypass).unwrap_err(), ERR_KEY_LOCKED);
+    let values = owned(storage.batch_get_opt(&[b"a", b"b"], 5, &bypass).unwrap());
+    assert_eq!(values, vec![Ok(Some(b"a1".to_vec())), Err(String::from(ERR_KEY_LOCKED))]);
+    // Once committed, the versions are seen by reads at or after their commit ts.
+    storage.commit(b"a", 3, 6).unwrap();
+    storage.commit(b"b", 4, 6).unwrap();
+    assert_eq!(get_opt("a", 5, &rc), Ok(Some("a1".to_string())));
+    assert_eq!(get_opt("a", 6, &rc), Ok(Some("a2".to_string())));
+    assert_eq!(get_opt("b", 6, &rc), Ok(None));
+    // The safe point holds for every mode.
+    storage.gc(6).unwrap();
+    assert_eq!(get_opt("a", 5, &rc), Err(String::from(ERR_TS_TOO_OLD)));
+}
+
+fn test_get_versions(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_get_versions").unwrap();
+    let storage = open(&path, storage_type);
+    // Long enough to be kept out of the write record by models which inline short values.
+    let long = "x".repeat(1000);
+    put(&storage, "a", &long, 1, 2);
+    delete(&storage, "a", 3, 4);
+    put(&storage, "a", "a3", 5, 6);
+    put(&storage, "ab", "ab1", 1, 2);
+    storage.prewrite(b"a", b"a4", 7).unwrap();
+    let version = |commit_ts, start_ts, value: Option<&str>| Version {
+        commit_ts,
+        start_ts,
+        write_type: if value.is_some() { WriteType::Put } else { WriteType::Delete },
+        value: value.map(|v| v.as_bytes().to_vec()),
+    };
+    let all = storage.get_versions(b"a", 0, u64::MAX, 10).unwrap();
+    assert_eq!(all.versions, vec![version(6, 5, Some("a3")), version(4, 3, None), version(2, 1, Some(&long))]);
+    assert_eq!(all.lock, Some(LockInfo::new(7, Some(b"a4".to_vec()))));
+    assert_eq!(all.lock.unwrap().lock_type, LockType::Put);
+    // Both ends of the range are inclusive, and the newest versions are kept by the limit.
+    assert_eq!(storage.get_versions(b"a", 4, 6, 10).unwrap().versions, vec![version(6, 5, Some("a3")), version(4, 3, None)]);
+    assert_eq!(storage.get_versions(b"a", 3, 5, 10).unwrap().versions, vec![version(4, 3, None)]);
+    assert_eq!(storage.get_versions(b"a", 0, u64::MAX, 1).unwrap().versions, vec![version(6, 5, Some("a3"))]);
+    assert!(storage.get_versions(b"a", 6, 5, 10).unwrap().versions.is_empty());
+    assert!(storage.get_versions(b"a", 0, u64::MAX, 0).unwrap().versions.is_empty());
+    // Versions of other keys sharing the prefix are not included.
+    assert_eq!(storage.get_versions(b"ab", 0, u64::MAX, 10).unwrap().versions, vec![version(2, 1, Some("ab1"))]);
+    let missing = storage.get_versions(b"b", 0, u64::MAX, 10).unwrap();
+    assert!(missing.versions.is_empty() && missing.lock.is_none());
+    storage.delete(b"b", 8).unwrap();
+    assert_eq!(storage.get_versions(b"b", 0, u64::MAX, 10).unwrap().lock, Some(LockInfo::new(8, None)));
+    // Versions removed by gc are still listed, merged with the ones kept.
+    storage.gc(6).unwrap();
+    storage.compact().unwrap();
+    assert_eq!(storage.get(b"a", 3).unwrap_err(), ERR_TS_TOO_OLD);
+    assert_eq!(storage.get_versions(b"a", 0, u64::MAX, 10).unwrap().versions, all.versions);
+    assert_eq!(storage.get_versions(b"a", 3, 6, 10).unwrap().versions, vec![version(6, 5, Some("a3")), version(4, 3, None)]);
+    assert_eq!(storage.get_versions(b"a", 0, u64::MAX, 2).unwrap().versions, vec![version(6, 5, Some("a3")), version(4, 3, None)]);
+    assert_eq!(storage.get_versions(b"a", 0, 4, 1).unwrap().versions, vec![version(4, 3, None)]);
+}
+
+fn test_flashback(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_flashback").unwrap();
+    let storage = open(&path, storage_type);
+    let long = "x".repeat(1000);
+    put(&storage, "a", "a1", 1, 2);
+    put(&storage, "a", "a2", 4, 5);
+    put(&storage, "b", "b1", 1, 2);
+    delete(&storage, "b", 4, 5);
+    put(&storage, "c", "c1", 4, 5);
+    put(&storage, "d", "d1", 1, 2);
+    put(&storage, "e", &long, 1, 2);
+    put(&storage, "e", "e2", 4, 5);
+    put(&storage, "z", "z1", 1, 2);
+    put(&storage, "z", "z2", 4, 5);
+    // Only the keys of the range changed after the target ts get a new version.
+    assert_eq!(storage.flashback(b"a", b"f", 3, 10).unwrap(), 4);
+    assert_eq!(scan(&storage, "a", "g", 10).unwrap(), vec!["a1", "b1", "d1", long.as_str()]);
+    assert_eq!(get(&storage, "c", 10).unwrap(), None);
+    assert_eq!(get(&storage, "z", 10).unwrap(), Some("z2".to_string()));
+    assert_eq!(scan(&storage, "a", "g", 9).unwrap(), vec!["a2", "c1", "d1", "e2"]);
+    // Running it again after it succeeded writes nothing.
+    assert_eq!(storage.flashback(b"a", b"f", 3, 10).unwrap(), 0);
+    assert_eq!(storage.flashback(b"f", b"a", 3, 11).unwrap(), 0);
+
+    storage.prewrite(b"d", b"d2", 11).unwrap();
+    assert_eq!(storage.flashback(b"a", b"f", 5, 12).unwrap_err(), ERR_KEY_LOCKED);
+    storage.rollback(b"d", 11).unwrap();
+    put(&storage, "c", "c2", 11, 12);
+    assert_eq!(storage.flashback(b"a", b"f", 5, 12).unwrap_err(), ERR_KEY_VERSION);
+    // Nothing is written when it fails.
+    assert_eq!(get(&storage, "a", u64::MAX).unwrap(), Some("a1".to_string()));
+    assert!(storage.flashback(b"a", b"f", 13, 13).is_err());
+    storage.gc(8).unwrap();
+    assert_eq!(storage.flashback(b"a", b"f", 3, 20).unwrap_err(), ERR_TS_TOO_OLD);
+    assert_eq!(storage.flashback(b"a", b"f", 10, 20).unwrap(), 1);
+    assert_eq!(scan(&storage, "a", "g", 20).unwrap(), vec!["a1", "b1", "d1", long.as_str()]);
+}
+
+// A range of more keys than a flashback writes at once.
+fn test_flashback_batches(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_flashback_batches").unwrap();
+    let storage = open(&path, storage_type);
+    let n = FLASHBACK_BATCH_SIZE * 2 + 1;
+    let kvs: Vec<(Value, Value)> = (0..n).map(|i| (format!("k{:05}", i).into_bytes(), b"v".to_vec())).collect();
+    storage.bulk_load(&kvs, 3, 4).unwrap();
+    put(&storage, "l", "l1", 1, 2);
+    assert_eq!(storage.flashback(b"k", b"m", 2, 10).unwrap(), n);
+    assert_eq!(scan(&storage, "k", "m", 10).unwrap(), vec!["l1"]);
+    assert_eq!(scan(&storage, "k", "m", 9).unwrap().len(), n + 1);
+    // Every batch is skipped when it is run again.
+    assert_eq!(storage.flashback(b"k", b"m", 2, 10).unwrap(), 0);
+}
+
+fn test_max_ts(storage_type: StorageType) {
+    let path = TempDir::new("_conformance_max_ts").unwrap();
+    {
+        let storage = open(&path, storage_type);
+        assert_eq!(storage.max_ts().unwrap(), 0);
+        put(&storage, "a", "a1", 1, 2);
+        put(&storage, "b", "b1", 3, 4);
+        put(&storage, "a", "a2", 5, 6);
+        assert_eq!(storage.max_ts().unwrap(), 6);
+        storage.prewrite(b"c", b"c1", 9).unwrap();
+        assert_eq!(storage.max_ts().unwrap(), 9);
+        delete(&storage, "b", 10, 11);
+        storage.bulk_load(&[(b"d".to_vec(), b"d1".to_vec())], 12, 13).unwrap();
+        assert_eq!(storage.max_ts().unwrap(), 13);
+        storage.prewrite(b"e", b"e1", 14).unwrap();
+    }
+    // Not below the data and the recovered locks after a restart.
+    assert!(open(&path, storage_type).max_ts().unwrap() >= 14);
+    // Without a saved mark, e.g. in a restored backup, the data is scanned once.
+    fs::remove_file(path.path().join(MAX_TS_FILE_NAME)).unwrap();
+    assert_eq!(open(&path, storage_type).max_ts().unwrap(), 14);
+}
+
+fn run(storage_type: StorageType) {
+    test_lock(storage_type);
+    test_conflict(storage_type);
+    test_rollback(storage_type);
+    test_resolve_lock(storage_type);
+    test_commit_rollback_race(storage_type);
+    test_delete(storage_type);
+    test_scan(storage_type);
+    test_prefix_keys(storage_type);
+    test_gc(storage_type);
+    test_restart(storage_type);
+    test_stats(storage_type);
+    test_bulk_load(storage_type);
+    test_backup(storage_type);
+    test_batch_get(storage_type);
+    test_snapshot(storage_type);
+    test_read_mode(storage_type);
+    test_get_versions(storage_type);
+    test_flashback(storage_type);
+    test_flashback_batches(storage_type);
+    test_max_ts(storage_type);
+}
+
+#[test]
+fn test_user_timestamp_conformance() {
+    run(StorageType::UserTimestampStorage);
+}
+
+#[test]
+fn test_tikv_conformance() {
+    run(StorageType::TiKVStorage);
+}
+
+#[test]
+#[ignore]
+fn test_unistore_conformance() {
+    // TODO: remove the ignore once unistore can be created by `create_storage`, until
+    // then none of the cases, get_versions included, cover it.
+    run(StorageType::Unistore);
+}
@@ -0,0 +1,144 @@
+///
+/// Crash a two-key transaction at every fail point of every phase, restart the
+/// storage and check that the transaction is either committed or cleanly rolled back.
+/// A crash either kills the process, or is a power failure which also drops the
+/// data not synced to the logs of a storage whose log is synced.
+///
+/// A power failure only truncates the logs, see `fault_fs`: rocksdb is not flushed
+/// in these tests, so everything it holds is in its WAL, but files written by
+/// flushes and compactions would survive the failure untouched.
+///
+
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+use std::u64;
+use tempdir::TempDir;
+
+use super::super::config::Config;
+use super::super::util::fail_point::{self, Action};
+use super::super::util::fault_fs;
+use super::pinned::PinnedValue;
+use super::storage::create_storage_cf;
+use super::{Key, MvccStorage, StorageType, ERR_KEY_LOCKED};
+
+const FAIL_POINTS: &[&str] = &[
+    "wal_before_append",
+    "wal_after_append",
+    "commit_after_db_put",
+    "before_unlock",
+];
+
+const START_TS: u64 = 10;
+const COMMIT_TS: u64 = 11;
+
+fn primary() -> Key {
+    b"primary".to_vec()
+}
+
+fn secondary() -> Key {
+    b"secondary".to_vec()
+}
+
+// Run the phases of the transaction, the fail point is enabled from `crash_phase`
+// on. Return the number of phases which were acknowledged.
+fn run_txn(storage: &Arc<dyn MvccStorage>, fail_point: &str, crash_phase: usize) -> usize {
+    let mut done = 0;
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+        let v = b"v2".to_vec();
+        for phase in 0..4 {
+            if phase == crash_phase {
+                fail_point::set(fail_point, Action::Panic);
+            }
+            match phase {
+                0 => storage.prewrite(&primary(), &v, START_TS).unwrap(),
+                1 => storage.prewrite(&secondary(), &v, START_TS).unwrap(),
+                2 => storage.commit(&primary(), START_TS, COMMIT_TS).unwrap(),
+                _ => storage.commit(&secondary(), START_TS, COMMIT_TS).unwrap(),
+            }
+            done += 1;
+        }
+    }));
+    fail_point::clear();
+    if ret.is_ok() {
+        assert_eq!(done, 4);
+    }
+    done
+}
+
+// Resolve the transaction the way a client finding its locks would, return
+// whether it is committed.
+fn resolve(storage: &Arc<dyn MvccStorage>) -> bool {
+    let committed = if storage.rollback(&primary(), START_TS).is_ok() {
+        false
+    } else {
+        // The primary key is either committed or has never been locked.
+        storage.commit(&primary(), START_TS, COMMIT_TS).is_ok()
+    };
+    let commit_ts = if committed { COMMIT_TS } else { 0 };
+    let ret = storage.resolve_lock(&secondary(), START_TS, commit_ts);
+    if committed {
+        ret.unwrap();
+    }
+    committed
+}
+
+fn check_crash(storage_type: StorageType, fail_point: &str, crash_phase: usize, power_failure: bool) {
+    let path = TempDir::new("_crash_test").unwrap();
+    let path = path.path().to_str().unwrap();
+    let mut config = Config::default();
+    config.memstore.wal_sync = power_failure;
+    let done = {
+        let storage = create_storage_cf(path, storage_type, &config).unwrap();
+        for key in [primary(), secondary()].iter() {
+            storage.prewrite(key, &b"v1".to_vec(), 1).unwrap();
+            storage.commit(key, 1, 2).unwrap();
+        }
+        run_txn(&storage, fail_point, crash_phase)
+    };
+    if power_failure {
+        fault_fs::power_off(Path::new(path)).unwrap();
+    }
+
+    let storage = create_storage_cf(path, storage_type, &config).unwrap();
+    let msg = format!(
+        "{:?} crashed at {} in phase {}, power failure: {}",
+        storage_type, fail_point, crash_phase, power_failure
+    );
+    // A key whose prewrite was acknowledged is still locked, unless its commit
+    // has been started.
+    for (key, prewrite_phase, commit_phase) in [(primary(), 0, 2), (secondary(), 1, 3)].iter() {
+        if done > *prewrite_phase && done < *commit_phase {
+            let err = storage.get(key, u64::MAX).err();
+            assert!(err.map_or(false, |e| e.starts_with(ERR_KEY_LOCKED)), "{}: acknowledged lock is lost", msg);
+        }
+    }
+    let committed = resolve(&storage);
+    if done > 2 {
+        assert!(committed, "{}: acknowledged commit is lost", msg);
+    }
+    let expected = if committed { b"v2".to_vec() } else { b"v1".to_vec() };
+    for key in [primary(), secondary()].iter() {
+        assert_eq!(storage.get(key, 2).unwrap().map(PinnedValue::into_vec), Some(b"v1".to_vec()), "{}", msg);
+        assert_eq!(storage.get(key, u64::MAX).unwrap().map(PinnedValue::into_vec), Some(expected.clone()), "{}", msg);
+    }
+}
+
+fn check_storage(storage_type: StorageType) {
+    for fail_point in FAIL_POINTS.iter() {
+        for crash_phase in 0..4 {
+            check_crash(storage_type, fail_point, crash_phase, false);
+            check_crash(storage_type, fail_point, crash_phase, true);
+        }
+    }
+}
+
+#[test]
+fn test_user_timestamp_crash() {
+    check_storage(StorageType::UserTimestampStorage);
+}
+
+#[test]
+fn test_tikv_crash() {
+    check_storage(StorageType::TiKVStorage);
+}
@@ -0,0 +1,280 @@
+///
+/// Records every call made through `MvccStorage` as a pair of invoke/complete
+/// events, so that a history can be checked offline by `mvcc::checker`.
+///
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{Key, KeyVersions, MvccStorage, ReadMode, Value};
+use super::pinned::PinnedValue;
+use super::snapshot::MvccSnapshot;
+use super::stats::StorageStats;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Prewrite { key: Key, value: Value, start_ts: u64 },
+    Delete { key: Key, start_ts: u64 },
+    Commit { key: Key, start_ts: u64, commit_ts: u64 },
+    Rollback { key: Key, start_ts: u64 },
+    Get { key: Key, ts: u64 },
+    Scan { start: Key, end: Key, ts: u64 },
+    Gc { safe_point: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpResult {
+    Ok,
+    Value(Option<Value>),
+    Values(Vec<Value>),
+    Err(String),
+}
+
+impl OpResult {
+    pub fn is_ok(&self) -> bool {
+        match self {
+            OpResult::Err(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// `time` is a logical clock shared by all events of one recorder, an operation
+/// invoked after another one completed always has a greater time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Invoke { id: u64, time: u64, op: Op },
+    Complete { id: u64, time: u64, result: OpResult },
+}
+
+/// An operation with both of its events.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub id: u64,
+    pub invoke: u64,
+    // `None` if the operation never completed.
+    pub complete: Option<u64>,
+    pub op: Op,
+    pub result: Option<OpResult>,
+}
+
+pub fn operations(events: &[Event]) -> Vec<Operation> {
+    let mut ops: Vec<Operation> = Vec::new();
+    let mut index = super::super::util::collection::HashMap::default();
+    for event in events.iter() {
+        match event {
+            Event::Invoke { id, time, op } => {
+                index.insert(*id, ops.len());
+                ops.push(Operation {
+                    id: *id,
+                    invoke: *time,
+                    complete: None,
+                    op: op.clone(),
+                    result: None,
+                });
+            }
+            Event::Complete { id, time, result } => {
+                if let Some(i) = index.get(id) {
+                    ops[*i].complete = Some(*time);
+                    ops[*i].result = Some(result.clone());
+                }
+            }
+        }
+    }
+    ops
+}
+
+pub struct HistoryRecorder {
+    storage: Arc<dyn MvccStorage>,
+    clock: AtomicU64,
+    next_id: AtomicU64,
+    events: Mutex<Vec<Event>>,
+}
+
+impl HistoryRecorder {
+    pub fn new(storage: Arc<dyn MvccStorage>) -> Self {
+        Self {
+            storage,
+            clock: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn history(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn invoke(&self, op: Op) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut events = self.events.lock().unwrap();
+        let time = self.clock.fetch_add(1, Ordering::SeqCst);
+        events.push(Event::Invoke { id, time, op });
+        id
+    }
+
+    fn complete(&self, id: u64, result: OpResult) {
+        let mut events = self.events.lock().unwrap();
+        let time = self.clock.fetch_add(1, Ordering::SeqCst);
+        events.push(Event::Complete { id, time, result });
+    }
+}
+
+impl MvccStorage for HistoryRecorder {
+    fn prewrite(&self, key: &[u8], value: &[u8], start_ts: u64) -> Result<(), String> {
+        let id = self.invoke(Op::Prewrite {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            start_ts,
+        });
+        let ret = self.storage.prewrite(key, value, start_ts);
+        self.complete(id, unit_result(&ret));
+        ret
+    }
+
+    fn delete(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
+        let id = self.invoke(Op::Delete {
+            key: key.to_vec(),
+            start_ts,
+        });
+        let ret = self.storage.delete(key, start_ts);
+        self.complete(id, unit_result(&ret));
+        ret
+    }
+
+    fn commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        let id = self.invoke(Op::Commit {
+            key: key.to_vec(),
+            start_ts,
+            commit_ts,
+        });
+        let ret = self.storage.commit(key, start_ts, commit_ts);
+        self.complete(id, unit_result(&ret));
+        ret
+    }
+
+    fn rollback(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
+        let id = self.invoke(Op::Rollback {
+            key: key.to_vec(),
+            start_ts,
+        });
+        let ret = self.storage.rollback(key, start_ts);
+        self.complete(id, unit_result(&ret));
+        ret
+    }
+
+    fn get(&self, key: &[u8], ts: u64) -> Result<Option<PinnedValue>, String> {
+        let id = self.invoke(Op::Get { key: key.to_vec(), ts });
+        let ret = self.storage.get(key, ts);
+        let result = match ret {
+            Ok(ref v) => value_result(v),
+            Err(ref e) => OpResult::Err(e.clone()),
+        };
+        self.complete(id, result);
+        ret
+    }
+
+    // Only reads under snapshot isolation are recorded, the checker expects every
+    // read to fail on locks.
+    fn get_opt(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String> {
+        if *mode == ReadMode::default() {
+            return self.get(key, ts);
+        }
+        self.storage.get_opt(key, ts, mode)
+    }
+
+    // Recorded as a get of every key, each of them spans the whole call.
+    fn batch_get(&self, keys: &[&[u8]], ts: u64) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        let ids: Vec<u64> = keys.iter().map(|key| self.invoke(Op::Get { key: key.to_vec(), ts })).collect();
+        let ret = self.storage.batch_get(keys, ts);
+        for (i, id) in ids.into_iter().enumerate() {
+            let result = match ret {
+                Ok(ref results) => match results[i] {
+                    Ok(ref v) => value_result(v),
+                    Err(ref e) => OpResult::Err(e.clone()),
+                },
+                Err(ref e) => OpResult::Err(e.clone()),
+            };
+            self.complete(id, result);
+        }
+        ret
+    }
+
+    fn batch_get_opt(&self, keys: &[&[u8]], ts: u64, mode: &ReadMode) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        if *mode == ReadMode::default() {
+            return self.batch_get(keys, ts);
+        }
+        self.storage.batch_get_opt(keys, ts, mode)
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8], ts: u64) -> Result<Vec<PinnedValue>, String> {
+        let id = self.invoke(Op::Scan {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            ts,
+        });
+        let ret = self.storage.scan(start, end, ts);
+        let result = match ret {
+            Ok(ref v) => OpResult::Values(v.iter().map(|v| v.to_vec()).collect()),
+            Err(ref e) => OpResult::Err(e.clone()),
+        };
+        self.complete(id, result);
+        ret
+    }
+
+    fn scan_opt(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String> {
+        if *mode == ReadMode::default() {
+            return self.scan(start, end, ts);
+        }
+        self.storage.scan_opt(start, end, ts, mode)
+    }
+
+    // Reads of a snapshot are not recorded.
+    fn snapshot(&self, ts: u64) -> Result<Box<dyn MvccSnapshot + '_>, String> {
+        self.storage.snapshot(ts)
+    }
+
+    fn get_versions(&self, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<KeyVersions, String> {
+        self.storage.get_versions(key, from_ts, to_ts, limit)
+    }
+
+    // Not recorded, the checker does not know the versions written by a flashback.
+    fn flashback(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<usize, String> {
+        self.storage.flashback(start, end, target_ts, new_commit_ts)
+    }
+
+    fn gc(&self, safe_point: u64) -> Result<(), String> {
+        let id = self.invoke(Op::Gc { safe_point });
+        let ret = self.storage.gc(safe_point);
+        self.complete(id, unit_result(&ret));
+        ret
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        self.storage.compact()
+    }
+
+    fn max_ts(&self) -> Result<u64, String> {
+        self.storage.max_ts()
+    }
+
+    fn stats(&self) -> Result<StorageStats, String> {
+        self.storage.stats()
+    }
+
+    fn backup(&self, dir: &Path) -> Result<(), String> {
+        self.storage.backup(dir)
+    }
+}
+
+fn value_result(v: &Option<PinnedValue>) -> OpResult {
+    OpResult::Value(v.as_ref().map(|v| v.to_vec()))
+}
+
+fn unit_result(ret: &Result<(), String>) -> OpResult {
+    match ret {
+        Ok(()) => OpResult::Ok,
+        Err(e) => OpResult::Err(e.clone()),
+    }
+}
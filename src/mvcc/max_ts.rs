@@ -0,0 +1,129 @@
+///
+/// The largest ts written into a storage. A high-water mark above it is persisted
+/// in the db directory, so that it is known after a restart without scanning the data.
+///
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use rocksdb::rocksdb_options::{bytes_to_u64, u64_to_bytes};
+
+pub const MAX_TS_FILE_NAME: &str = "MAX_TS";
+
+// The mark is raised this far above a ts passing it, so that it is saved once in
+// many writes instead of on every one.
+const MAX_TS_STEP: u64 = 1 << 16;
+
+pub struct MaxTs {
+    // None if the mark is not persisted.
+    path: Option<PathBuf>,
+    max: AtomicU64,
+    // Every ts written is at most the mark saved in `path`.
+    mark: Mutex<u64>,
+}
+
+impl MaxTs {
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            max: AtomicU64::new(0),
+            mark: Mutex::new(u64::max_value()),
+        }
+    }
+
+    // Load the mark saved in `dir`, the bool is false if none has been saved yet. The
+    // largest ts is only known to be at most the mark until a larger one is written.
+    pub fn open(dir: &Path) -> Result<(Self, bool), String> {
+        let path = dir.join(MAX_TS_FILE_NAME);
+        let mark = if path.exists() {
+            let data = fs::read(&path).map_err(|e| format!("read {:?}: {:?}", path, e))?;
+            if data.len() != 8 {
+                return Err(format!("corrupt {:?}: {:?}", path, data));
+            }
+            Some(bytes_to_u64(&data))
+        } else {
+            None
+        };
+        let max_ts = Self {
+            path: Some(path),
+            max: AtomicU64::new(mark.unwrap_or(0)),
+            mark: Mutex::new(mark.unwrap_or(0)),
+        };
+        Ok((max_ts, mark.is_some()))
+    }
+
+    // Must be called before `ts` is written, so that the saved mark is never below
+    // the data which survives a crash.
+    pub fn observe(&self, ts: u64) -> Result<(), String> {
+        let mut current = self.max.load(Ordering::SeqCst);
+        while current < ts {
+            match self.max.compare_exchange(current, ts, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(max) => current = max,
+            }
+        }
+        let mut mark = self.mark.lock().unwrap();
+        if ts > *mark {
+            let new_mark = ts.saturating_add(MAX_TS_STEP);
+            if let Some(ref path) = self.path {
+                save(path, new_mark)?;
+            }
+            *mark = new_mark;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self) -> u64 {
+        self.max.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for MaxTs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Replace the mark at `path` atomically, as the log of the mem-store is rewritten.
+fn save(path: &Path, mark: u64) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(&u64_to_bytes(mark)).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .and_then(|_| File::open(dir).and_then(|d| d.sync_all()))
+        .map_err(|e| format!("save {:?}: {:?}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_max_ts_mark() {
+        let dir = TempDir::new("_max_ts_mark").unwrap();
+        {
+            let (max_ts, saved) = MaxTs::open(dir.path()).unwrap();
+            assert!(!saved);
+            assert_eq!(max_ts.get(), 0);
+            max_ts.observe(5).unwrap();
+            max_ts.observe(3).unwrap();
+            assert_eq!(max_ts.get(), 5);
+        }
+        let (max_ts, saved) = MaxTs::open(dir.path()).unwrap();
+        assert!(saved);
+        assert_eq!(max_ts.get(), 5 + MAX_TS_STEP);
+        // Writes below the mark do not save it again.
+        max_ts.observe(7).unwrap();
+        assert_eq!(MaxTs::open(dir.path()).unwrap().0.get(), 5 + MAX_TS_STEP);
+        max_ts.observe(6 + MAX_TS_STEP).unwrap();
+        assert_eq!(MaxTs::open(dir.path()).unwrap().0.get(), 6 + 2 * MAX_TS_STEP);
+
+        fs::write(dir.path().join(MAX_TS_FILE_NAME), b"bad").unwrap();
+        assert!(MaxTs::open(dir.path()).is_err());
+    }
+}
@@ -2,45 +2,195 @@
 /// Several kinds of mem-store
 ///
 
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+use super::super::config::{MemStoreConfig, MemStoreKind};
 use super::super::util::collection::HashMap as Map;
-use super::{Key, Value};
-use std::cmp::Ordering;
+use super::wal::{self, Wal};
+use super::{Key, ReadMode, Value};
+
+// The value is None if the key is prewritten to be deleted.
+type V = (u64, Option<Value>);
 
-type V = (u64, Value);
+enum Locks {
+    /// Hash table
+    Hash(Map<Key, V>),
+    /// Ordered by key
+    Ordered(BTreeMap<Key, V>),
+}
 
-/// Hash table
 pub struct MemStore {
     // key -> (ts, value)
-    map: Map<Key, V>,
+    map: Locks,
+    // Every change is logged before it is applied to `map`, None if locks are
+    // not required to survive a restart.
+    wal: Option<Wal>,
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemStore {
     pub fn new() -> Self {
         Self {
-            map: Map::default(),
+            map: Locks::Hash(Map::default()),
+            wal: None,
+        }
+    }
+
+    // Recover the locks logged in `dir`.
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        Self::open_opt(dir, &MemStoreConfig::default())
+    }
+
+    // Recover the locks logged in `dir` if the log is enabled.
+    pub fn open_opt(dir: &Path, config: &MemStoreConfig) -> Result<Self, String> {
+        let (wal, locks) = if config.enable_wal {
+            let (mut wal, locks) = Wal::open(dir)?;
+            wal.set_sync(config.wal_sync);
+            (Some(wal), locks)
+        } else {
+            (None, Map::default())
+        };
+        let map = match config.kind {
+            MemStoreKind::Hash => Locks::Hash(locks),
+            MemStoreKind::Ordered => Locks::Ordered(locks.into_iter().collect()),
+        };
+        Ok(Self { map, wal })
+    }
+
+    pub fn insert(&mut self, key: Key, value: Option<Value>, ts: u64) -> Result<Option<V>, String> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append_lock(&key, value.as_ref(), ts)?;
+        }
+        Ok(match self.map {
+            Locks::Hash(ref mut m) => m.insert(key, (ts, value)),
+            Locks::Ordered(ref mut m) => m.insert(key, (ts, value)),
+        })
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<V>, String> {
+        if let Some(wal) = self.wal.as_mut() {
+            let ts = match self.map {
+                Locks::Hash(ref m) => m.get(key).map(|(ts, _)| *ts),
+                Locks::Ordered(ref m) => m.get(key).map(|(ts, _)| *ts),
+            };
+            if let Some(ts) = ts {
+                wal.append_unlock(key, ts)?;
+            }
+        }
+        Ok(match self.map {
+            Locks::Hash(ref mut m) => m.remove(key),
+            Locks::Ordered(ref mut m) => m.remove(key),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        match self.map {
+            Locks::Hash(ref m) => m.get(key),
+            Locks::Ordered(ref m) => m.get(key),
         }
     }
 
-    pub fn insert(&mut self, key: Key, value: Value, ts: u64) -> Option<V> {
-        self.map.insert(key, (ts, value))
+    // Return whether a read of `key` at `ts` in `mode` fails on its lock.
+    pub fn conflict(&self, key: &[u8], ts: u64, mode: &ReadMode) -> bool {
+        self.get(key).map_or(false, |(start_ts, _)| mode.conflict(*start_ts, ts))
+    }
+
+    // Return whether a read of [start, end) at `ts` in `mode` fails on a lock of a key in it.
+    pub fn range_conflict(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> bool {
+        match self.map {
+            Locks::Hash(ref m) => m
+                .iter()
+                .any(|(key, (start_ts, _))| key.as_slice() >= start && key.as_slice() < end && mode.conflict(*start_ts, ts)),
+            Locks::Ordered(ref m) => {
+                if start >= end {
+                    return false;
+                }
+                m.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                    .any(|(_, (start_ts, _))| mode.conflict(*start_ts, ts))
+            }
+        }
     }
 
-    pub fn contains_key(&self, key: &Key) -> bool {
-        self.map.contains_key(key)
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Key, &'a V)> + 'a> {
+        match self.map {
+            Locks::Hash(ref m) => Box::new(m.iter()),
+            Locks::Ordered(ref m) => Box::new(m.iter()),
+        }
     }
 
-    pub fn remove(&mut self, key: &Key) -> Option<V> {
-        self.map.remove(key)
+    pub fn min_start_ts(&self) -> Option<u64> {
+        self.iter().map(|(_, (start_ts, _))| *start_ts).min()
     }
 
-    pub fn get(&self, key: &Key) -> Option<&V> {
-        self.map.get(key)
+    pub fn max_start_ts(&self) -> Option<u64> {
+        self.iter().map(|(_, (start_ts, _))| *start_ts).max()
     }
-    pub fn range_conflict(&self, start: &Key, end: &Key, ts: u64) -> bool {
-        // TODO:  check whether there is one key locked, which is between range [start,end].
-        return false;
+
+    // Write all locks into a log in `dir`, which is recovered by `open`.
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        wal::save(dir, self.iter())
     }
 }
 
 /// Skip list
 pub struct SkipList {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_memstore_kinds() {
+        for kind in [MemStoreKind::Hash, MemStoreKind::Ordered].iter() {
+            let path = TempDir::new("_memstore").unwrap();
+            let config = MemStoreConfig {
+                kind: *kind,
+                wal_sync: true,
+                ..Default::default()
+            };
+            {
+                let mut store = MemStore::open_opt(path.path(), &config).unwrap();
+                store.insert(b"a".to_vec(), Some(b"v".to_vec()), 5).unwrap();
+                store.insert(b"c".to_vec(), None, 3).unwrap();
+                store.insert(b"e".to_vec(), None, 7).unwrap();
+                store.remove(&b"e".to_vec()).unwrap();
+            }
+            let store = MemStore::open_opt(path.path(), &config).unwrap();
+            assert_eq!(store.get(&b"a".to_vec()), Some(&(5, Some(b"v".to_vec()))));
+            assert!(!store.contains_key(&b"e".to_vec()));
+            assert_eq!(store.min_start_ts(), Some(3));
+            assert_eq!(store.max_start_ts(), Some(5));
+            let si = ReadMode::default();
+            assert!(store.range_conflict(b"b", b"d", 3, &si));
+            assert!(!store.range_conflict(b"b", b"d", 2, &si));
+            assert!(!store.range_conflict(b"d", b"z", 10, &si));
+            assert!(!store.range_conflict(b"d", b"a", 10, &si));
+            assert!(!store.range_conflict(b"b", b"d", 3, &ReadMode::read_committed()));
+            assert!(!store.range_conflict(b"b", b"d", 3, &ReadMode::bypass_locks(vec![3])));
+            assert!(store.conflict(b"a", 5, &ReadMode::bypass_locks(vec![3])));
+
+            // Without the log nothing survives a restart.
+            let config = MemStoreConfig {
+                kind: *kind,
+                enable_wal: false,
+                wal_sync: false,
+            };
+            let mut store = MemStore::open_opt(path.path(), &config).unwrap();
+            assert_eq!(store.iter().count(), 0);
+            store.insert(b"a".to_vec(), None, 1).unwrap();
+            assert_eq!(MemStore::open_opt(path.path(), &config).unwrap().iter().count(), 0);
+        }
+    }
+}
@@ -1,10 +1,32 @@
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use self::pinned::PinnedValue;
+use self::snapshot::MvccSnapshot;
+use self::stats::StorageStats;
+use self::txn_types::{LockType, WriteType};
 
 pub mod user_timestamp;
 pub mod tikv;
 pub mod unistore;
 pub mod memstore;
+pub mod max_ts;
+pub mod wal;
 pub mod storage;
+pub mod history;
+pub mod checker;
+pub mod reference;
+pub mod stats;
+pub mod backup;
+pub mod txn_types;
+pub mod snapshot;
+pub mod pinned;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod model_check;
+#[cfg(test)]
+mod crash_test;
 
 type Key = Vec<u8>;
 type Value = Vec<u8>;
@@ -12,9 +34,14 @@ type Value = Vec<u8>;
 pub type CfName = &'static str;
 pub const CF_DEFAULT: CfName = "default";
 pub const CF_OLD: CfName = "old";
+pub const CF_WRITE: CfName = "write";
 pub const ERR_KEY_LOCKED: &str = "key is locked";
 pub const ERR_KEY_VERSION: &str = "key has been written";
+pub const ERR_TS_TOO_OLD: &str = "ts is older than gc safe point";
+// Keys a flashback writes at once.
+pub const FLASHBACK_BATCH_SIZE: usize = 1024;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StorageType {
     UserTimestampStorage,
     TiKVStorage,
@@ -22,11 +49,279 @@ pub enum StorageType {
     Unknown,
 }
 
+impl StorageType {
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageType::UserTimestampStorage => "user_timestamp",
+            StorageType::TiKVStorage => "tikv",
+            StorageType::Unistore => "unistore",
+            StorageType::Unknown => "unknown",
+        }
+    }
+
+    pub fn from_name(name: &str) -> StorageType {
+        match name {
+            "user_timestamp" => StorageType::UserTimestampStorage,
+            "tikv" => StorageType::TiKVStorage,
+            "unistore" => StorageType::Unistore,
+            _ => StorageType::Unknown,
+        }
+    }
+
+    // Version of the layout of keys and values in the engine, a backup can only be
+    // restored by a model of the same version.
+    pub fn encoding_version(self) -> u32 {
+        match self {
+            StorageType::UserTimestampStorage => user_timestamp::ENCODING_VERSION,
+            StorageType::TiKVStorage => tikv::ENCODING_VERSION,
+            StorageType::Unistore | StorageType::Unknown => 0,
+        }
+    }
+}
+
+// Keys and values are passed as slices, so callers need not own them, and reads
+// return values pinned in the buffers of the engine where a model can avoid a copy.
 pub trait MvccStorage: Sync + Send {
-    fn prewrite(&self, key: &Key, value: &Value, start_ts: u64) -> Result<(), String>;
-    fn commit(&self, key: &Key, start_ts: u64, commit_ts: u64) -> Result<(), String>;
-    fn rollback(&self, key: &Key, start_ts: u64) -> Result<(), String>;
-    fn get(&self, key: &Key, ts: u64) -> Result<Option<Value>, String>;
-    fn scan(&self, start: &Key, end: &Key, ts: u64) -> Result<Vec<Value>, String>;
+    // Models hold the write lock of the mem-store while checking `key`, so that two
+    // transactions can not both pass the check and overwrite each other's lock.
+    fn prewrite(&self, key: &[u8], value: &[u8], start_ts: u64) -> Result<(), String>;
+    // Prewrite a deletion of `key`, which is visible as a missing key once committed.
+    fn delete(&self, key: &[u8], start_ts: u64) -> Result<(), String>;
+    // Commit and rollback hold the write lock from the check of the lock until it is
+    // removed, so that only one of them succeeds for a transaction.
+    fn commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String>;
+    fn rollback(&self, key: &[u8], start_ts: u64) -> Result<(), String>;
+    // Resolve a lock left by a transaction whose primary key has been committed at
+    // `commit_ts`, or rolled back if `commit_ts` is 0.
+    fn resolve_lock(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        if commit_ts == 0 {
+            self.rollback(key, start_ts)
+        } else {
+            self.commit(key, start_ts, commit_ts)
+        }
+    }
+    fn get(&self, key: &[u8], ts: u64) -> Result<Option<PinnedValue>, String> {
+        self.get_opt(key, ts, &ReadMode::default())
+    }
+    // Read `key` at `ts`, the locks met are treated as `mode` tells.
+    fn get_opt(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String>;
+    // Values of `keys` at `ts` in the same order, a locked key fails on its own.
+    fn batch_get(&self, keys: &[&[u8]], ts: u64) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        self.batch_get_opt(keys, ts, &ReadMode::default())
+    }
+    fn batch_get_opt(&self, keys: &[&[u8]], ts: u64, mode: &ReadMode) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        Ok(keys.iter().map(|key| self.get_opt(key, ts, mode)).collect())
+    }
+    // Return values of keys in [start, end).
+    fn scan(&self, start: &[u8], end: &[u8], ts: u64) -> Result<Vec<PinnedValue>, String> {
+        self.scan_opt(start, end, ts, &ReadMode::default())
+    }
+    fn scan_opt(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String>;
+    // A view of the storage at `ts` as it is now, later writes and locks are not seen by it.
+    // Models take the locks and the engine snapshot under the lock of the mem-store, so
+    // that a lock missing from the view has been committed into the engine snapshot if
+    // it is committed at all.
+    fn snapshot(&self, ts: u64) -> Result<Box<dyn MvccSnapshot + '_>, String>;
+    // Versions which are not visible to any read at or after `safe_point` may be
+    // removed, reads before `safe_point` fail with `ERR_TS_TOO_OLD`. Models reject them
+    // before removing anything, so that no reader sees a version being removed.
+    fn gc(&self, safe_point: u64) -> Result<(), String>;
+    // Compact all data of the underlying engine.
+    fn compact(&self) -> Result<(), String>;
+    // Committed versions of `key` in [from_ts, to_ts], the newest first and at most
+    // `limit` of them, and its lock if any. Versions removed by gc are moved into
+    // CF_OLD, which is read too, so they are still returned. The lock is read before
+    // the versions, so a transaction committed meanwhile is seen as its lock, its
+    // version or both, but never missed. Unistore has no storage to list them.
+    fn get_versions(&self, _key: &[u8], _from_ts: u64, _to_ts: u64, _limit: usize) -> Result<KeyVersions, String> {
+        Err(String::from("get_versions is not supported"))
+    }
+    // Write versions committed at `new_commit_ts` into the keys of [start, end) changed
+    // after `target_ts`, so that reads at or after `new_commit_ts` see the range as it
+    // was at `target_ts`. It fails if a key in the range is locked or has a version
+    // at or after `new_commit_ts`, and returns the number of keys written. Models
+    // write the keys in batches of `FLASHBACK_BATCH_SIZE`, prewrites and commits are
+    // blocked while a batch is read and written, so that no key of it is locked or
+    // written meanwhile. Reads at or after `new_commit_ts` may see the range partly
+    // flashed back until it returns, and a failed flashback can be run again.
+    fn flashback(&self, _start: &[u8], _end: &[u8], _target_ts: u64, _new_commit_ts: u64) -> Result<usize, String> {
+        Err(String::from("flashback is not supported"))
+    }
+    // The largest commit ts of a version or start ts of a lock, so that a timestamp
+    // oracle started after it never writes below the data already stored. It may be
+    // larger after a restart, models persist a high-water mark instead of scanning.
+    fn max_ts(&self) -> Result<u64, String>;
+    // Space and write amplification since the storage was opened.
+    fn stats(&self) -> Result<StorageStats, String>;
+    // Save a consistent copy of the data and the locks into `dir`, which must not
+    // exist, to be opened by `backup::restore`. Prewrites and unlocks are blocked
+    // meanwhile, so that the saved locks match the checkpoint.
+    fn backup(&self, _dir: &Path) -> Result<(), String> {
+        Err(String::from("backup is not supported"))
+    }
+    // Write `kvs` as committed by a transaction from `start_ts` to `commit_ts`, keys
+    // must be sorted and distinct. Models write them into the engine directly, which
+    // is only meant to load keys not written before, e.g. by `prepare`. They hold the
+    // write lock of the mem-store from checking the keys until they are ingested, so
+    // that none of them is locked meanwhile. The files are built with the options
+    // their column family was opened with, so that they are compressed and filtered
+    // like the ones written by flushes and compactions.
+    fn bulk_load(&self, kvs: &[(Key, Value)], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        check_bulk_load(kvs, start_ts, commit_ts)?;
+        for (key, value) in kvs.iter() {
+            self.prewrite(key, value, start_ts)?;
+        }
+        for (key, _) in kvs.iter() {
+            self.commit(key, start_ts, commit_ts)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn check_bulk_load(kvs: &[(Key, Value)], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+    if start_ts > commit_ts {
+        return Err(format!("start ts {} is after commit ts {}", start_ts, commit_ts));
+    }
+    for pair in kvs.windows(2) {
+        if pair[0].0 >= pair[1].0 {
+            return Err(String::from("keys to bulk load must be sorted and distinct"));
+        }
+    }
+    Ok(())
+}
+
+pub fn check_flashback(target_ts: u64, new_commit_ts: u64) -> Result<(), String> {
+    if target_ts >= new_commit_ts {
+        return Err(format!("target ts {} is not before the new commit ts {}", target_ts, new_commit_ts));
+    }
+    Ok(())
+}
+
+// Return whether the latest version of a key, committed at `commit_ts` by a transaction
+// started at `start_ts`, is written by a flashback at `new_commit_ts`, which skips the
+// key so that an interrupted flashback can be run again. Any other version at or after
+// `new_commit_ts` fails the flashback.
+pub fn flashed_back(start_ts: u64, commit_ts: u64, new_commit_ts: u64) -> Result<bool, String> {
+    if start_ts == new_commit_ts && commit_ts == new_commit_ts {
+        return Ok(true);
+    }
+    if commit_ts >= new_commit_ts {
+        return Err(String::from(ERR_KEY_VERSION));
+    }
+    Ok(false)
+}
+
+// A committed version of a key, the value is None unless it is a Put.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub commit_ts: u64,
+    pub start_ts: u64,
+    pub write_type: WriteType,
+    pub value: Option<Value>,
+}
+
+// A prewritten change of a key which is not committed yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockInfo {
+    pub start_ts: u64,
+    pub lock_type: LockType,
+    pub value: Option<Value>,
+}
+
+impl LockInfo {
+    // A lock kept by a mem-store, whose value is None for a deletion.
+    pub fn new(start_ts: u64, value: Option<Value>) -> Self {
+        let lock_type = if value.is_some() { LockType::Put } else { LockType::Delete };
+        Self {
+            start_ts,
+            lock_type,
+            value,
+        }
+    }
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyVersions {
+    pub versions: Vec<Version>,
+    pub lock: Option<LockInfo>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsolationLevel {
+    // Snapshot isolation, a read fails on a lock of a transaction started at or
+    // before its ts, which may still commit before it.
+    Si,
+    // Read committed, a read sees the versions committed at or before its ts and
+    // ignores locks.
+    Rc,
+}
+
+// How a read treats locks, reads without a mode are under snapshot isolation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadMode {
+    pub isolation_level: IsolationLevel,
+    // Start ts of transactions whose locks are ignored, the caller knows they are
+    // rolled back or committed after the ts of the read.
+    pub bypass_locks: Vec<u64>,
+}
+
+impl Default for ReadMode {
+    fn default() -> Self {
+        Self {
+            isolation_level: IsolationLevel::Si,
+            bypass_locks: vec![],
+        }
+    }
+}
+
+impl ReadMode {
+    pub fn read_committed() -> Self {
+        Self {
+            isolation_level: IsolationLevel::Rc,
+            bypass_locks: vec![],
+        }
+    }
+
+    pub fn bypass_locks(bypass_locks: Vec<u64>) -> Self {
+        Self {
+            isolation_level: IsolationLevel::Si,
+            bypass_locks,
+        }
+    }
+
+    // Return whether a read at `ts` fails on a lock of the transaction started at `start_ts`.
+    pub fn conflict(&self, start_ts: u64, ts: u64) -> bool {
+        self.isolation_level == IsolationLevel::Si && start_ts <= ts && !self.bypass_locks.contains(&start_ts)
+    }
+}
+
+// Reads before the gc safe point are rejected, the versions they need may have been removed.
+#[derive(Default)]
+pub struct SafePoint {
+    ts: AtomicU64,
+}
+
+impl SafePoint {
+    pub fn new() -> Self {
+        Self {
+            ts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn advance(&self, safe_point: u64) {
+        let mut current = self.ts.load(Ordering::SeqCst);
+        while current < safe_point {
+            match self.ts.compare_exchange(current, safe_point, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(ts) => current = ts,
+            }
+        }
+    }
+
+    pub fn check(&self, ts: u64) -> Result<(), String> {
+        if ts < self.ts.load(Ordering::SeqCst) {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        Ok(())
+    }
+}
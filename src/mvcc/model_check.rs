@@ -0,0 +1,203 @@
+///
+/// Randomized test which runs the same operations against a storage model and
+/// `ReferenceStorage`, and shrinks a diverging sequence to a minimal one.
+///
+
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempdir::TempDir;
+
+use super::history::{Op, OpResult};
+use super::pinned::PinnedValue;
+use super::reference::ReferenceStorage;
+use super::storage::create_storage;
+use super::{Key, MvccStorage, StorageType};
+use super::{ERR_KEY_LOCKED, ERR_KEY_VERSION, ERR_TS_TOO_OLD};
+
+const KEY_NUM: u64 = 5;
+const OPS_PER_CASE: usize = 200;
+const CASES: u64 = 50;
+
+fn key(i: u64) -> Key {
+    format!("k{}", i).into_bytes()
+}
+
+fn apply(storage: &Arc<dyn MvccStorage>, op: &Op) -> OpResult {
+    let ret = match op {
+        Op::Prewrite { key, value, start_ts } => storage.prewrite(key, value, *start_ts).map(|_| OpResult::Ok),
+        Op::Delete { key, start_ts } => storage.delete(key, *start_ts).map(|_| OpResult::Ok),
+        Op::Commit { key, start_ts, commit_ts } => storage.commit(key, *start_ts, *commit_ts).map(|_| OpResult::Ok),
+        Op::Rollback { key, start_ts } => storage.rollback(key, *start_ts).map(|_| OpResult::Ok),
+        Op::Get { key, ts } => storage.get(key, *ts).map(|v| OpResult::Value(v.map(PinnedValue::into_vec))),
+        Op::Scan { start, end, ts } => storage
+            .scan(start, end, *ts)
+            .map(|values| OpResult::Values(values.into_iter().map(PinnedValue::into_vec).collect())),
+        Op::Gc { safe_point } => storage.gc(*safe_point).map(|_| OpResult::Ok),
+    };
+    match ret {
+        Ok(result) => result,
+        // Models are free to word other errors differently.
+        Err(ref e) if e == ERR_KEY_LOCKED || e == ERR_KEY_VERSION || e == ERR_TS_TOO_OLD => {
+            OpResult::Err(e.clone())
+        }
+        Err(_) => OpResult::Err(String::from("error")),
+    }
+}
+
+struct Txn {
+    start_ts: u64,
+    commit_ts: Option<u64>,
+    keys: Vec<Key>,
+}
+
+// Generate operations of concurrent transactions. Only running transactions issue
+// writes and the gc safe point never passes them, the models are allowed to drop
+// anything else once it is older than the safe point.
+fn generate(rng: &mut StdRng, len: usize) -> Vec<Op> {
+    let mut ts = 0;
+    let mut safe_point = 0;
+    let mut txns: Vec<Txn> = vec![];
+    let mut ops = Vec::with_capacity(len);
+    while ops.len() < len {
+        let choice = rng.gen_range(0, 100);
+        if txns.is_empty() || (choice < 10 && txns.len() < 4) {
+            ts += 1;
+            txns.push(Txn {
+                start_ts: ts,
+                commit_ts: None,
+                keys: vec![],
+            });
+            continue;
+        }
+        let i = rng.gen_range(0, txns.len());
+        if choice < 40 {
+            if txns[i].commit_ts.is_some() {
+                continue;
+            }
+            let k = key(rng.gen_range(0, KEY_NUM));
+            let start_ts = txns[i].start_ts;
+            txns[i].keys.push(k.clone());
+            if rng.gen_range(0, 4) == 0 {
+                ops.push(Op::Delete { key: k, start_ts });
+            } else {
+                let value = format!("v{}_{}", start_ts, ops.len()).into_bytes();
+                ops.push(Op::Prewrite { key: k, value, start_ts });
+            }
+        } else if choice < 55 {
+            if txns[i].keys.is_empty() {
+                continue;
+            }
+            if txns[i].commit_ts.is_none() {
+                ts += 1;
+                txns[i].commit_ts = Some(ts);
+            }
+            let k = txns[i].keys[rng.gen_range(0, txns[i].keys.len())].clone();
+            ops.push(Op::Commit {
+                key: k,
+                start_ts: txns[i].start_ts,
+                commit_ts: txns[i].commit_ts.unwrap(),
+            });
+        } else if choice < 62 {
+            if txns[i].keys.is_empty() {
+                continue;
+            }
+            let k = txns[i].keys[rng.gen_range(0, txns[i].keys.len())].clone();
+            ops.push(Op::Rollback { key: k, start_ts: txns[i].start_ts });
+        } else if choice < 67 {
+            // Finish the transaction by resolving all of its locks.
+            let txn = txns.remove(i);
+            for k in txn.keys.into_iter() {
+                ops.push(match txn.commit_ts {
+                    Some(commit_ts) => Op::Commit { key: k, start_ts: txn.start_ts, commit_ts },
+                    None => Op::Rollback { key: k, start_ts: txn.start_ts },
+                });
+            }
+        } else if choice < 82 {
+            let ts = rng.gen_range(0, ts + 2);
+            ops.push(Op::Get { key: key(rng.gen_range(0, KEY_NUM)), ts });
+        } else if choice < 95 {
+            let start = rng.gen_range(0, KEY_NUM);
+            let end = rng.gen_range(start, KEY_NUM + 1);
+            let ts = rng.gen_range(0, ts + 2);
+            ops.push(Op::Scan { start: key(start), end: key(end), ts });
+        } else {
+            let bound = txns.iter().map(|t| t.start_ts - 1).min().unwrap_or(ts);
+            if bound > safe_point {
+                safe_point = rng.gen_range(safe_point, bound + 1);
+            }
+            ops.push(Op::Gc { safe_point });
+        }
+    }
+    ops
+}
+
+// Return the index of the first operation on which the model and the reference disagree.
+fn diverge(storage_type: StorageType, ops: &[Op]) -> Option<(usize, OpResult, OpResult)> {
+    let path = TempDir::new("_model_check").unwrap();
+    let storage = create_storage(path.path().to_str().unwrap(), storage_type).unwrap();
+    let reference: Arc<dyn MvccStorage> = Arc::new(ReferenceStorage::new());
+    for (i, op) in ops.iter().enumerate() {
+        let expected = apply(&reference, op);
+        let actual = apply(&storage, op);
+        if expected != actual {
+            return Some((i, expected, actual));
+        }
+    }
+    None
+}
+
+// Remove chunks of operations, then single ones, as long as the sequence still diverges.
+fn shrink(storage_type: StorageType, ops: Vec<Op>) -> Vec<Op> {
+    let mut ops = ops;
+    if let Some((i, _, _)) = diverge(storage_type, &ops) {
+        ops.truncate(i + 1);
+    }
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let mut candidate = ops[..start].to_vec();
+            candidate.extend_from_slice(&ops[end..]);
+            if let Some((i, _, _)) = diverge(storage_type, &candidate) {
+                candidate.truncate(i + 1);
+                ops = candidate;
+            } else {
+                start += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    ops
+}
+
+fn check_model(storage_type: StorageType) {
+    for seed in 0..CASES {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ops = generate(&mut rng, OPS_PER_CASE);
+        if diverge(storage_type, &ops).is_none() {
+            continue;
+        }
+        let ops = shrink(storage_type, ops);
+        let (i, expected, actual) = diverge(storage_type, &ops).unwrap();
+        for op in ops.iter() {
+            println!("{:?}", op);
+        }
+        panic!(
+            "{:?} diverges from the reference with seed {} at {:?}: expected {:?}, got {:?}",
+            storage_type, seed, ops[i], expected, actual
+        );
+    }
+}
+
+#[test]
+fn test_user_timestamp_model() {
+    check_model(StorageType::UserTimestampStorage);
+}
+
+#[test]
+fn test_tikv_model() {
+    check_model(StorageType::TiKVStorage);
+}
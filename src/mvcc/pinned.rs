@@ -0,0 +1,97 @@
+///
+/// Values returned by reads, which keep the buffer read from the engine instead of
+/// copying the value out of it.
+///
+
+use std::fmt;
+use std::ops::{Deref, Range};
+
+use rocksdb::DBVector;
+
+use super::Value;
+
+pub enum PinnedValue {
+    Owned(Value),
+    // The value at `range` of a buffer returned by the engine, e.g. the short
+    // value of a write record.
+    Engine(DBVector, Range<usize>),
+}
+
+impl PinnedValue {
+    pub fn from_engine(buf: DBVector) -> PinnedValue {
+        let len = buf.len();
+        PinnedValue::Engine(buf, 0..len)
+    }
+
+    // The range of `value` in `buf`, an error if `value` is not borrowed from it.
+    pub fn range_in(buf: &[u8], value: &[u8]) -> Result<Range<usize>, String> {
+        match (value.as_ptr() as usize).checked_sub(buf.as_ptr() as usize) {
+            Some(start) if start <= buf.len() && value.len() <= buf.len() - start => Ok(start..start + value.len()),
+            _ => Err(String::from("value is not a part of the buffer")),
+        }
+    }
+
+    pub fn into_vec(self) -> Value {
+        match self {
+            PinnedValue::Owned(v) => v,
+            PinnedValue::Engine(buf, range) => buf[range].to_vec(),
+        }
+    }
+}
+
+impl Deref for PinnedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PinnedValue::Owned(v) => v,
+            PinnedValue::Engine(buf, range) => &buf[range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Value> for PinnedValue {
+    fn from(v: Value) -> PinnedValue {
+        PinnedValue::Owned(v)
+    }
+}
+
+impl PartialEq for PinnedValue {
+    fn eq(&self, other: &PinnedValue) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Value> for PinnedValue {
+    fn eq(&self, other: &Value) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for PinnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_range_in() {
+        let buf = b"0123456789".to_vec();
+        assert_eq!(PinnedValue::range_in(&buf, &buf[2..5]).unwrap(), 2..5);
+        assert_eq!(PinnedValue::range_in(&buf, &buf[10..]).unwrap(), 10..10);
+        // Values before, after or across the end of the buffer.
+        assert!(PinnedValue::range_in(&buf[5..], &buf[..2]).is_err());
+        assert!(PinnedValue::range_in(&buf[..5], &buf[6..]).is_err());
+        assert!(PinnedValue::range_in(&buf[..5], &buf[3..7]).is_err());
+    }
+}
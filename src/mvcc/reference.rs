@@ -0,0 +1,319 @@
+///
+/// A pure in-memory MVCC, the expected behaviour of every storage model.
+/// It is kept as simple as possible and is not meant to be fast.
+///
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use super::pinned::PinnedValue;
+use super::snapshot::MvccSnapshot;
+use super::stats::StorageStats;
+use super::txn_types::WriteType;
+use super::{check_flashback, flashed_back, Key, KeyVersions, LockInfo, MvccStorage, ReadMode, Value, Version};
+use super::{ERR_KEY_LOCKED, ERR_KEY_VERSION, ERR_TS_TOO_OLD};
+
+#[derive(Clone, Default)]
+struct Inner {
+    // key -> (start_ts, value), the value is None for a deletion.
+    locks: BTreeMap<Key, (u64, Option<Value>)>,
+    // key -> commit_ts -> (start_ts, value)
+    versions: BTreeMap<Key, BTreeMap<u64, (u64, Option<Value>)>>,
+    // Versions removed by gc, which are only listed by `get_versions`.
+    old: BTreeMap<Key, BTreeMap<u64, (u64, Option<Value>)>>,
+    // (key, start_ts) of rolled back locks, a transaction can not lock the key again.
+    rollbacks: BTreeSet<(Key, u64)>,
+    safe_point: u64,
+    logical_bytes_written: u64,
+}
+
+impl Inner {
+    fn commit_ts(&self, key: &[u8], start_ts: u64) -> Option<u64> {
+        self.versions.get(key).and_then(|versions| {
+            versions
+                .iter()
+                .find(|(_, (ts, _))| *ts == start_ts)
+                .map(|(commit_ts, _)| *commit_ts)
+        })
+    }
+
+    fn visible(&self, key: &[u8], ts: u64) -> Option<Value> {
+        self.versions
+            .get(key)
+            .and_then(|versions| versions.range(..=ts).next_back())
+            .and_then(|(_, (_, value))| value.clone())
+    }
+
+    fn get(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String> {
+        if ts < self.safe_point {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        if let Some((start_ts, _)) = self.locks.get(key) {
+            if mode.conflict(*start_ts, ts) {
+                return Err(String::from(ERR_KEY_LOCKED));
+            }
+        }
+        Ok(self.visible(key, ts).map(PinnedValue::from))
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String> {
+        if ts < self.safe_point {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let range = (Bound::Included(start), Bound::Excluded(end));
+        if self.locks.range::<[u8], _>(range).any(|(_, (start_ts, _))| mode.conflict(*start_ts, ts)) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        Ok(self
+            .versions
+            .range::<[u8], _>(range)
+            .filter_map(|(key, _)| self.visible(key, ts).map(PinnedValue::from))
+            .collect())
+    }
+
+    fn prewrite(&mut self, key: &[u8], value: Option<Value>, start_ts: u64) -> Result<(), String> {
+        if self.locks.contains_key(key) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let latest = self.versions.get(key).and_then(|versions| versions.keys().next_back());
+        if let Some(commit_ts) = latest {
+            if *commit_ts >= start_ts {
+                return Err(String::from(ERR_KEY_VERSION));
+            }
+        }
+        if self.rollbacks.contains(&(key.to_vec(), start_ts)) {
+            return Err(String::from(ERR_KEY_VERSION));
+        }
+        self.logical_bytes_written += (key.len() + value.as_ref().map_or(0, |v| v.len())) as u64;
+        self.locks.insert(key.to_vec(), (start_ts, value));
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ReferenceStorage {
+    inner: Mutex<Inner>,
+}
+
+impl ReferenceStorage {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+}
+
+impl MvccStorage for ReferenceStorage {
+    fn prewrite(&self, key: &[u8], value: &[u8], start_ts: u64) -> Result<(), String> {
+        self.inner.lock().unwrap().prewrite(key, Some(value.to_vec()), start_ts)
+    }
+
+    fn delete(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
+        self.inner.lock().unwrap().prewrite(key, None, start_ts)
+    }
+
+    fn commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.locks.get(key) {
+            Some((ts, _)) if *ts == start_ts => {
+                let (_, value) = inner.locks.remove(key).unwrap();
+                inner
+                    .versions
+                    .entry(key.to_vec())
+                    .or_default()
+                    .insert(commit_ts, (start_ts, value));
+                Ok(())
+            }
+            Some(_) => Err(String::from("This key was prewrite by other transaction")),
+            None if inner.commit_ts(key, start_ts) == Some(commit_ts) => Ok(()),
+            None => Err(String::from("rollback-ed by other txn")),
+        }
+    }
+
+    fn rollback(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.locks.get(key) {
+            Some((ts, _)) if *ts == start_ts => {
+                inner.locks.remove(key);
+                inner.rollbacks.insert((key.to_vec(), start_ts));
+                Ok(())
+            }
+            Some(_) => Err(String::from("This key was prewrite by other transaction")),
+            None if inner.commit_ts(key, start_ts).is_some() => {
+                Err(String::from("committed by other txn"))
+            }
+            None => Err(String::from("rollback-ed by other txn")),
+        }
+    }
+
+    fn get_opt(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String> {
+        self.inner.lock().unwrap().get(key, ts, mode)
+    }
+
+    fn batch_get_opt(&self, keys: &[&[u8]], ts: u64, mode: &ReadMode) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        let inner = self.inner.lock().unwrap();
+        if ts < inner.safe_point {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        Ok(keys.iter().map(|key| inner.get(key, ts, mode)).collect())
+    }
+
+    fn scan_opt(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String> {
+        self.inner.lock().unwrap().scan(start, end, ts, mode)
+    }
+
+    fn snapshot(&self, ts: u64) -> Result<Box<dyn MvccSnapshot + '_>, String> {
+        let inner = self.inner.lock().unwrap();
+        if ts < inner.safe_point {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        Ok(Box::new(ReferenceSnapshot {
+            inner: inner.clone(),
+            ts,
+        }))
+    }
+
+    fn get_versions(&self, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<KeyVersions, String> {
+        let inner = self.inner.lock().unwrap();
+        let mut result = KeyVersions::default();
+        if from_ts <= to_ts {
+            let mut all = BTreeMap::new();
+            for versions in [&inner.versions, &inner.old].iter().cloned().filter_map(|m| m.get(key)) {
+                all.extend(versions.range(from_ts..=to_ts));
+            }
+            result.versions = all
+                .into_iter()
+                .rev()
+                .take(limit)
+                .map(|(commit_ts, (start_ts, value))| Version {
+                    commit_ts: *commit_ts,
+                    start_ts: *start_ts,
+                    write_type: if value.is_some() { WriteType::Put } else { WriteType::Delete },
+                    value: value.clone(),
+                })
+                .collect();
+        }
+        result.lock = inner.locks.get(key).map(|(start_ts, value)| LockInfo::new(*start_ts, value.clone()));
+        Ok(result)
+    }
+
+    fn flashback(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<usize, String> {
+        check_flashback(target_ts, new_commit_ts)?;
+        let mut inner = self.inner.lock().unwrap();
+        if target_ts < inner.safe_point {
+            return Err(String::from(ERR_TS_TOO_OLD));
+        }
+        if start >= end {
+            return Ok(0);
+        }
+        let range = (Bound::Included(start), Bound::Excluded(end));
+        if inner.locks.range::<[u8], _>(range).next().is_some() {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let mut changes = vec![];
+        for (key, versions) in inner.versions.range::<[u8], _>(range) {
+            if let Some((commit_ts, (start_ts, latest))) = versions.iter().next_back() {
+                if flashed_back(*start_ts, *commit_ts, new_commit_ts)? || *commit_ts <= target_ts {
+                    continue;
+                }
+                let value = versions.range(..=target_ts).next_back().and_then(|(_, (_, value))| value.clone());
+                if value.is_some() || latest.is_some() {
+                    changes.push((key.clone(), value));
+                }
+            }
+        }
+        let count = changes.len();
+        for (key, value) in changes {
+            inner.logical_bytes_written += (key.len() + value.as_ref().map_or(0, |v| v.len())) as u64;
+            inner.versions.get_mut(&key).unwrap().insert(new_commit_ts, (new_commit_ts, value));
+        }
+        Ok(count)
+    }
+
+    fn gc(&self, safe_point: u64) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(start_ts) = inner.locks.values().map(|(start_ts, _)| *start_ts).min() {
+            if start_ts <= safe_point {
+                return Err(format!("lock of txn {} must be resolved before gc", start_ts));
+            }
+        }
+        if safe_point <= inner.safe_point {
+            return Ok(());
+        }
+        inner.safe_point = safe_point;
+        let rollbacks = inner.rollbacks.iter().filter(|(_, start_ts)| *start_ts > safe_point).cloned().collect();
+        inner.rollbacks = rollbacks;
+        let inner = &mut *inner;
+        for (key, versions) in inner.versions.iter_mut() {
+            // Only the latest version before the safe point is still visible.
+            let old: Vec<u64> = versions.range(..=safe_point).map(|(ts, _)| *ts).collect();
+            let mut removed = old.len();
+            if let Some(ts) = old.last() {
+                if versions[ts].1.is_some() {
+                    removed -= 1;
+                }
+            }
+            for ts in old.iter().take(removed) {
+                let version = versions.remove(ts).unwrap();
+                inner.old.entry(key.clone()).or_insert_with(BTreeMap::new).insert(*ts, version);
+            }
+        }
+        let empty: Vec<Key> = inner
+            .versions
+            .iter()
+            .filter(|(_, versions)| versions.is_empty())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in empty.iter() {
+            inner.versions.remove(key);
+        }
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn max_ts(&self) -> Result<u64, String> {
+        let inner = self.inner.lock().unwrap();
+        let locks = inner.locks.values().map(|(start_ts, _)| *start_ts);
+        let versions = inner.versions.values().filter_map(|versions| versions.keys().next_back().cloned());
+        Ok(locks.chain(versions).max().unwrap_or(0))
+    }
+
+    fn stats(&self) -> Result<StorageStats, String> {
+        let inner = self.inner.lock().unwrap();
+        let mut stats = StorageStats {
+            logical_bytes_written: inner.logical_bytes_written,
+            ..Default::default()
+        };
+        for (key, versions) in inner.versions.iter() {
+            stats.total_versions += versions.len() as u64;
+            if let Some((_, (_, Some(value)))) = versions.iter().next_back() {
+                stats.live_versions += 1;
+                stats.live_bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+// A copy of all locks and versions.
+struct ReferenceSnapshot {
+    inner: Inner,
+    ts: u64,
+}
+
+impl MvccSnapshot for ReferenceSnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<PinnedValue>, String> {
+        self.inner.get(key, self.ts, &ReadMode::default())
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<PinnedValue>, String> {
+        self.inner.scan(start, end, self.ts, &ReadMode::default())
+    }
+}
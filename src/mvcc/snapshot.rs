@@ -0,0 +1,55 @@
+///
+/// Snapshots of a storage at a ts, which serve many reads from one consistent view
+/// of the engine and the locks.
+///
+
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+use super::memstore::MemStore;
+use super::pinned::PinnedValue;
+use super::{Key, ERR_KEY_LOCKED};
+
+pub trait MvccSnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<PinnedValue>, String>;
+    // Values of `keys` in the same order, each read fails on its own.
+    fn batch_get(&self, keys: &[&[u8]]) -> Vec<Result<Option<PinnedValue>, String>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+    // Return values of keys in [start, end).
+    fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<PinnedValue>, String>;
+}
+
+/// Keys locked by transactions started at or before the ts of a snapshot, as they
+/// were when the snapshot was taken. Such a transaction may commit before the ts,
+/// so reads of these keys fail with `ERR_KEY_LOCKED` as `MvccStorage::get` does.
+pub struct SnapshotLocks {
+    keys: BTreeSet<Key>,
+}
+
+impl SnapshotLocks {
+    // Callers hold the lock of `mem_store` until the engine snapshot is taken.
+    pub fn new(mem_store: &MemStore, ts: u64) -> Self {
+        let keys = mem_store
+            .iter()
+            .filter(|(_, (start_ts, _))| *start_ts <= ts)
+            .map(|(key, _)| key.clone())
+            .collect();
+        Self { keys }
+    }
+
+    pub fn check(&self, key: &[u8]) -> Result<(), String> {
+        if self.keys.contains(key) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        Ok(())
+    }
+
+    pub fn check_range(&self, start: &[u8], end: &[u8]) -> Result<(), String> {
+        let range = (Bound::Included(start), Bound::Excluded(end));
+        if start < end && self.keys.range::<[u8], _>(range).next().is_some() {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        Ok(())
+    }
+}
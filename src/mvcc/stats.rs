@@ -0,0 +1,92 @@
+///
+/// Space and write amplification of a storage model.
+///
+
+use std::collections::BTreeMap;
+
+use rocksdb::{DBStatisticsTickerType, DB};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StorageStats {
+    // Bytes of keys and values given to prewrite and delete since the storage was opened.
+    pub logical_bytes_written: u64,
+    // Bytes written by rocksdb since the storage was opened, statistics must be
+    // enabled in the DB options.
+    pub wal_bytes_written: u64,
+    pub flush_bytes_written: u64,
+    pub compaction_bytes_written: u64,
+    // Size of the SST files of each column family.
+    pub sst_size: BTreeMap<String, u64>,
+    // Bytes of keys and values visible to a read at the latest timestamp.
+    pub live_bytes: u64,
+    // Versions visible to a read at the latest timestamp, and all versions kept
+    // readable, the ones moved into CF_OLD by gc are only counted in `sst_size`.
+    pub live_versions: u64,
+    pub total_versions: u64,
+    // Human readable statistics of the engine.
+    #[serde(skip)]
+    pub engine: Option<String>,
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        return 0.0;
+    }
+    a as f64 / b as f64
+}
+
+impl StorageStats {
+    pub fn rocksdb_bytes_written(&self) -> u64 {
+        self.wal_bytes_written + self.flush_bytes_written + self.compaction_bytes_written
+    }
+
+    pub fn total_sst_size(&self) -> u64 {
+        self.sst_size.values().sum()
+    }
+
+    /// Bytes written by rocksdb per logical byte written.
+    pub fn write_amplification(&self) -> f64 {
+        ratio(self.rocksdb_bytes_written(), self.logical_bytes_written)
+    }
+
+    /// Bytes on disk per live logical byte.
+    pub fn space_amplification(&self) -> f64 {
+        ratio(self.total_sst_size(), self.live_bytes)
+    }
+
+    /// Fill in the statistics kept by rocksdb, of all column families of `db`.
+    pub fn collect_engine_stats(&mut self, db: &DB) {
+        self.wal_bytes_written = db.get_statistics_ticker_count(DBStatisticsTickerType::WalFileBytes);
+        self.flush_bytes_written = db.get_statistics_ticker_count(DBStatisticsTickerType::FlushWriteBytes);
+        self.compaction_bytes_written = db.get_statistics_ticker_count(DBStatisticsTickerType::CompactWriteBytes);
+        for name in db.cf_names() {
+            let cf = db.cf_handle(name).unwrap();
+            let size = db.get_property_int_cf(cf, "rocksdb.total-sst-files-size").unwrap_or(0);
+            self.sst_size.insert(name.to_owned(), size);
+        }
+        self.engine = db.get_property_value("rocksdb.stats");
+    }
+
+    pub fn print(&self) {
+        println!(
+            "[SPACE] logical bytes written: {}, rocksdb bytes written: {} (wal: {}, flush: {}, compaction: {}), write amplification: {:.2}",
+            self.logical_bytes_written,
+            self.rocksdb_bytes_written(),
+            self.wal_bytes_written,
+            self.flush_bytes_written,
+            self.compaction_bytes_written,
+            self.write_amplification()
+        );
+        let sst_size: Vec<String> = self.sst_size.iter().map(|(cf, size)| format!("{}: {}", cf, size)).collect();
+        println!(
+            "[SPACE] sst size: {} ({}), live bytes: {}, space amplification: {:.2}, live versions: {}, total versions: {}",
+            self.total_sst_size(),
+            sst_size.join(", "),
+            self.live_bytes,
+            self.space_amplification(),
+            self.live_versions,
+            self.total_versions
+        );
+    }
+}
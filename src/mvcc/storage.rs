@@ -1,19 +1,21 @@
-use rocksdb::{DBOptions, ColumnFamilyOptions};
-use super::StorageType;
+use rocksdb::DBOptions;
+use super::super::config::Config;
+use super::{CfName, StorageType, CF_DEFAULT, CF_OLD, CF_WRITE};
 use std::sync::Arc;
 use super::user_timestamp::create_storage as create_ts_storage;
 use super::user_timestamp::create_storage_cf as create_ts_storage_cf;
 use super::tikv::create_storage as create_tikv_storage;
 use super::tikv::create_storage_cf as create_tikv_storage_cf;
+use super::tikv::build_cf_opts as build_tikv_cf_opts;
 use super::MvccStorage;
 
 pub fn create_storage_opt(path: &str, storage_type: StorageType, option: DBOptions) -> Result<Arc<dyn MvccStorage>, String> {
     match storage_type {
         StorageType::UserTimestampStorage => {
-            return create_ts_storage(option, path);
+            create_ts_storage(option, path)
         },
         StorageType::TiKVStorage => {
-            return create_tikv_storage(option, path);
+            create_tikv_storage(option, path)
         }
         _ => Err(String::from("no support type to create"))
     }
@@ -22,16 +24,31 @@ pub fn create_storage_opt(path: &str, storage_type: StorageType, option: DBOptio
 pub fn create_storage(path: &str, storage_type: StorageType) -> Result<Arc<dyn MvccStorage>, String> {
     let mut option = DBOptions::default();
     option.create_if_missing(true);
-    return create_storage_opt(path, storage_type, option);
+    option.enable_statistics(true);
+    create_storage_opt(path, storage_type, option)
 }
 
-pub fn create_storage_cf(path: &str, storage_type: StorageType, option: DBOptions, cfds: Vec<(&str, ColumnFamilyOptions)>) -> Result<Arc<dyn MvccStorage>, String> {
+// Column families used by a storage model.
+pub fn column_families(storage_type: StorageType) -> &'static [CfName] {
+    match storage_type {
+        StorageType::UserTimestampStorage => &[CF_DEFAULT, CF_OLD],
+        StorageType::TiKVStorage => &[CF_DEFAULT, CF_WRITE, CF_OLD],
+        _ => &[CF_DEFAULT],
+    }
+}
+
+// Create a storage whose rocksdb and mem-store are set up by `config`.
+pub fn create_storage_cf(path: &str, storage_type: StorageType, config: &Config) -> Result<Arc<dyn MvccStorage>, String> {
+    config.validate()?;
+    let option = config.rocksdb.build_opt();
     match storage_type {
         StorageType::UserTimestampStorage => {
-            return create_ts_storage_cf(option, path, cfds);
+            let cfds = config.rocksdb.build_cf_opts(storage_type.name(), column_families(storage_type))?;
+            create_ts_storage_cf(option, path, cfds, &config.memstore, &config.rocksdb.read)
         },
         StorageType::TiKVStorage => {
-            return create_tikv_storage_cf(option, path, cfds);
+            let cfds = build_tikv_cf_opts(&config.rocksdb)?;
+            create_tikv_storage_cf(option, path, cfds, &config.memstore, &config.tikv, &config.rocksdb.read)
         }
         _ => Err(String::from("no support type to create"))
     }
@@ -40,12 +57,12 @@ pub fn create_storage_cf(path: &str, storage_type: StorageType, option: DBOption
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::path::Path;
     use std::str;
     use std::string::String;
     use tempdir::TempDir;
     use std::u64;
-    use super::super::{Value, Key};
+    use super::super::Value;
+    use super::super::pinned::PinnedValue;
     use super::super::{MvccStorage, ERR_KEY_LOCKED, ERR_KEY_VERSION};
 
 
@@ -62,7 +79,7 @@ mod test {
 
     fn read(storage: &Arc<dyn MvccStorage>, key: &str, commit_ts: u64) -> Result<Option<Value>, String> {
         let k = key.as_bytes().to_vec();
-        storage.get(&k, commit_ts)
+        storage.get(&k, commit_ts).map(|v| v.map(PinnedValue::into_vec))
     }
 
     fn inner_test_mvcc_prewrite(storage_type: StorageType) {
@@ -72,7 +89,8 @@ mod test {
         let e = prewrite(&storage, "abcd", "v2", 1).err().unwrap();
         assert_eq!(e, ERR_KEY_LOCKED.to_string());
         commit(&storage, "abcd", 1, 2).unwrap();
-        let value = read(&storage, "abcd", 3).unwrap();
+        let value = read(&storage, "abcd", 3).unwrap().unwrap();
+        assert_eq!(value.as_slice(), b"v1");
         let e = prewrite(&storage, "abcd", "v2", 1).err().unwrap();
         assert_eq!(e, ERR_KEY_VERSION.to_string());
     }
@@ -92,12 +110,12 @@ mod test {
         let ret = read(&storage, "abcd", 2).unwrap();
         assert!(ret.is_some());
         let value = ret.unwrap();
-        assert_eq!(value.as_slice(), "v1".as_bytes());
+        assert_eq!(value.as_slice(), b"v1");
         commit(&storage, "abcd", 3, 3).unwrap();
         let ret = read(&storage, "abcd", 2).unwrap().unwrap();
-        assert_eq!(ret.as_slice(), "v1".as_bytes());
+        assert_eq!(ret.as_slice(), b"v1");
         let ret = read(&storage, "abcd", u64::MAX).unwrap().unwrap();
-        assert_eq!(ret.as_slice(), "v2".as_bytes());
+        assert_eq!(ret.as_slice(), b"v2");
     }
 
 
@@ -109,6 +127,79 @@ mod test {
         inner_test_mvcc_read(StorageType::TiKVStorage);
     }
 
+    #[test]
+    fn test_tikv_short_value() {
+        let path = TempDir::new("_mvcc_tikv_short_value").expect("");
+        let path = path.path().to_str().unwrap();
+        let open = |threshold| {
+            let mut config = Config::default();
+            config.tikv.short_value_threshold = threshold;
+            create_storage_cf(path, StorageType::TiKVStorage, &config).unwrap()
+        };
+        {
+            // Values shorter than 4 bytes are kept in the write record.
+            let storage = open(4);
+            prewrite(&storage, "a", "v1", 1).unwrap();
+            commit(&storage, "a", 1, 2).unwrap();
+            prewrite(&storage, "a", "long1", 3).unwrap();
+            commit(&storage, "a", 3, 4).unwrap();
+            prewrite(&storage, "b", "long2", 3).unwrap();
+            commit(&storage, "b", 3, 4).unwrap();
+            let kvs = vec![(b"c".to_vec(), b"v3".to_vec()), (b"d".to_vec(), b"long4".to_vec())];
+            storage.bulk_load(&kvs, 5, 6).unwrap();
+            assert_eq!(read(&storage, "a", 2).unwrap().unwrap(), b"v1");
+            assert_eq!(read(&storage, "a", 4).unwrap().unwrap(), b"long1");
+            // Short and long values are returned in the buffers read from the engine.
+            for ts in [2, 4].iter() {
+                match storage.get(b"a", *ts).unwrap() {
+                    Some(PinnedValue::Engine(..)) => (),
+                    v => panic!("value is not pinned: {:?}", v),
+                }
+            }
+            let values = storage.scan(&b"a".to_vec(), &b"z".to_vec(), 6).unwrap();
+            assert_eq!(values, vec![b"long1".to_vec(), b"long2".to_vec(), b"v3".to_vec(), b"long4".to_vec()]);
+            for v in values.iter() {
+                if let PinnedValue::Owned(_) = v {
+                    panic!("value is not pinned: {:?}", v);
+                }
+            }
+            assert_eq!(storage.stats().unwrap().live_bytes, 4 + 5 + 5 + 2 + 5);
+        }
+        // Versions written with another threshold are still readable.
+        let storage = open(0);
+        prewrite(&storage, "a", "v5", 7).unwrap();
+        commit(&storage, "a", 7, 8).unwrap();
+        assert_eq!(read(&storage, "a", 2).unwrap().unwrap(), b"v1");
+        assert_eq!(read(&storage, "a", 8).unwrap().unwrap(), b"v5");
+        storage.gc(8).unwrap();
+        assert_eq!(read(&storage, "a", 8).unwrap().unwrap(), b"v5");
+        assert_eq!(read(&storage, "d", 8).unwrap().unwrap(), b"long4");
+        assert_eq!(storage.stats().unwrap().total_versions, 4);
+    }
+
+    #[test]
+    fn test_read_options() {
+        for storage_type in [StorageType::UserTimestampStorage, StorageType::TiKVStorage].iter() {
+            for enabled in [true, false].iter() {
+                let path = TempDir::new("_mvcc_read_options").expect("");
+                let mut config = Config::default();
+                config.rocksdb.read.prefix_seek = *enabled;
+                config.rocksdb.read.iterate_upper_bound = *enabled;
+                let storage = create_storage_cf(path.path().to_str().unwrap(), *storage_type, &config).unwrap();
+                for (key, ts) in [("a", 1), ("ab", 3), ("a", 5), ("b", 7)].iter() {
+                    prewrite(&storage, key, &format!("{}{}", key, ts), *ts).unwrap();
+                    commit(&storage, key, *ts, *ts + 1).unwrap();
+                }
+                assert_eq!(read(&storage, "a", 4).unwrap().unwrap(), b"a1");
+                assert_eq!(read(&storage, "a", 6).unwrap().unwrap(), b"a5");
+                assert!(read(&storage, "a\x00", 8).unwrap().is_none());
+                assert_eq!(prewrite(&storage, "ab", "v", 3).unwrap_err(), ERR_KEY_VERSION);
+                let values = storage.scan(&b"a".to_vec(), &b"b".to_vec(), 8).unwrap();
+                assert_eq!(values, vec![b"a5".to_vec(), b"ab3".to_vec()]);
+            }
+        }
+    }
+
     #[test]
     fn test_user_timestamp_storage() {
         println!("====prewrite start");
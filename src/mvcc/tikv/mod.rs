@@ -1,30 +1,61 @@
-
 use std::string::String;
 use std::u64;
-use std::io::Write;
+use std::path::Path;
 
 use super::{Key, Value};
 
-use rocksdb::{DB, ReadOptions, SeekKey, DBOptions, ColumnFamilyOptions};
-use std::collections::{HashSet};
-use super::super::util::engine::FixedSuffixSliceTransform;
+use rocksdb::{CFHandle, DBIterator, DBVector, DB, ReadOptions, SeekKey, DBOptions, ColumnFamilyOptions, Snapshot, WriteBatch, WriteOptions};
+use super::super::util::codec::{decode_bytes, encode_bytes, encode_u64_desc, encode_versioned_key, encode_versioned_key_to, split_versioned_key, versioned_key_end, TS_LEN};
+use super::super::util::engine::{get_cf_handle, ingest_sorted, new_engine_opt, CFOptions, DbReader, FixedSuffixSliceTransform};
+use super::max_ts::MaxTs;
 use super::memstore::MemStore;
-use super::{MvccStorage, CF_DEFAULT, ERR_KEY_VERSION, ERR_KEY_LOCKED};
+use super::{check_bulk_load, check_flashback, flashed_back, KeyVersions, LockInfo, MvccStorage, ReadMode, SafePoint, StorageType, Version, CF_DEFAULT, CF_OLD, CF_WRITE, ERR_KEY_VERSION, ERR_KEY_LOCKED, FLASHBACK_BATCH_SIZE};
+use super::backup;
+use super::pinned::PinnedValue;
+use super::snapshot::{MvccSnapshot, SnapshotLocks};
+use super::txn_types::{Write, WriteRef, WriteType};
+use super::stats::StorageStats;
 use std::sync::{Arc, RwLock};
-use rocksdb::rocksdb_options::{bytes_to_u64, u64_to_bytes};
+use std::sync::atomic::{AtomicU64, Ordering};
 use rocksdb::rocksdb::Writable;
+#[cfg(test)]
+use super::super::util::{fail_point, fault_fs};
+use super::super::config::{CfConfig, DbConfig, MemStoreConfig, PrefixExtractor, ReadConfig, TikvConfig};
+
+// Bumped whenever the layout of keys or values changes.
+pub const ENCODING_VERSION: u32 = 5;
+
+// Records gc moves or deletes in one write batch.
+const GC_BATCH_SIZE: usize = 1024;
 
-const TIMESTAMP_LEN: usize = 16;
+// A committed version is a `Write` record in CF_WRITE, keyed by the key and the commit
+// ts. A value shorter than the short value threshold is kept in the record, a longer
+// one in CF_DEFAULT, keyed by the key and the start ts, and read by a second lookup.
+// A version removed by gc is moved into CF_OLD under the same key as in CF_WRITE,
+// with its value kept in the record whatever its length.
 
 pub struct Storage {
     // Store pre-write result.
-    // TODO: add wal for mem_store
-    // latch_map: HashSet<Key>,
     mem_store: RwLock<MemStore>,
 
     // Only committed value can write to DB.
-
     db: DB,
+
+    safe_point: SafePoint,
+
+    max_ts: MaxTs,
+
+    logical_bytes_written: AtomicU64,
+
+    short_value_threshold: usize,
+
+    read: ReadConfig,
+
+    // Options each column family was opened with, for the files built by `bulk_load`.
+    cf_opts: Vec<(String, ColumnFamilyOptions)>,
+
+    // Sync commits to rocksdb, see `MemStoreConfig::wal_sync`.
+    sync: bool,
 }
 
 impl Storage {
@@ -32,16 +63,261 @@ impl Storage {
         Self {
             mem_store: RwLock::new(MemStore::new()),
             db,
+            safe_point: SafePoint::new(),
+            max_ts: MaxTs::new(),
+            logical_bytes_written: AtomicU64::new(0),
+            short_value_threshold: TikvConfig::default().short_value_threshold,
+            read: ReadConfig::default(),
+            cf_opts: vec![],
+            sync: false,
+        }
+    }
+
+    // Open a storage whose locks are logged in `path` and survive a restart,
+    // unless the log is disabled in `memstore`.
+    pub fn open(db: DB, path: &str, cf_opts: Vec<(String, ColumnFamilyOptions)>, memstore: &MemStoreConfig, config: &TikvConfig, read: &ReadConfig) -> Result<Self, String> {
+        let (max_ts, saved) = MaxTs::open(Path::new(path))?;
+        let storage = Self {
+            mem_store: RwLock::new(MemStore::open_opt(Path::new(path), memstore)?),
+            db,
+            safe_point: SafePoint::new(),
+            max_ts,
+            logical_bytes_written: AtomicU64::new(0),
+            short_value_threshold: config.short_value_threshold,
+            read: read.clone(),
+            cf_opts,
+            sync: memstore.wal_sync,
+        };
+        storage.recover()?;
+        if !saved {
+            // The data was written before the mark, e.g. it is restored from a backup.
+            storage.max_ts.observe(storage.scan_max_ts()?)?;
+        }
+        Ok(storage)
+    }
+
+    fn cf(&self, name: &str) -> Result<&CFHandle, String> {
+        get_cf_handle(&self.db, name)
+    }
+
+    fn cf_options(&self, name: &str) -> ColumnFamilyOptions {
+        self.cf_opts
+            .iter()
+            .find(|(cf, _)| cf == name)
+            .map_or_else(ColumnFamilyOptions::new, |(_, opts)| opts.clone())
+    }
+
+    fn latest(&self) -> DbReader {
+        DbReader::Db(&self.db)
+    }
+
+    // Iterate the write records of a single key at a time, the newest first. An iterator
+    // which is not given the key is bounded by the prefix only, to be reused for other keys.
+    fn key_write_iter<'a>(&self, r: &DbReader<'a>, key: Option<&[u8]>) -> Result<DBIterator<&'a DB>, String> {
+        self.key_iter(r, CF_WRITE, key)
+    }
+
+    // Iterate records of `cf` keyed by versioned keys, like `key_write_iter`.
+    fn key_iter<'a>(&self, r: &DbReader<'a>, cf: &str, key: Option<&[u8]>) -> Result<DBIterator<&'a DB>, String> {
+        let mut read_opt = ReadOptions::new();
+        read_opt.set_prefix_same_as_start(self.read.prefix_seek);
+        if let Some(key) = key {
+            if self.read.iterate_upper_bound {
+                read_opt.set_iterate_upper_bound(versioned_key_end(key));
+            }
+        }
+        Ok(r.iter_cf_opt(self.cf(cf)?, read_opt))
+    }
+
+    // Iterate write records across keys, up to the encoded key `end` if given.
+    fn range_write_iter<'a>(&self, r: &DbReader<'a>, end: Option<Vec<u8>>) -> Result<DBIterator<&'a DB>, String> {
+        let mut read_opt = ReadOptions::new();
+        // Do not let the prefix extractor of CF_WRITE skip other keys.
+        read_opt.set_total_order_seek(true);
+        if let Some(end) = end {
+            if self.read.iterate_upper_bound {
+                read_opt.set_iterate_upper_bound(end);
+            }
+        }
+        Ok(r.iter_cf_opt(self.cf(CF_WRITE)?, read_opt))
+    }
+
+    // The record of a version, and its value if it is too long to be kept in the record.
+    fn split_value<'a>(&self, value: Option<&'a [u8]>, start_ts: u64) -> (WriteRef<'a>, Option<&'a [u8]>) {
+        let mut write = WriteRef {
+            write_type: WriteType::Put,
+            start_ts,
+            commit_ts: 0,
+            short_value: None,
+        };
+        match value {
+            Some(v) if v.len() < self.short_value_threshold => {
+                write.short_value = Some(v);
+                (write, None)
+            }
+            Some(v) => (write, Some(v)),
+            None => {
+                write.write_type = WriteType::Delete;
+                (write, None)
+            }
+        }
+    }
+
+    // Return the value of the version whose record `write` is at `write_key` in CF_WRITE,
+    // None if it is a deletion. The value stays pinned in the buffer returned by the
+    // engine. `write` may be borrowed from an iterator, whose values are only valid
+    // until it moves, so a short value is read again with its record.
+    fn load_value(&self, r: &DbReader, write_key: &[u8], write: WriteRef) -> Result<Option<PinnedValue>, String> {
+        if write.write_type != WriteType::Put {
+            return Ok(None);
+        }
+        let (encoded_key, commit_ts) = split_versioned_key(write_key)?;
+        if write.short_value.is_none() {
+            return Ok(Some(PinnedValue::from_engine(self.load_long_value(r, encoded_key, write.start_ts)?)));
+        }
+        let data = match r.get_cf(self.cf(CF_WRITE)?, write_key)? {
+            Some(data) => data,
+            None => return Err(format!("write of {:?} committed at {} is missing", encoded_key, commit_ts)),
+        };
+        let range = match WriteRef::parse(&data)?.short_value {
+            Some(v) => PinnedValue::range_in(&data, v)?,
+            None => return Err(format!("short value of {:?} committed at {} is missing", encoded_key, commit_ts)),
+        };
+        Ok(Some(PinnedValue::Engine(data, range)))
+    }
+
+    // Like `load_value`, but a short value is copied out of `write`, for callers which
+    // do not return the value.
+    fn copy_value(&self, r: &DbReader, encoded_key: &[u8], write: WriteRef) -> Result<Option<Value>, String> {
+        if write.write_type != WriteType::Put {
+            return Ok(None);
+        }
+        match write.short_value {
+            Some(v) => Ok(Some(v.to_vec())),
+            None => Ok(Some(self.load_long_value(r, encoded_key, write.start_ts)?.to_vec())),
+        }
+    }
+
+    fn load_long_value(&self, r: &DbReader, encoded_key: &[u8], start_ts: u64) -> Result<DBVector, String> {
+        let mut data_key = Vec::with_capacity(encoded_key.len() + TS_LEN);
+        data_key.extend_from_slice(encoded_key);
+        data_key.extend_from_slice(&encode_u64_desc(start_ts));
+        r.get_cf(self.cf(CF_DEFAULT)?, &data_key)?
+            .ok_or_else(|| format!("value of {:?} written at {} is missing", encoded_key, start_ts))
+    }
+
+    // Return the value of `key` at `ts`, read by `iter` from the write records of `key`.
+    // The seek key is encoded into `buf`, which callers reading many keys reuse.
+    fn read_version(&self, r: &DbReader, iter: &mut DBIterator<&DB>, buf: &mut Vec<u8>, key: &[u8], ts: u64) -> Result<Option<PinnedValue>, String> {
+        // The first version not newer than `ts`, rollback records are no versions.
+        buf.clear();
+        encode_versioned_key_to(buf, key, ts);
+        iter.seek(SeekKey::Key(buf));
+        while iter.valid() {
+            let (write_key, _) = split_versioned_key(iter.key())?;
+            if write_key != &buf[..buf.len() - TS_LEN] {
+                break;
+            }
+            let write = WriteRef::parse(iter.value())?;
+            if write.write_type != WriteType::Rollback {
+                return self.load_value(r, iter.key(), write);
+            }
+            iter.next();
+        }
+        Ok(None)
+    }
+
+    // Read every key whose result is not an error yet by one iterator, which seeks
+    // to the keys in order so that it only moves forward.
+    fn read_versions(&self, r: &DbReader, keys: &[&[u8]], ts: u64, results: &mut Vec<Result<Option<PinnedValue>, String>>) -> Result<(), String> {
+        let mut order: Vec<usize> = (0..keys.len()).filter(|i| results[*i].is_ok()).collect();
+        order.sort_by(|a, b| keys[*a].cmp(keys[*b]));
+        let mut iter = self.key_write_iter(r, None)?;
+        let mut buf = vec![];
+        for i in order {
+            results[i] = self.read_version(r, &mut iter, &mut buf, keys[i], ts);
+        }
+        Ok(())
+    }
+
+    fn scan_versions(&self, r: &DbReader, start: &[u8], end: &[u8], ts: u64) -> Result<Vec<PinnedValue>, String> {
+        let end = encode_bytes(end);
+        let mut iter = self.range_write_iter(r, Some(end.clone()))?;
+        let mut result = Vec::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        iter.seek(SeekKey::Key(&encode_bytes(start)));
+        while iter.valid() {
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            if write_key >= end.as_slice() {
+                break;
+            }
+            // Versions of a key are sorted by commit ts in descending order, only
+            // the first one not newer than `ts` is visible.
+            let visited = last_key.as_ref().map_or(false, |last| last.as_slice() == write_key);
+            let write = WriteRef::parse(iter.value())?;
+            if !visited && commit_ts <= ts && write.write_type != WriteType::Rollback {
+                if let Some(value) = self.load_value(r, iter.key(), write)? {
+                    result.push(value);
+                }
+                last_key = Some(write_key.to_vec());
+            }
+            iter.next();
+        }
+        Ok(result)
+    }
+
+    // Remove the locks of transactions which crashed after writing DB but before unlocking.
+    fn recover(&self) -> Result<(), String> {
+        let mut mem_store = self.mem_store.write().unwrap();
+        let locks: Vec<(Key, u64)> = mem_store.iter().map(|(key, (ts, _))| (key.clone(), *ts)).collect();
+        for (key, start_ts) in locks {
+            if self.get_commit_ts(&key, start_ts)?.is_some() {
+                mem_store.remove(&key)?;
+            }
         }
+        Ok(())
     }
 
-    fn get_uncommitted_data(&self, key: &Key, start_ts: u64) -> Result<Option<Value>, String> {
-        let mut mem_store = self.mem_store.read().unwrap();
-        match mem_store.get(&key) {
+    // Return whether the lock of `start_ts` is removed. A rolled back lock leaves a
+    // rollback record, written before the lock is removed so that a late prewrite of
+    // the transaction always finds one of them. A version of another transaction
+    // committed at `start_ts` has the same key, it is kept instead and fails the
+    // late prewrite as well.
+    fn unlock_uncommitted_data(&self, mem_store: &mut MemStore, key: &[u8], start_ts: u64, rollback: bool) -> Result<bool, String> {
+        match mem_store.get(key) {
+            Some((timestamp, _)) if *timestamp == start_ts => (),
+            Some(_) => return Err(String::from("This key was prewrite by other transaction")),
+            None => return Ok(false),
+        }
+        let write_key = encode_versioned_key(key, start_ts);
+        if rollback && self.db.get_cf(self.cf(CF_WRITE)?, &write_key)?.is_none() {
+            let wb = WriteBatch::new();
+            let write = Write::new(WriteType::Rollback, start_ts, None);
+            wb.put_cf(self.cf(CF_WRITE)?, &write_key, &write.to_bytes())?;
+            let mut write_opt = WriteOptions::new();
+            write_opt.set_sync(self.sync);
+            self.db.write_opt(&wb, &write_opt)?;
+        }
+        #[cfg(test)]
+        fail_point::eval("before_unlock")?;
+        mem_store.remove(key)?;
+        Ok(true)
+    }
+
+    // Return a batch committing the prewritten value if `key` is locked by `start_ts`,
+    // it is filled under the lock instead of copying the value out.
+    fn uncommitted_batch(&self, mem_store: &MemStore, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<Option<WriteBatch>, String> {
+        match mem_store.get(key) {
             Some((timestamp, value)) => {
                 if *timestamp == start_ts {
                     // Pre-write result is ok
-                    Ok(Some(value.clone()))
+                    let (write, long_value) = self.split_value(value.as_ref().map(|v| v.as_slice()), start_ts);
+                    let wb = WriteBatch::new();
+                    if let Some(v) = long_value {
+                        wb.put_cf(self.cf(CF_DEFAULT)?, &encode_versioned_key(key, start_ts), v)?;
+                    }
+                    wb.put_cf(self.cf(CF_WRITE)?, &encode_versioned_key(key, commit_ts), &write.to_bytes())?;
+                    Ok(Some(wb))
                 } else {
                     // Rollback-ed or committed by other txn
                     Err(String::from("This key was prewrite by other transaction"))
@@ -50,131 +326,554 @@ impl Storage {
             None => Ok(None)
         }
     }
-}
 
-impl MvccStorage  for Storage {
-    fn prewrite(&self, key: &Key, value: &Value, ts: u64) -> Result<(), String> {
-         // TODO: check write conflict
-        if self.mem_store.read().unwrap().contains_key(&key) {
+    // Return the commit ts of the transaction started at `start_ts`, or None if
+    // it has not been committed.
+    fn get_commit_ts(&self, key: &[u8], start_ts: u64) -> Result<Option<u64>, String> {
+        let mut iter = self.key_write_iter(&self.latest(), Some(key))?;
+        let seek_key = encode_versioned_key(key, u64::MAX);
+        let encoded = &seek_key[..seek_key.len() - TS_LEN];
+        iter.seek(SeekKey::Key(&seek_key));
+        while iter.valid() {
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            if write_key != encoded || commit_ts < start_ts {
+                break;
+            }
+            let write = WriteRef::parse(iter.value())?;
+            if write.start_ts == start_ts && write.write_type != WriteType::Rollback {
+                return Ok(Some(commit_ts));
+            }
+            iter.next();
+        }
+        Ok(None)
+    }
+
+    fn prewrite_mutation(&self, key: &[u8], value: Option<&[u8]>, ts: u64) -> Result<(), String> {
+        let mut mem_store = self.mem_store.write().unwrap();
+        if mem_store.contains_key(key) {
             return Err(String::from(ERR_KEY_LOCKED));
         }
-        let mut read_opt = ReadOptions::new();
-        // read_opt.set_prefix_same_as_start(true);
-        let mut iter = self.db.iter_opt(read_opt);
-        let key_ts = encode_ts_to_key(u64::MAX, key);
-        iter.seek(SeekKey::Key(&key_ts));
-        if iter.valid() {
-            let k = iter.key();
-            let commit_ts = decode_ts_from_key(k);
-            let k_len = k.len();
-            let write_key = k[..(k_len-8)].to_vec();
-            if write_key == *key && commit_ts >= ts {
+        let mut iter = self.key_write_iter(&self.latest(), Some(key))?;
+        let seek_key = encode_versioned_key(key, u64::MAX);
+        iter.seek(SeekKey::Key(&seek_key));
+        while iter.valid() {
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            if write_key != &seek_key[..seek_key.len() - TS_LEN] || commit_ts < ts {
+                break;
+            }
+            // A rollback record only fails the transaction it rolled back.
+            if WriteRef::parse(iter.value())?.write_type != WriteType::Rollback || commit_ts == ts {
                 return Err(String::from(ERR_KEY_VERSION));
             }
+            iter.next();
+        }
+        self.max_ts.observe(ts)?;
+        mem_store.insert(key.to_vec(), value.map(|v| v.to_vec()), ts)?;
+        let bytes = key.len() + value.map_or(0, |v| v.len());
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // The largest commit ts of a version, found by reading the latest version of every key.
+    fn scan_max_ts(&self) -> Result<u64, String> {
+        let mut max_ts = 0;
+        let mut iter = self.range_write_iter(&self.latest(), None)?;
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            // The first record of a key is its latest version, the older ones are skipped.
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            max_ts = max_ts.max(commit_ts);
+            let next = versioned_key_end(&decode_bytes(write_key)?.0);
+            iter.seek(SeekKey::Key(&next));
+        }
+        Ok(max_ts)
+    }
+
+    // Flash back at most `FLASHBACK_BATCH_SIZE` keys of [start, end) at once, return the
+    // number of keys written and the first changed key left for the next batch.
+    fn flashback_batch(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<(usize, Option<Key>), String> {
+        let mem_store = self.mem_store.write().unwrap();
+        if mem_store.range_conflict(start, end, u64::MAX, &ReadMode::default()) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let r = self.latest();
+        // Keys changed after `target_ts`, and whether their latest version is a Put.
+        let mut changed = vec![];
+        let mut resume = None;
+        {
+            let end = encode_bytes(end);
+            let mut iter = self.range_write_iter(&r, Some(end.clone()))?;
+            let mut last_key: Option<Vec<u8>> = None;
+            iter.seek(SeekKey::Key(&encode_bytes(start)));
+            while iter.valid() {
+                let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+                if write_key >= end.as_slice() {
+                    break;
+                }
+                // The latest version of a key comes first.
+                let visited = last_key.as_ref().map_or(false, |last| last.as_slice() == write_key);
+                let write = WriteRef::parse(iter.value())?;
+                if !visited && write.write_type != WriteType::Rollback {
+                    if !flashed_back(write.start_ts, commit_ts, new_commit_ts)? && commit_ts > target_ts {
+                        let key = decode_bytes(write_key)?.0;
+                        if changed.len() == FLASHBACK_BATCH_SIZE {
+                            resume = Some(key);
+                            break;
+                        }
+                        changed.push((key, write.write_type == WriteType::Put));
+                    }
+                    last_key = Some(write_key.to_vec());
+                }
+                iter.next();
+            }
+        }
+        let mut iter = self.key_write_iter(&r, None)?;
+        let wb = WriteBatch::new();
+        let mut buf = vec![];
+        let (mut count, mut bytes) = (0, 0);
+        for (key, latest_put) in changed {
+            let value = self.read_version(&r, &mut iter, &mut buf, &key, target_ts)?;
+            // Missing both at `target_ts` and now.
+            if value.is_none() && !latest_put {
+                continue;
+            }
+            let (write, long_value) = self.split_value(value.as_ref().map(|v| &**v), new_commit_ts);
+            if let Some(v) = long_value {
+                wb.put_cf(self.cf(CF_DEFAULT)?, &encode_versioned_key(&key, new_commit_ts), v)?;
+            }
+            wb.put_cf(self.cf(CF_WRITE)?, &encode_versioned_key(&key, new_commit_ts), &write.to_bytes())?;
+            count += 1;
+            bytes += key.len() + value.as_ref().map_or(0, |v| v.len());
+        }
+        let mut write_opt = WriteOptions::new();
+        write_opt.set_sync(self.sync);
+        self.db.write_opt(&wb, &write_opt)?;
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok((count, resume))
+    }
+
+    // The newest `limit` versions of `key` in [from_ts, to_ts] kept in `cf`, which is
+    // CF_WRITE or CF_OLD.
+    fn list_versions(&self, r: &DbReader, cf: &str, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<Vec<Version>, String> {
+        let mut iter = self.key_iter(r, cf, Some(key))?;
+        let seek_key = encode_versioned_key(key, to_ts);
+        let encoded = &seek_key[..seek_key.len() - TS_LEN];
+        let mut versions = vec![];
+        iter.seek(SeekKey::Key(&seek_key));
+        while iter.valid() && versions.len() < limit {
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            if write_key != encoded || commit_ts < from_ts {
+                break;
+            }
+            let write = WriteRef::parse(iter.value())?;
+            if write.write_type != WriteType::Rollback {
+                versions.push(Version {
+                    commit_ts,
+                    start_ts: write.start_ts,
+                    write_type: write.write_type,
+                    value: self.copy_value(r, write_key, write)?,
+                });
+            }
+            iter.next();
+        }
+        Ok(versions)
+    }
+
+    // Walk all versions of every key, the latest version of a key comes first.
+    fn count_versions(&self, stats: &mut StorageStats) -> Result<(), String> {
+        let mut iter = self.range_write_iter(&self.latest(), None)?;
+        let mut last_key: Option<Vec<u8>> = None;
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            let (write_key, _) = split_versioned_key(iter.key())?;
+            let write = WriteRef::parse(iter.value())?;
+            if write.write_type == WriteType::Rollback {
+                iter.next();
+                continue;
+            }
+            let visited = last_key.as_ref().map_or(false, |last| last.as_slice() == write_key);
+            if !visited {
+                if let Some(value) = self.copy_value(&self.latest(), write_key, write)? {
+                    stats.live_versions += 1;
+                    stats.live_bytes += (decode_bytes(write_key)?.0.len() + value.len()) as u64;
+                }
+                last_key = Some(write_key.to_vec());
+            }
+            stats.total_versions += 1;
+            iter.next();
         }
-        let mut mem_store = self.mem_store.write().unwrap();
-        mem_store.insert(key.clone(), value.clone(), ts);
         Ok(())
     }
+}
+
+impl MvccStorage  for Storage {
+    fn prewrite(&self, key: &[u8], value: &[u8], ts: u64) -> Result<(), String> {
+        self.prewrite_mutation(key, Some(value), ts)
+    }
+
+    fn delete(&self, key: &[u8], ts: u64) -> Result<(), String> {
+        self.prewrite_mutation(key, None, ts)
+    }
 
-    fn commit(&self, key: &Key, start_ts: u64, commit_ts: u64) -> Result<(), String> {
-        if let Some(value) = self.get_uncommitted_data(&key, start_ts)? {
-            let mut v = encode_ts_to_value(start_ts, &value);
-            let key_ts = encode_ts_to_key(commit_ts, key);
-            self.db.put(&key_ts, &v);
+    fn commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        // The write guard is held until the lock is removed, so that a rollback can not
+        // remove it after the batch is filled.
+        let mut mem_store = self.mem_store.write().unwrap();
+        if let Some(wb) = self.uncommitted_batch(&mem_store, key, start_ts, commit_ts)? {
+            self.max_ts.observe(commit_ts)?;
+            let mut write_opt = WriteOptions::new();
+            write_opt.set_sync(self.sync);
+            self.db.write_opt(&wb, &write_opt)?;
+            #[cfg(test)]
             {
-                let mut mem_store = self.mem_store.write().unwrap();
-                mem_store.remove(&key);
+                if self.sync {
+                    fault_fs::synced_logs(Path::new(self.db.path()));
+                }
+                fail_point::eval("commit_after_db_put")?;
             }
+            self.unlock_uncommitted_data(&mut mem_store, key, start_ts, false)?;
             return Ok(());
         }
+        drop(mem_store);
         // Find to see if it is committed or rollback-ed
-        return Err(String::from("rollback-ed by other txn"));
+        let key_ts = encode_versioned_key(key, commit_ts);
+        let committed = match self.db.get_cf(self.cf(CF_WRITE)?, &key_ts)? {
+            Some(v) => {
+                let write = WriteRef::parse(&v)?;
+                write.start_ts == start_ts && write.write_type != WriteType::Rollback
+            }
+            None => false,
+        };
+        if committed {
+            Ok(())
+        } else {
+            Err(String::from("rollback-ed by other txn"))
+        }
+    }
+
+    fn rollback(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
+        if self.unlock_uncommitted_data(&mut self.mem_store.write().unwrap(), key, start_ts, true)? {
+            return Ok(());
+        }
+        if self.get_commit_ts(key, start_ts)?.is_some() {
+            Err(String::from("committed by other txn"))
+        } else {
+            Err(String::from("rollback-ed by other txn"))
+        }
+    }
+
+    fn get_opt(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String> {
+        self.safe_point.check(ts)?;
+        if self.mem_store.read().unwrap().conflict(key, ts, mode) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let r = self.latest();
+        let mut iter = self.key_write_iter(&r, Some(key))?;
+        self.read_version(&r, &mut iter, &mut vec![], key, ts)
+    }
+
+    fn batch_get_opt(&self, keys: &[&[u8]], ts: u64, mode: &ReadMode) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        self.safe_point.check(ts)?;
+        let mut results: Vec<Result<Option<PinnedValue>, String>> = {
+            let mem_store = self.mem_store.read().unwrap();
+            keys.iter()
+                .map(|key| {
+                    if mem_store.conflict(key, ts, mode) {
+                        Err(String::from(ERR_KEY_LOCKED))
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .collect()
+        };
+        self.read_versions(&self.latest(), keys, ts, &mut results)?;
+        Ok(results)
+    }
+
+    fn scan_opt(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String> {
+        self.safe_point.check(ts)?;
+        if self.mem_store.read().unwrap().range_conflict(start, end, ts, mode) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        self.scan_versions(&self.latest(), start, end, ts)
+    }
+
+    fn snapshot(&self, ts: u64) -> Result<Box<dyn MvccSnapshot + '_>, String> {
+        self.safe_point.check(ts)?;
+        let mem_store = self.mem_store.read().unwrap();
+        let locks = SnapshotLocks::new(&mem_store, ts);
+        let snap = self.db.snapshot();
+        Ok(Box::new(StorageSnapshot {
+            storage: self,
+            snap,
+            locks,
+            ts,
+        }))
+    }
+
+    fn get_versions(&self, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<KeyVersions, String> {
+        let lock = self.mem_store.read().unwrap().get(key).map(|(start_ts, value)| LockInfo::new(*start_ts, value.clone()));
+        // Both column families are read from one snapshot, so that a version moved by
+        // gc meanwhile is listed once.
+        let snap = self.db.snapshot();
+        let r = DbReader::Snapshot(&snap);
+        let mut versions = self.list_versions(&r, CF_WRITE, key, from_ts, to_ts, limit)?;
+        versions.extend(self.list_versions(&r, CF_OLD, key, from_ts, to_ts, limit)?);
+        versions.sort_by(|a, b| b.commit_ts.cmp(&a.commit_ts));
+        versions.truncate(limit);
+        Ok(KeyVersions { versions, lock })
     }
 
-    fn rollback(&self, key: &Key, ts: u64) -> Result<(), String> {
-        // todo
-        Err(String::from("not support"))
+    fn flashback(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<usize, String> {
+        check_flashback(target_ts, new_commit_ts)?;
+        self.safe_point.check(target_ts)?;
+        self.max_ts.observe(new_commit_ts)?;
+        let mut count = 0;
+        let mut next = Some(start.to_vec());
+        while let Some(batch_start) = next {
+            let (written, resume) = self.flashback_batch(&batch_start, end, target_ts, new_commit_ts)?;
+            count += written;
+            next = resume;
+        }
+        Ok(count)
     }
 
-    fn get(&self, key: &Key, ts: u64) -> Result<Option<Value>, String> {
-        if let Some((start_ts, _)) = self.mem_store.read().unwrap().get(key) {
-            if *start_ts <= ts {
-                return Err(String::from(ERR_KEY_LOCKED));
+    fn gc(&self, safe_point: u64) -> Result<(), String> {
+        if let Some(start_ts) = self.mem_store.read().unwrap().min_start_ts() {
+            if start_ts <= safe_point {
+                return Err(format!("lock of txn {} must be resolved before gc", start_ts));
             }
         }
-        let mut read_opt = ReadOptions::new();
-        let mut iter = self.db.iter_opt(read_opt);
-        let key_ts = encode_ts_to_key(ts, key);
-        iter.seek(SeekKey::Key(&key_ts));
-        if iter.valid() {
-            let k = iter.key();
-            let l = k.len();
-            let commit_ts = bytes_to_u64(&k[l-8..]);
-            let write_key = k[..l-8].to_vec();
-            if write_key == *key && commit_ts <= ts {
-                let value = iter.value();
-                return Ok(Some(decode_data_from_value(value)))
+        self.safe_point.advance(safe_point);
+        let r = self.latest();
+        let mut iter = self.range_write_iter(&r, None)?;
+        let (default_cf, write_cf, old_cf) = (self.cf(CF_DEFAULT)?, self.cf(CF_WRITE)?, self.cf(CF_OLD)?);
+        let mut wb = WriteBatch::new();
+        let mut batched = 0;
+        let mut last_key: Option<Vec<u8>> = None;
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            if batched == GC_BATCH_SIZE {
+                self.db.write(&wb)?;
+                wb = WriteBatch::new();
+                batched = 0;
+            }
+            let (write_key, commit_ts) = split_versioned_key(iter.key())?;
+            if commit_ts <= safe_point {
+                let visited = last_key.as_ref().map_or(false, |last| last.as_slice() == write_key);
+                let write = WriteRef::parse(iter.value())?;
+                // No transaction started before the safe point prewrites anymore, its
+                // rollback record can be dropped without hiding the version behind it.
+                if write.write_type == WriteType::Rollback {
+                    wb.delete_cf(write_cf, iter.key())?;
+                    batched += 1;
+                    iter.next();
+                    continue;
+                }
+                // Keep the latest version before the safe point unless it is a deletion,
+                // the others are moved into CF_OLD for `get_versions`.
+                if visited || write.write_type != WriteType::Put {
+                    let value = self.copy_value(&r, write_key, write)?;
+                    let old = WriteRef {
+                        short_value: value.as_ref().map(|v| v.as_slice()),
+                        ..write
+                    };
+                    wb.put_cf(old_cf, iter.key(), &old.to_bytes())?;
+                    wb.delete_cf(write_cf, iter.key())?;
+                    if write.write_type == WriteType::Put && write.short_value.is_none() {
+                        let mut data_key = write_key.to_vec();
+                        data_key.extend_from_slice(&encode_u64_desc(write.start_ts));
+                        wb.delete_cf(default_cf, &data_key)?;
+                    }
+                    batched += 1;
+                }
+                last_key = Some(write_key.to_vec());
             }
+            iter.next();
         }
-        Ok(None)
+        self.db.write(&wb)
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        for name in [CF_DEFAULT, CF_WRITE, CF_OLD].iter() {
+            self.db.compact_range_cf(self.cf(name)?, None, None);
+        }
+        Ok(())
+    }
+
+    fn max_ts(&self) -> Result<u64, String> {
+        let max_start_ts = self.mem_store.read().unwrap().max_start_ts().unwrap_or(0);
+        Ok(self.max_ts.get().max(max_start_ts))
     }
 
-    fn scan(&self, start: &Key, end: &Key, ts: u64) -> Result<Vec<Value>, String> {
-        Err(String::from("not support"))
+    fn stats(&self) -> Result<StorageStats, String> {
+        let mut stats = StorageStats {
+            logical_bytes_written: self.logical_bytes_written.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        stats.collect_engine_stats(&self.db);
+        self.count_versions(&mut stats)?;
+        Ok(stats)
+    }
+
+    fn backup(&self, dir: &Path) -> Result<(), String> {
+        let mem_store = self.mem_store.write().unwrap();
+        backup::checkpoint(&self.db, &mem_store, StorageType::TiKVStorage, dir)
+    }
+
+    fn bulk_load(&self, kvs: &[(Key, Value)], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        check_bulk_load(kvs, start_ts, commit_ts)?;
+        let mem_store = self.mem_store.write().unwrap();
+        if kvs.iter().any(|(key, _)| mem_store.contains_key(key)) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let mut values = vec![];
+        let mut writes = vec![];
+        for (key, value) in kvs.iter() {
+            let (write, long_value) = self.split_value(Some(value), start_ts);
+            if let Some(v) = long_value {
+                values.push((encode_versioned_key(key, start_ts), v.to_vec()));
+            }
+            writes.push((encode_versioned_key(key, commit_ts), write.to_bytes()));
+        }
+        self.max_ts.observe(commit_ts)?;
+        // Values first, so that no record refers to a value not ingested yet.
+        ingest_sorted(&self.db, CF_DEFAULT, self.cf_options(CF_DEFAULT), values)?;
+        ingest_sorted(&self.db, CF_WRITE, self.cf_options(CF_WRITE), writes)?;
+        let bytes: usize = kvs.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
     }
 }
 
-fn encode_ts_to_value(ts: u64, value: &Value) -> Value {
-    let mut res = value.clone();
-    // res.append(ts.to_be_bytes());
-    let mut ts_data = u64_to_bytes(ts);
-    // TODO:  expected mutable reference, found array of 8 elements
-    res.append(&mut ts_data);
-    res
+pub struct StorageSnapshot<'a> {
+    storage: &'a Storage,
+    snap: Snapshot<&'a DB>,
+    locks: SnapshotLocks,
+    ts: u64,
 }
 
-fn encode_ts_to_key(ts: u64, key: &Key) -> Key {
-    let mut res = key.clone();
-    let mut ts_data= u64_to_bytes(ts);
-    res.append(&mut ts_data);
-    res
+impl<'a> MvccSnapshot for StorageSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<PinnedValue>, String> {
+        self.locks.check(key)?;
+        let r = DbReader::Snapshot(&self.snap);
+        let mut iter = self.storage.key_write_iter(&r, Some(key))?;
+        self.storage.read_version(&r, &mut iter, &mut vec![], key, self.ts)
+    }
+
+    fn batch_get(&self, keys: &[&[u8]]) -> Vec<Result<Option<PinnedValue>, String>> {
+        let mut results: Vec<Result<Option<PinnedValue>, String>> =
+            keys.iter().map(|key| self.locks.check(key).map(|_| None)).collect();
+        let r = DbReader::Snapshot(&self.snap);
+        if let Err(e) = self.storage.read_versions(&r, keys, self.ts, &mut results) {
+            return keys.iter().map(|_| Err(e.clone())).collect();
+        }
+        results
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<PinnedValue>, String> {
+        self.locks.check_range(start, end)?;
+        self.storage.scan_versions(&DbReader::Snapshot(&self.snap), start, end, self.ts)
+    }
 }
 
-fn decode_ts_from_value(value: &[u8]) -> u64 {
-    // u64::from_be_bytes(&value.as_slice()[..8].as_ref())
-    let l = value.len();
-    return bytes_to_u64(&value[l-8..]);
+pub fn create_storage(options: DBOptions, path: &str) -> Result<Arc<dyn MvccStorage>, String> {
+    let mut cfds = vec![];
+    for name in [CF_DEFAULT, CF_WRITE, CF_OLD].iter() {
+        let mut cf = ColumnFamilyOptions::new();
+        let f = Box::new(FixedSuffixSliceTransform::new(8));
+        cf.set_prefix_extractor("FixedSuffixSliceTransform", f).unwrap();
+        cfds.push((*name, cf));
+    }
+    create_storage_cf(options, path, cfds, &MemStoreConfig::default(), &TikvConfig::default(), &ReadConfig::default())
 }
 
-fn decode_data_from_value(value: &[u8]) -> Vec<u8> {
-    let l = value.len();
-    return value[..(l-8)].to_vec();
+// Keys of every column family end with an 8 bytes ts, the rest of a key is its
+// prefix unless another prefix extractor is configured.
+fn cf_config(cf: &CfConfig) -> CfConfig {
+    let mut cf = cf.clone();
+    if cf.prefix_extractor == PrefixExtractor::None {
+        cf.prefix_extractor = PrefixExtractor::FixedSuffix;
+        cf.prefix_extractor_len = 8;
+    }
+    cf
 }
 
-fn decode_ts_from_key(key: &[u8]) -> u64 {
-    let l = key.len();
-    return bytes_to_u64(&key[l-8..]);
+pub fn build_cf_opts(config: &DbConfig) -> Result<Vec<(&'static str, ColumnFamilyOptions)>, String> {
+    let model = StorageType::TiKVStorage.name();
+    let mut opts = vec![];
+    for name in [CF_DEFAULT, CF_WRITE, CF_OLD].iter() {
+        opts.push((*name, cf_config(config.cf_config(model, name)).build_opt()?));
+    }
+    Ok(opts)
 }
 
-pub fn create_storage(options: DBOptions, path: &str) -> Result<Arc<dyn MvccStorage>, String> {
-    let mut cfoption = ColumnFamilyOptions::new();
-    let f = Box::new(FixedSuffixSliceTransform::new(8));
-    cfoption.set_prefix_extractor("FixedSuffixSliceTransform", f).unwrap();
-    let mut cfds = Vec::new();
-    cfds.push((CF_DEFAULT, cfoption));
-    let mut db = DB::open_cf(options, path, cfds)?;
-    let storage = Storage::new(db);
-    return Ok(Arc::new(storage));
+pub fn create_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>, memstore: &MemStoreConfig, config: &TikvConfig, read: &ReadConfig) -> Result<Arc<dyn MvccStorage>, String> {
+    let storage = open_storage_cf(options, path, cfds, memstore, config, read)?;
+    Ok(Arc::new(storage))
 }
 
-pub fn create_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>) -> Result<Arc<dyn MvccStorage>, String> {
-    let mut cfds = cfds;
-    for (_, cf) in cfds.iter_mut() {
-        let f = Box::new(FixedSuffixSliceTransform::new(8));
-        cf.set_prefix_extractor("FixedSuffixSliceTransform", f).unwrap();
+fn open_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>, memstore: &MemStoreConfig, config: &TikvConfig, read: &ReadConfig) -> Result<Storage, String> {
+    let mut cfs_opts = vec![];
+    let mut cf_opts = vec![];
+    for (name, cf) in cfds {
+        cf_opts.push((name.to_owned(), cf.clone()));
+        cfs_opts.push(CFOptions::new(name, cf));
     }
-    let mut db = DB::open_cf(options, path, cfds)?;
-    let storage = Storage::new(db);
-    return Ok(Arc::new(storage));
-}
\ No newline at end of file
+    let db = new_engine_opt(path, options, cfs_opts)?;
+    Storage::open(db, path, cf_opts, memstore, config, read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::config::{CompressionType, Config};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_bulk_load_cf_options() {
+        let path = TempDir::new("_tikv_bulk_load_cf_options").unwrap();
+        let mut config = Config::default();
+        config.rocksdb.defaultcf.compression = CompressionType::No;
+        // Keep the value in CF_DEFAULT, so that both column families get a file.
+        config.tikv.short_value_threshold = 0;
+        let cfds = build_cf_opts(&config.rocksdb).unwrap();
+        let storage = open_storage_cf(config.rocksdb.build_opt(), path.path().to_str().unwrap(), cfds, &config.memstore, &config.tikv, &config.rocksdb.read).unwrap();
+        storage.bulk_load(&[(b"k".to_vec(), b"v".to_vec())], 1, 2).unwrap();
+        for cf in [CF_DEFAULT, CF_WRITE].iter() {
+            let tables = storage.db.get_properties_of_all_tables_cf(storage.cf(cf).unwrap()).unwrap();
+            assert_eq!(tables.len(), 1);
+            for (_, props) in tables.iter() {
+                assert_eq!(props.compression_name(), "NoCompression");
+                assert_eq!(props.filter_policy_name(), "rocksdb.BuiltinBloomFilter");
+            }
+        }
+        assert_eq!(storage.get(b"k", 2).unwrap().unwrap(), b"v".to_vec());
+    }
+
+    #[test]
+    fn test_prefix_extractor() {
+        let path = TempDir::new("_tikv_prefix_extractor").unwrap();
+        let mut config = Config::default();
+        config.tikv.short_value_threshold = 0;
+        let mut write = CfConfig::default();
+        write.prefix_extractor = PrefixExtractor::FixedPrefix;
+        write.prefix_extractor_len = 1;
+        config.rocksdb.cf.insert(CF_WRITE.to_owned(), write);
+        let cfds = build_cf_opts(&config.rocksdb).unwrap();
+        let storage = open_storage_cf(config.rocksdb.build_opt(), path.path().to_str().unwrap(), cfds, &config.memstore, &config.tikv, &config.rocksdb.read).unwrap();
+        storage.bulk_load(&[(b"k".to_vec(), b"v".to_vec())], 1, 2).unwrap();
+        assert_eq!(storage.get(b"k", 2).unwrap().map(PinnedValue::into_vec), Some(b"v".to_vec()));
+        // The configured extractor is kept, the fixed suffix one is only a default.
+        for (cf, extractor) in [(CF_DEFAULT, "FixedSuffixSliceTransform"), (CF_WRITE, "FixedPrefixSliceTransform")].iter() {
+            let tables = storage.db.get_properties_of_all_tables_cf(storage.cf(cf).unwrap()).unwrap();
+            assert_eq!(tables.len(), 1);
+            for (_, props) in tables.iter() {
+                assert_eq!(props.prefix_extractor_name(), *extractor);
+                let user_props = props.user_collected_properties();
+                let whole_key = user_props.get("rocksdb.block.based.table.whole.key.filtering");
+                assert_eq!(whole_key, Some(&b"0"[..]));
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+///
+/// Records of a transaction: a lock from prewrite to commit, and a write for every
+/// committed version.
+///
+/// A record starts with the version of its format and its type, followed by the
+/// fields of every record of the type, and then optional fields as a tag, length
+/// and payload. Fields unknown to a decoder are skipped, so new ones can be added
+/// without changing the version, which is only bumped when old decoders can not
+/// read a record anymore.
+///
+/// Version 2 of a lock drops the primary key and the ttl of version 1, which the
+/// mem-store never knew, version 1 locks are still read.
+///
+
+use super::super::util::codec::{corrupted, decode_var_u64, encode_var_u64};
+use super::Value;
+
+const FORMAT_VERSION: u8 = 1;
+const LOCK_FORMAT_VERSION: u8 = 2;
+
+// Tags of optional fields.
+const TAG_SHORT_VALUE: u8 = b'v';
+const TAG_COMMIT_TS: u8 = b'C';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockType {
+    Put,
+    Delete,
+    Lock,
+    Pessimistic,
+}
+
+impl LockType {
+    fn to_u8(self) -> u8 {
+        match self {
+            LockType::Put => b'P',
+            LockType::Delete => b'D',
+            LockType::Lock => b'L',
+            LockType::Pessimistic => b'S',
+        }
+    }
+
+    fn from_u8(b: u8) -> Option<LockType> {
+        match b {
+            b'P' => Some(LockType::Put),
+            b'D' => Some(LockType::Delete),
+            b'L' => Some(LockType::Lock),
+            b'S' => Some(LockType::Pessimistic),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteType {
+    Put,
+    Delete,
+    Lock,
+    Rollback,
+}
+
+impl WriteType {
+    fn to_u8(self) -> u8 {
+        match self {
+            WriteType::Put => b'P',
+            WriteType::Delete => b'D',
+            WriteType::Lock => b'L',
+            WriteType::Rollback => b'R',
+        }
+    }
+
+    fn from_u8(b: u8) -> Option<WriteType> {
+        match b {
+            b'P' => Some(WriteType::Put),
+            b'D' => Some(WriteType::Delete),
+            b'L' => Some(WriteType::Lock),
+            b'R' => Some(WriteType::Rollback),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lock {
+    pub lock_type: LockType,
+    pub start_ts: u64,
+    // Optional fields, None if absent.
+    pub short_value: Option<Value>,
+}
+
+impl Lock {
+    pub fn new(lock_type: LockType, start_ts: u64, short_value: Option<Value>) -> Lock {
+        Lock {
+            lock_type,
+            start_ts,
+            short_value,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![LOCK_FORMAT_VERSION, self.lock_type.to_u8()];
+        encode_var_u64(&mut buf, self.start_ts);
+        if let Some(ref v) = self.short_value {
+            encode_field(&mut buf, TAG_SHORT_VALUE, v);
+        }
+        buf
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Lock, String> {
+        let (mut r, version) = Reader::new(data, "lock", LOCK_FORMAT_VERSION)?;
+        let lock_type = LockType::from_u8(r.u8()?).ok_or_else(|| r.corrupted())?;
+        if version == 1 {
+            // The primary key.
+            let len = r.var_u64()? as usize;
+            r.bytes(len)?;
+        }
+        let start_ts = r.var_u64()?;
+        if version == 1 {
+            // The ttl.
+            r.var_u64()?;
+        }
+        let mut lock = Lock::new(lock_type, start_ts, None);
+        while let Some((tag, payload)) = r.field()? {
+            if tag == TAG_SHORT_VALUE {
+                lock.short_value = Some(payload.to_vec());
+            }
+        }
+        Ok(lock)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Write {
+    pub write_type: WriteType,
+    pub start_ts: u64,
+    // Optional fields, 0 or None if absent. The commit ts is only kept by models
+    // whose engine does not return the ts of a version.
+    pub commit_ts: u64,
+    pub short_value: Option<Value>,
+}
+
+impl Write {
+    pub fn new(write_type: WriteType, start_ts: u64, short_value: Option<Value>) -> Write {
+        Write {
+            write_type,
+            start_ts,
+            commit_ts: 0,
+            short_value,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let write = WriteRef {
+            write_type: self.write_type,
+            start_ts: self.start_ts,
+            commit_ts: self.commit_ts,
+            short_value: self.short_value.as_ref().map(|v| v.as_slice()),
+        };
+        write.to_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Write, String> {
+        WriteRef::parse(data).map(|write| write.to_owned())
+    }
+}
+
+// A write whose short value is borrowed from the record it is parsed from, so a
+// read can return the value without copying it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteRef<'a> {
+    pub write_type: WriteType,
+    pub start_ts: u64,
+    pub commit_ts: u64,
+    pub short_value: Option<&'a [u8]>,
+}
+
+impl<'a> WriteRef<'a> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![FORMAT_VERSION, self.write_type.to_u8()];
+        encode_var_u64(&mut buf, self.start_ts);
+        if self.commit_ts > 0 {
+            encode_u64_field(&mut buf, TAG_COMMIT_TS, self.commit_ts);
+        }
+        if let Some(v) = self.short_value {
+            encode_field(&mut buf, TAG_SHORT_VALUE, v);
+        }
+        buf
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<WriteRef<'a>, String> {
+        let (mut r, _) = Reader::new(data, "write", FORMAT_VERSION)?;
+        let write_type = WriteType::from_u8(r.u8()?).ok_or_else(|| r.corrupted())?;
+        let mut write = WriteRef {
+            write_type,
+            start_ts: r.var_u64()?,
+            commit_ts: 0,
+            short_value: None,
+        };
+        while let Some((tag, payload)) = r.field()? {
+            match tag {
+                TAG_COMMIT_TS => write.commit_ts = r.u64_payload(payload)?,
+                TAG_SHORT_VALUE => write.short_value = Some(payload),
+                _ => (),
+            }
+        }
+        Ok(write)
+    }
+
+    pub fn to_owned(&self) -> Write {
+        Write {
+            write_type: self.write_type,
+            start_ts: self.start_ts,
+            commit_ts: self.commit_ts,
+            short_value: self.short_value.map(|v| v.to_vec()),
+        }
+    }
+}
+
+fn encode_field(buf: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    buf.push(tag);
+    encode_var_u64(buf, payload.len() as u64);
+    buf.extend_from_slice(payload);
+}
+
+fn encode_u64_field(buf: &mut Vec<u8>, tag: u8, v: u64) {
+    let mut payload = vec![];
+    encode_var_u64(&mut payload, v);
+    encode_field(buf, tag, &payload);
+}
+
+// Reads a record, every error tells which record is corrupted.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    // The reader after the version, and the version, which is at most `max_version`.
+    fn new(data: &'a [u8], what: &'static str, max_version: u8) -> Result<(Reader<'a>, u8), String> {
+        let mut r = Reader { data, pos: 0, what };
+        let version = r.u8()?;
+        if version == 0 || version > max_version {
+            return Err(format!("unsupported format version {} of {}: {:?}", version, what, data));
+        }
+        Ok((r, version))
+    }
+
+    fn corrupted(&self) -> String {
+        corrupted(self.what, self.data)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(self.corrupted());
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn var_u64(&mut self) -> Result<u64, String> {
+        let (v, len) = decode_var_u64(&self.data[self.pos..]).map_err(|_| self.corrupted())?;
+        self.pos += len;
+        Ok(v)
+    }
+
+    // The next optional field, or None at the end of the record.
+    fn field(&mut self) -> Result<Option<(u8, &'a [u8])>, String> {
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+        let tag = self.u8()?;
+        let len = self.var_u64()? as usize;
+        Ok(Some((tag, self.bytes(len)?)))
+    }
+
+    fn u64_payload(&self, payload: &[u8]) -> Result<u64, String> {
+        match decode_var_u64(payload) {
+            Ok((v, len)) if len == payload.len() => Ok(v),
+            _ => Err(self.corrupted()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::u64;
+
+    #[test]
+    fn test_lock_codec() {
+        let locks = vec![
+            Lock::new(LockType::Put, 1, Some(b"v".to_vec())),
+            Lock::new(LockType::Delete, u64::MAX, None),
+            Lock::new(LockType::Lock, 10, Some(vec![])),
+            Lock::new(LockType::Pessimistic, 5, None),
+        ];
+        for lock in locks {
+            let data = lock.to_bytes();
+            assert_eq!(Lock::parse(&data).unwrap(), lock);
+            // Every truncated record is an error instead of a panic.
+            for len in 0..data.len() {
+                if let Ok(l) = Lock::parse(&data[..len]) {
+                    // Only possible when a whole optional field is cut off.
+                    assert_ne!(l, lock);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_codec() {
+        let mut write = Write::new(WriteType::Put, 7, Some(b"value".to_vec()));
+        write.commit_ts = 8;
+        let writes = vec![
+            write,
+            Write::new(WriteType::Delete, 1, None),
+            Write::new(WriteType::Rollback, u64::MAX, None),
+            Write::new(WriteType::Lock, 0, None),
+        ];
+        for write in writes {
+            let data = write.to_bytes();
+            assert_eq!(Write::parse(&data).unwrap(), write);
+            assert_eq!(WriteRef::parse(&data).unwrap().short_value, write.short_value.as_ref().map(|v| v.as_slice()));
+            for len in 0..data.len() {
+                if let Ok(w) = Write::parse(&data[..len]) {
+                    assert_ne!(w, write);
+                }
+            }
+        }
+        assert!(Write::parse(&[]).is_err());
+        assert!(Write::parse(&[FORMAT_VERSION, b'X', 1]).is_err());
+        assert!(Lock::parse(&Write::new(WriteType::Rollback, 1, None).to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_forward_compatible() {
+        let write = Write::new(WriteType::Put, 7, Some(b"v".to_vec()));
+        let mut data = write.to_bytes();
+        // A field added by a newer encoder is skipped.
+        encode_field(&mut data, b'z', b"unknown");
+        assert_eq!(Write::parse(&data).unwrap(), write);
+        // But a newer format version is refused.
+        data[0] = FORMAT_VERSION + 1;
+        assert!(Write::parse(&data).unwrap_err().starts_with("unsupported format version"));
+        // A bad payload of a known field is corrupted.
+        let mut data = Write::new(WriteType::Put, 7, None).to_bytes();
+        encode_field(&mut data, TAG_COMMIT_TS, &[0x80]);
+        assert!(Write::parse(&data).unwrap_err().starts_with("corrupted write"));
+
+        // A version 1 lock with a primary key, a ttl and a txn size.
+        let mut data = vec![1, b'P'];
+        encode_var_u64(&mut data, 2);
+        data.extend_from_slice(b"pk");
+        encode_var_u64(&mut data, 5);
+        encode_var_u64(&mut data, 3000);
+        encode_u64_field(&mut data, b't', 16);
+        encode_field(&mut data, TAG_SHORT_VALUE, b"v");
+        assert_eq!(Lock::parse(&data).unwrap(), Lock::new(LockType::Put, 5, Some(b"v".to_vec())));
+    }
+}
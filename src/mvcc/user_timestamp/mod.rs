@@ -3,3 +3,4 @@ pub mod storage;
 
 pub use storage::create_storage as create_storage;
 pub use storage::create_storage_cf as create_storage_cf;
+pub use storage::ENCODING_VERSION;
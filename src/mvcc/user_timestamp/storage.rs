@@ -1,26 +1,57 @@
-
 use std::string::String;
 use std::u64;
+use std::path::Path;
 
 
+use super::super::max_ts::MaxTs;
 use super::super::memstore::MemStore;
 use super::super::{Key, Value};
-use rocksdb::{DB, WriteOptions, ReadOptions, SeekKey, DBOptions, ColumnFamilyOptions};
-use rocksdb::rocksdb_options::{bytes_to_u64, u64_to_bytes};
-use super::super::MvccStorage;
-use std::sync::{Arc, RwLock};
+use rocksdb::{DB, DBVector, WriteBatch, WriteOptions, ReadOptions, SeekKey, DBOptions, ColumnFamilyOptions, Snapshot};
 use rocksdb::rocksdb::Writable;
+use rocksdb::rocksdb_options::u64_to_bytes;
+use super::super::{check_bulk_load, check_flashback, flashed_back, KeyVersions, LockInfo, MvccStorage, ReadMode, SafePoint, StorageType, Version, FLASHBACK_BATCH_SIZE};
+use super::super::backup;
+use super::super::pinned::PinnedValue;
+use super::super::snapshot::{MvccSnapshot, SnapshotLocks};
+use super::super::txn_types::{WriteRef, WriteType};
+use super::super::stats::StorageStats;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use super::super::{ERR_KEY_LOCKED, ERR_KEY_VERSION};
+#[cfg(test)]
+use super::super::super::util::{fail_point, fault_fs};
+use super::super::super::config::{MemStoreConfig, ReadConfig};
+use super::super::super::util::codec::{decode_bytes, encode_bytes, encode_bytes_to};
+use super::super::super::util::engine::{get_cf_handle, ingest_sorted, new_engine_opt, CFOptions, DbReader};
+use super::super::{CF_DEFAULT, CF_OLD};
 
-const TIMESTAMP_LEN: usize = 16;
+// Bumped whenever the layout of keys or values changes.
+pub const ENCODING_VERSION: u32 = 4;
+
+// Keys are encoded by `codec::encode_bytes` as in the other models, a committed
+// version is a `Write` record which keeps the value and the commit ts. A version
+// removed by gc is moved into CF_OLD at the same key and timestamp.
 
 pub struct Storage {
     // Store pre-write result.
-    // TODO: add wal for mem_store
     mem_store: RwLock<MemStore>,
 
     // Only committed value can write to DB.
     db: DB,
+
+    safe_point: SafePoint,
+
+    max_ts: MaxTs,
+
+    logical_bytes_written: AtomicU64,
+
+    read: ReadConfig,
+
+    // Options each column family was opened with, for the files built by `bulk_load`.
+    cf_opts: Vec<(String, ColumnFamilyOptions)>,
+
+    // Sync commits to rocksdb, see `MemStoreConfig::wal_sync`.
+    sync: bool,
 }
 
 impl Storage {
@@ -28,38 +59,93 @@ impl Storage {
         Self {
             mem_store: RwLock::new(MemStore::new()),
             db,
+            safe_point: SafePoint::new(),
+            max_ts: MaxTs::new(),
+            logical_bytes_written: AtomicU64::new(0),
+            read: ReadConfig::default(),
+            cf_opts: vec![],
+            sync: false,
         }
     }
 
-    // Return committed value whose start ts equal to `start_ts`, or return None.
-    fn get_committed_version(&self, key: &Key, start_ts: u64, commit_ts: u64) -> Option<Value> {
-        let mut read_opt = ReadOptions::new();
-        read_opt.set_timestamp(commit_ts);
-        match self.db.get_opt(key, &read_opt) {
-            Ok(ret) => {
-                if let Some(value) = ret {
-                    let value = value.to_vec();
-                    let txn_start_ts = decode_start_ts_from_value(&value);
-                    let txn_commit_ts = decode_commit_ts_from_value(&value);
-                    if txn_commit_ts == commit_ts &&  txn_start_ts == start_ts {
-                        let mut res = Value::with_capacity(value.len() - TIMESTAMP_LEN);
-                        res.copy_from_slice(&value.as_slice()[TIMESTAMP_LEN..]);
-                        return Some(res)
-                    }
-                }
-            },
-            Err(_) => (),
+    // Open a storage whose locks are logged in `path` and survive a restart,
+    // unless the log is disabled in `memstore`.
+    pub fn open(db: DB, path: &str, cf_opts: Vec<(String, ColumnFamilyOptions)>, memstore: &MemStoreConfig, read: &ReadConfig) -> Result<Self, String> {
+        let (max_ts, saved) = MaxTs::open(Path::new(path))?;
+        let storage = Self {
+            mem_store: RwLock::new(MemStore::open_opt(Path::new(path), memstore)?),
+            db,
+            safe_point: SafePoint::new(),
+            max_ts,
+            logical_bytes_written: AtomicU64::new(0),
+            read: read.clone(),
+            cf_opts,
+            sync: memstore.wal_sync,
+        };
+        storage.recover()?;
+        if !saved {
+            // The data was written before the mark, e.g. it is restored from a backup.
+            storage.max_ts.observe(storage.scan_max_ts()?)?;
+        }
+        Ok(storage)
+    }
+
+    // Keys in SST files carry their timestamp as a suffix, as rocksdb stores them, so
+    // the options need the timestamp comparator even if the column family is unknown.
+    fn cf_options(&self, name: &str) -> ColumnFamilyOptions {
+        match self.cf_opts.iter().find(|(cf, _)| cf == name) {
+            Some((_, opts)) => opts.clone(),
+            None => {
+                let mut opts = ColumnFamilyOptions::new();
+                opts.set_timestamp_comparator(8);
+                opts
+            }
+        }
+    }
+
+    // Remove the locks of transactions which crashed after writing DB but before unlocking.
+    fn recover(&self) -> Result<(), String> {
+        let mut mem_store = self.mem_store.write().unwrap();
+        let locks: Vec<(Key, u64)> = mem_store.iter().map(|(key, (ts, _))| (key.clone(), *ts)).collect();
+        for (key, start_ts) in locks {
+            if self.get_commit_ts(&key, start_ts)?.is_some() {
+                mem_store.remove(&key)?;
+            }
         }
-        return None;
+        Ok(())
     }
 
-    fn get_uncommitted_data(&self, key: &Key, start_ts: u64) -> Result<Option<Value>, String> {
-        let mut mem_store = self.mem_store.read().unwrap();
-        match mem_store.get(&key) {
+    // Return the commit ts of the transaction started at `start_ts`, or None if
+    // it has not been committed.
+    fn get_commit_ts(&self, key: &[u8], start_ts: u64) -> Result<Option<u64>, String> {
+        let key = encode_bytes(key);
+        let mut ts = u64::MAX;
+        loop {
+            let mut read_opt = ReadOptions::new();
+            read_opt.set_timestamp(ts);
+            let value = match self.db.get_opt(&key, &read_opt)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            let write = WriteRef::parse(&value)?;
+            if write.start_ts == start_ts && write.write_type != WriteType::Rollback {
+                return Ok(Some(write.commit_ts));
+            }
+            if write.commit_ts <= start_ts {
+                return Ok(None);
+            }
+            ts = write.commit_ts - 1;
+        }
+    }
+
+    // Return the write record committing the prewritten value if `key` is locked by
+    // `start_ts`, it is encoded under the lock instead of copying the value out.
+    fn encode_uncommitted_data(&self, mem_store: &MemStore, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<Option<Vec<u8>>, String> {
+        match mem_store.get(key) {
             Some((timestamp, value)) => {
                 if *timestamp == start_ts {
                     // Pre-write result is ok
-                    Ok(Some(value.clone()))
+                    Ok(Some(encode_write(value.as_ref().map(|v| v.as_slice()), start_ts, commit_ts)))
                 } else {
                     // Rollback-ed or committed by other txn
                     Err(String::from("This key was prewrite by other transaction"))
@@ -69,149 +155,655 @@ impl Storage {
         }
     }
 
-    fn unlock_uncommitted_data(&self, key: &Key, start_ts: u64) -> Result<Option<Value>, String> {
+    // Return whether the lock of `start_ts` is removed. A rolled back lock leaves a
+    // rollback record at `start_ts`, written before the lock is removed, unless a
+    // version of another transaction is committed at `start_ts`, which is kept and
+    // fails a late prewrite of this transaction as well.
+    fn unlock_uncommitted_data(&self, mem_store: &mut MemStore, key: &[u8], start_ts: u64, rollback: bool) -> Result<bool, String> {
+        match mem_store.get(key) {
+            // Pre-write result is ok
+            Some((timestamp, _)) if *timestamp == start_ts => (),
+            // Rollback-ed or committed by other txn
+            Some(_) => return Err(String::from("This key was prewrite by other transaction")),
+            None => return Ok(false),
+        }
+        if rollback && !self.committed_at(key, start_ts)? {
+            let write = WriteRef {
+                write_type: WriteType::Rollback,
+                start_ts,
+                commit_ts: start_ts,
+                short_value: None,
+            };
+            let mut write_opt = WriteOptions::new();
+            write_opt.set_timestamp(start_ts);
+            write_opt.set_sync(self.sync);
+            self.db.put_opt(&encode_bytes(key), &write.to_bytes(), &write_opt)?;
+        }
+        #[cfg(test)]
+        fail_point::eval("before_unlock")?;
+        mem_store.remove(key)?;
+        Ok(true)
+    }
+
+    // Cover the record of the encoded `key` at `commit_ts` by a tombstone, after moving
+    // it into CF_OLD if it is a version.
+    fn remove_version(&self, key: &[u8], version: Option<&[u8]>, commit_ts: u64) -> Result<(), String> {
+        let wb = WriteBatch::new();
+        if let Some(data) = version {
+            wb.put_cf(get_cf_handle(&self.db, CF_OLD)?, key, data)?;
+        }
+        wb.delete(key)?;
+        let mut write_opt = WriteOptions::new();
+        write_opt.set_timestamp(commit_ts);
+        self.db.write_opt(&wb, &write_opt)
+    }
+
+    // Whether a version of `key` is committed at exactly `commit_ts`.
+    fn committed_at(&self, key: &[u8], commit_ts: u64) -> Result<bool, String> {
+        let mut read_opt = ReadOptions::new();
+        read_opt.set_timestamp(commit_ts);
+        match self.db.get_opt(&encode_bytes(key), &read_opt)? {
+            Some(v) => Ok(WriteRef::parse(&v)?.commit_ts == commit_ts),
+            None => Ok(false),
+        }
+    }
+
+    fn prewrite_mutation(&self, key: &[u8], value: Option<&[u8]>, ts: u64) -> Result<(), String> {
         let mut mem_store = self.mem_store.write().unwrap();
-        match mem_store.remove(&key) {
-            Some((timestamp, value)) => {
-                if timestamp == start_ts {
-                    // Pre-write result is ok
-                    Ok(Some(value))
-                } else {
-                    // Rollback-ed or committed by other txn
-                    mem_store.insert(key.clone(), value, timestamp);
-                    Err(String::from("This key was prewrite by other transaction"))
+        if mem_store.contains_key(key) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let encoded = encode_bytes(key);
+        let mut read_ts = u64::MAX;
+        loop {
+            let mut read_opt = ReadOptions::new();
+            read_opt.set_timestamp(read_ts);
+            let value = match self.db.get_opt(&encoded, &read_opt)? {
+                Some(v) => v,
+                None => break,
+            };
+            let write = WriteRef::parse(&value)?;
+            if write.commit_ts < ts {
+                break;
+            }
+            // A rollback record only fails the transaction it rolled back.
+            if write.write_type != WriteType::Rollback || write.commit_ts == ts {
+                return Err(String::from(ERR_KEY_VERSION));
+            }
+            read_ts = write.commit_ts - 1;
+        }
+        self.max_ts.observe(ts)?;
+        mem_store.insert(key.to_vec(), value.map(|v| v.to_vec()), ts)?;
+        let bytes = key.len() + value.map_or(0, |v| v.len());
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Return the record of the latest version of the encoded `key` at `ts`. Rollback
+    // records are no versions, the record before one is read instead.
+    fn get_write(&self, r: &DbReader, key: &[u8], ts: u64) -> Result<Option<DBVector>, String> {
+        let mut ts = ts;
+        loop {
+            let mut read_opt = ReadOptions::new();
+            read_opt.set_timestamp(ts);
+            let data = match r.get_opt(key, read_opt)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            let commit_ts = {
+                let write = WriteRef::parse(&data)?;
+                if write.write_type != WriteType::Rollback {
+                    return Ok(Some(data));
                 }
+                write.commit_ts
+            };
+            if commit_ts == 0 {
+                return Ok(None);
             }
-            None => Ok(None)
+            ts = commit_ts - 1;
         }
     }
-}
 
-impl MvccStorage for Storage {
-    fn prewrite(&self, key: &Key, value: &Value, ts: u64) -> Result<(), String> {
-        // TODO: check write conflict
-        if self.mem_store.read().unwrap().contains_key(&key) {
+    // The value is pinned in the buffer returned by the engine. The key is encoded
+    // into `buf`, which callers reading many keys reuse.
+    fn read_version(&self, r: &DbReader, buf: &mut Vec<u8>, key: &[u8], ts: u64) -> Result<Option<PinnedValue>, String> {
+        buf.clear();
+        encode_bytes_to(buf, key);
+        match self.get_write(r, buf, ts)? {
+            Some(data) => pin_value(data),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_versions(&self, r: &DbReader, start: &[u8], end: &[u8], ts: u64) -> Result<Vec<PinnedValue>, String> {
+        let end = encode_bytes(end);
+        let mut read_opt = ReadOptions::new();
+        read_opt.set_timestamp(ts);
+        // A prefix extractor set by the config must not skip other keys.
+        read_opt.set_total_order_seek(true);
+        if self.read.iterate_upper_bound {
+            read_opt.set_iterate_upper_bound(end.clone());
+        }
+        let mut iter = r.iter_opt(read_opt);
+        let mut result = Vec::new();
+        iter.seek(SeekKey::Key(&encode_bytes(start)));
+        while iter.valid() {
+            if iter.key() >= end.as_slice() {
+                break;
+            }
+            // Values of an iterator are only valid until it moves, the record is read
+            // again to keep the value pinned, which also skips a rollback record.
+            if let Some(data) = self.get_write(r, iter.key(), ts)? {
+                if let Some(value) = pin_value(data)? {
+                    result.push(value);
+                }
+            }
+            iter.next();
+        }
+        Ok(result)
+    }
+
+    // The largest commit ts of a version, only the latest version of every key is
+    // seen, it has the largest commit ts.
+    fn scan_max_ts(&self) -> Result<u64, String> {
+        let mut max_ts = 0;
+        let mut read_opt = ReadOptions::new();
+        read_opt.set_timestamp(u64::MAX);
+        read_opt.set_total_order_seek(true);
+        let mut iter = self.db.iter_opt(read_opt);
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            max_ts = max_ts.max(WriteRef::parse(iter.value())?.commit_ts);
+            iter.next();
+        }
+        Ok(max_ts)
+    }
+
+    // Flash back at most `FLASHBACK_BATCH_SIZE` keys of [start, end) at once, return the
+    // number of keys written and the first changed key left for the next batch.
+    fn flashback_batch(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<(usize, Option<Key>), String> {
+        let mem_store = self.mem_store.write().unwrap();
+        if mem_store.range_conflict(start, end, u64::MAX, &ReadMode::default()) {
             return Err(String::from(ERR_KEY_LOCKED));
         }
+        let r = DbReader::Db(&self.db);
+        // Keys changed after `target_ts`, and whether their latest version is a Put.
+        let mut changed = vec![];
+        let mut resume = None;
+        {
+            let end = encode_bytes(end);
+            let mut read_opt = ReadOptions::new();
+            read_opt.set_timestamp(u64::MAX);
+            read_opt.set_total_order_seek(true);
+            if self.read.iterate_upper_bound {
+                read_opt.set_iterate_upper_bound(end.clone());
+            }
+            let mut iter = self.db.iter_opt(read_opt);
+            iter.seek(SeekKey::Key(&encode_bytes(start)));
+            while iter.valid() && iter.key() < end.as_slice() {
+                if let Some(latest) = self.get_write(&r, iter.key(), u64::MAX)? {
+                    let write = WriteRef::parse(&latest)?;
+                    if !flashed_back(write.start_ts, write.commit_ts, new_commit_ts)? && write.commit_ts > target_ts {
+                        let key = decode_bytes(iter.key())?.0;
+                        if changed.len() == FLASHBACK_BATCH_SIZE {
+                            resume = Some(key);
+                            break;
+                        }
+                        changed.push((key, write.write_type == WriteType::Put));
+                    }
+                }
+                iter.next();
+            }
+        }
+        let wb = WriteBatch::new();
+        let mut buf = vec![];
+        let (mut count, mut bytes) = (0, 0);
+        for (key, latest_put) in changed {
+            let value = self.read_version(&r, &mut buf, &key, target_ts)?;
+            // Missing both at `target_ts` and now.
+            if value.is_none() && !latest_put {
+                continue;
+            }
+            let write = encode_write(value.as_ref().map(|v| &**v), new_commit_ts, new_commit_ts);
+            wb.put(&encode_bytes(&key), &write)?;
+            count += 1;
+            bytes += key.len() + value.map_or(0, |v| v.len());
+        }
+        let mut write_opt = WriteOptions::new();
+        write_opt.set_timestamp(new_commit_ts);
+        write_opt.set_sync(self.sync);
+        self.db.write_opt(&wb, &write_opt)?;
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok((count, resume))
+    }
+
+    // The newest `limit` versions of the encoded `key` in [from_ts, to_ts] kept in `cf`.
+    // An iterator given a start ts returns every version of a key in the range of
+    // timestamps instead of the latest one, with the timestamp appended to the key.
+    fn list_versions(&self, r: &DbReader, cf: &str, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<Vec<Version>, String> {
+        let mut read_opt = ReadOptions::new();
+        read_opt.set_timestamp(to_ts);
+        read_opt.set_iter_start_ts(from_ts);
+        read_opt.set_total_order_seek(true);
+        let mut iter = r.iter_cf_opt(get_cf_handle(&self.db, cf)?, read_opt);
+        let mut versions = vec![];
+        iter.seek(SeekKey::Key(key));
+        // No encoded key is a prefix of another one.
+        while iter.valid() && iter.key().starts_with(key) && versions.len() < limit {
+            // A tombstone left by gc has no value.
+            if !iter.value().is_empty() {
+                let write = WriteRef::parse(iter.value())?;
+                if write.write_type != WriteType::Rollback {
+                    versions.push(Version {
+                        commit_ts: write.commit_ts,
+                        start_ts: write.start_ts,
+                        write_type: write.write_type,
+                        value: write.short_value.map(|v| v.to_vec()),
+                    });
+                }
+            }
+            iter.next();
+        }
+        Ok(versions)
+    }
+
+    // Walk all versions of every key.
+    fn count_versions(&self, stats: &mut StorageStats) -> Result<(), String> {
         let mut read_opt = ReadOptions::new();
         read_opt.set_timestamp(u64::MAX);
-        let ret = self.db.get_opt(key, &read_opt)?;
-        if let Some(value) = ret {
-            let value = value.to_vec();
-            let commit_ts = decode_commit_ts_from_value(&value);
-            if commit_ts >= ts {
-                return Err(String::from(ERR_KEY_VERSION));
+        read_opt.set_total_order_seek(true);
+        let mut iter = self.db.iter_opt(read_opt);
+        let r = DbReader::Db(&self.db);
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            let key = iter.key().to_vec();
+            let mut ts = u64::MAX;
+            while let Some(data) = self.get_write(&r, &key, ts)? {
+                if ts == u64::MAX {
+                    if let Some(value) = decode_data_from_value(&data)? {
+                        stats.live_versions += 1;
+                        stats.live_bytes += (decode_bytes(&key)?.0.len() + value.len()) as u64;
+                    }
+                }
+                stats.total_versions += 1;
+                let commit_ts = WriteRef::parse(&data)?.commit_ts;
+                if commit_ts == 0 {
+                    break;
+                }
+                ts = commit_ts - 1;
             }
+            iter.next();
         }
-        let mut mem_store = self.mem_store.write().unwrap();
-        mem_store.insert(key.clone(), value.clone(), ts);
         Ok(())
     }
+}
+
+impl MvccStorage for Storage {
+    fn prewrite(&self, key: &[u8], value: &[u8], ts: u64) -> Result<(), String> {
+        self.prewrite_mutation(key, Some(value), ts)
+    }
 
-    fn commit(&self, key: &Key, start_ts: u64, commit_ts: u64) -> Result<(), String> {
-        // we should keep key in lock until data has been committed into db.
-        if let Some(value) = self.get_uncommitted_data(&key, start_ts)? {
+    fn delete(&self, key: &[u8], ts: u64) -> Result<(), String> {
+        self.prewrite_mutation(key, None, ts)
+    }
+
+    fn commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        // we should keep key in lock until data has been committed into db. The write
+        // guard is held until then, so that a rollback can not remove the lock meanwhile.
+        let mut mem_store = self.mem_store.write().unwrap();
+        if let Some(v) = self.encode_uncommitted_data(&mem_store, key, start_ts, commit_ts)? {
+            self.max_ts.observe(commit_ts)?;
             let mut write_opt = WriteOptions::new();
             write_opt.set_timestamp(commit_ts);
-            let mut v = value;
-            encode_ts_to_value(start_ts, &mut v);
-            encode_ts_to_value(commit_ts, &mut v);
-            self.db.put_opt(&key, &v, &write_opt);
-            self.unlock_uncommitted_data(&key, start_ts).unwrap();
+            write_opt.set_sync(self.sync);
+            self.db.put_opt(&encode_bytes(key), &v, &write_opt)?;
+            #[cfg(test)]
+            {
+                if self.sync {
+                    fault_fs::synced_logs(Path::new(self.db.path()));
+                }
+                fail_point::eval("commit_after_db_put")?;
+            }
+            self.unlock_uncommitted_data(&mut mem_store, key, start_ts, false)?;
             return Ok(());
         }
+        drop(mem_store);
         // Find to see if it is committed or rollback-ed
-        if let Some(_) = self.get_committed_version(key, start_ts, commit_ts) {
-            return Ok(());
+        if self.get_commit_ts(key, start_ts)? == Some(commit_ts) {
+            Ok(())
         } else {
-            return Err(String::from("rollback-ed by other txn"));
+            Err(String::from("rollback-ed by other txn"))
         }
     }
 
-    fn rollback(&self, key: &Key, start_ts: u64) -> Result<(), String> {
+    fn rollback(&self, key: &[u8], start_ts: u64) -> Result<(), String> {
         // when rollback, we could remove key at once
-        if let Some(value) = self.unlock_uncommitted_data(key, start_ts)? {
-            // TODO: write rollback into WRITE_CF
+        if self.unlock_uncommitted_data(&mut self.mem_store.write().unwrap(), key, start_ts, true)? {
             return Ok(());
         }
 
         // Find to see if it is committed or rollback-ed
-        if let Some(_) = self.get_committed_version(key, start_ts, u64::MAX) {
-            return Err(String::from("committed by other txn"));
+        if self.get_commit_ts(key, start_ts)?.is_some() {
+            Err(String::from("committed by other txn"))
         } else {
-            return Err(String::from("rollback-ed by other txn"));
+            Err(String::from("rollback-ed by other txn"))
         }
     }
 
-    fn get(&self, key: &Key, ts: u64) -> Result<Option<Value>, String> {
-        if let Some((start_ts, _)) = self.mem_store.read().unwrap().get(key) {
-            if *start_ts <= ts {
-                return Err(String::from(ERR_KEY_LOCKED));
+    fn get_opt(&self, key: &[u8], ts: u64, mode: &ReadMode) -> Result<Option<PinnedValue>, String> {
+        self.safe_point.check(ts)?;
+        if self.mem_store.read().unwrap().conflict(key, ts, mode) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        self.read_version(&DbReader::Db(&self.db), &mut vec![], key, ts)
+    }
+
+    fn batch_get_opt(&self, keys: &[&[u8]], ts: u64, mode: &ReadMode) -> Result<Vec<Result<Option<PinnedValue>, String>>, String> {
+        self.safe_point.check(ts)?;
+        let locked: Vec<bool> = {
+            let mem_store = self.mem_store.read().unwrap();
+            keys.iter().map(|key| mem_store.conflict(key, ts, mode)).collect()
+        };
+        // The binding has no multi_get, point gets still make use of bloom filters.
+        let r = DbReader::Db(&self.db);
+        let mut buf = vec![];
+        Ok(keys
+            .iter()
+            .zip(locked)
+            .map(|(key, locked)| {
+                if locked {
+                    return Err(String::from(ERR_KEY_LOCKED));
+                }
+                self.read_version(&r, &mut buf, key, ts)
+            })
+            .collect())
+    }
+
+    fn scan_opt(&self, start: &[u8], end: &[u8], ts: u64, mode: &ReadMode) -> Result<Vec<PinnedValue>, String> {
+        self.safe_point.check(ts)?;
+        if self.mem_store.read().unwrap().range_conflict(start, end, ts, mode) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        self.scan_versions(&DbReader::Db(&self.db), start, end, ts)
+    }
+
+    fn snapshot(&self, ts: u64) -> Result<Box<dyn MvccSnapshot + '_>, String> {
+        self.safe_point.check(ts)?;
+        let mem_store = self.mem_store.read().unwrap();
+        let locks = SnapshotLocks::new(&mem_store, ts);
+        let snap = self.db.snapshot();
+        Ok(Box::new(StorageSnapshot {
+            storage: self,
+            snap,
+            locks,
+            ts,
+        }))
+    }
+
+    fn get_versions(&self, key: &[u8], from_ts: u64, to_ts: u64, limit: usize) -> Result<KeyVersions, String> {
+        let lock = self.mem_store.read().unwrap().get(key).map(|(start_ts, value)| LockInfo::new(*start_ts, value.clone()));
+        // Both column families are read from one snapshot, so that a version moved by
+        // gc meanwhile is listed once.
+        let snap = self.db.snapshot();
+        let r = DbReader::Snapshot(&snap);
+        let encoded = encode_bytes(key);
+        let mut versions = self.list_versions(&r, CF_DEFAULT, &encoded, from_ts, to_ts, limit)?;
+        versions.extend(self.list_versions(&r, CF_OLD, &encoded, from_ts, to_ts, limit)?);
+        versions.sort_by(|a, b| b.commit_ts.cmp(&a.commit_ts));
+        versions.truncate(limit);
+        Ok(KeyVersions { versions, lock })
+    }
+
+    fn flashback(&self, start: &[u8], end: &[u8], target_ts: u64, new_commit_ts: u64) -> Result<usize, String> {
+        check_flashback(target_ts, new_commit_ts)?;
+        self.safe_point.check(target_ts)?;
+        self.max_ts.observe(new_commit_ts)?;
+        let mut count = 0;
+        let mut next = Some(start.to_vec());
+        while let Some(batch_start) = next {
+            let (written, resume) = self.flashback_batch(&batch_start, end, target_ts, new_commit_ts)?;
+            count += written;
+            next = resume;
+        }
+        Ok(count)
+    }
+
+    fn gc(&self, safe_point: u64) -> Result<(), String> {
+        if let Some(start_ts) = self.mem_store.read().unwrap().min_start_ts() {
+            if start_ts <= safe_point {
+                return Err(format!("lock of txn {} must be resolved before gc", start_ts));
             }
         }
+        self.safe_point.advance(safe_point);
         let mut read_opt = ReadOptions::new();
-        read_opt.set_timestamp(ts);
-        match self.db.get_opt(key, &read_opt)? {
-            Some(v) => {
-                let value = v.to_vec();
-                let value_len = value.len() - TIMESTAMP_LEN;
-                let res = &value[..value_len].to_owned();
-                Ok(Some(res.clone()))
+        read_opt.set_timestamp(u64::MAX);
+        read_opt.set_total_order_seek(true);
+        let mut iter = self.db.iter_opt(read_opt);
+        iter.seek(SeekKey::Start);
+        while iter.valid() {
+            let key = iter.key().to_vec();
+            // Walk the records at or before the safe point, the latest first. A removed
+            // version is moved into CF_OLD and covered by a tombstone at its ts, which
+            // also ends the walk of the next gc.
+            let mut ts = safe_point;
+            let mut visited = false;
+            // A tombstone hides every version before it, so the rollback records newer
+            // than the latest version are only dropped if that version is not kept.
+            let mut rollbacks = vec![];
+            loop {
+                let mut read_opt = ReadOptions::new();
+                read_opt.set_timestamp(ts);
+                let data = match self.db.get_opt(&key, &read_opt)? {
+                    Some(v) => v,
+                    None => break,
+                };
+                let write = WriteRef::parse(&data)?;
+                // Rollback records are dropped as no transaction before the safe point
+                // prewrites anymore.
+                if write.write_type == WriteType::Rollback {
+                    if visited {
+                        self.remove_version(&key, None, write.commit_ts)?;
+                    } else {
+                        rollbacks.push(write.commit_ts);
+                    }
+                } else {
+                    // Keep the latest version before the safe point unless it is a deletion.
+                    if !visited && write.write_type == WriteType::Put {
+                        rollbacks.clear();
+                    } else {
+                        self.remove_version(&key, Some(&data[..]), write.commit_ts)?;
+                    }
+                    visited = true;
+                }
+                if write.commit_ts == 0 {
+                    break;
+                }
+                ts = write.commit_ts - 1;
             }
-            None => Ok(None),
+            for commit_ts in rollbacks {
+                self.remove_version(&key, None, commit_ts)?;
+            }
+            iter.next();
         }
+        Ok(())
     }
 
-    fn scan(&self, start: &Key, end: &Key, ts: u64) -> Result<Vec<Value>, String> {
-//        if self.mem_store.range_conflict(&start, &end, ts) {
-//            return Err(String::from("key is locked"));
-//        }
-//        let mut read_opt = ReadOptions::new();
-//        read_opt.set_timestamp(ts);
-//        let snap = self.db.snapshot();
-//        let mut iter = snap.iter_opt(read_opt);
-        let mut result = Vec::new();
-//        iter.seek(SeekKey::Key(start));
-//        while iter.valid() {
-//            let key = iter.key();
-//            if key.gt(end) {
-//                break;
-//            }
-//            result.push(Vec::from(key));
-//        }
-        return Ok(result)
+    fn compact(&self) -> Result<(), String> {
+        for name in [CF_DEFAULT, CF_OLD].iter() {
+            self.db.compact_range_cf(get_cf_handle(&self.db, name)?, None, None);
+        }
+        Ok(())
+    }
+
+    fn max_ts(&self) -> Result<u64, String> {
+        let max_start_ts = self.mem_store.read().unwrap().max_start_ts().unwrap_or(0);
+        Ok(self.max_ts.get().max(max_start_ts))
+    }
+
+    fn stats(&self) -> Result<StorageStats, String> {
+        let mut stats = StorageStats {
+            logical_bytes_written: self.logical_bytes_written.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        stats.collect_engine_stats(&self.db);
+        self.count_versions(&mut stats)?;
+        Ok(stats)
+    }
+
+    fn backup(&self, dir: &Path) -> Result<(), String> {
+        let mem_store = self.mem_store.write().unwrap();
+        backup::checkpoint(&self.db, &mem_store, StorageType::UserTimestampStorage, dir)
+    }
+
+    fn bulk_load(&self, kvs: &[(Key, Value)], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+        check_bulk_load(kvs, start_ts, commit_ts)?;
+        let mem_store = self.mem_store.write().unwrap();
+        if kvs.iter().any(|(key, _)| mem_store.contains_key(key)) {
+            return Err(String::from(ERR_KEY_LOCKED));
+        }
+        let entries = kvs.iter().map(|(key, value)| {
+            let mut k = encode_bytes(key);
+            k.append(&mut u64_to_bytes(commit_ts));
+            (k, encode_write(Some(value), start_ts, commit_ts))
+        });
+        self.max_ts.observe(commit_ts)?;
+        ingest_sorted(&self.db, CF_DEFAULT, self.cf_options(CF_DEFAULT), entries)?;
+        let bytes: usize = kvs.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.logical_bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
     }
 }
 
-fn encode_ts_to_value(ts: u64, value: &mut Value) {
-    let mut v = u64_to_bytes(ts);
-    value.append(&mut v);
+pub struct StorageSnapshot<'a> {
+    storage: &'a Storage,
+    snap: Snapshot<&'a DB>,
+    locks: SnapshotLocks,
+    ts: u64,
 }
 
-fn decode_start_ts_from_value(value: &Value) -> u64 {
-    let l = value.len();
-    bytes_to_u64(&value[l-16..l-8])
+impl<'a> MvccSnapshot for StorageSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<PinnedValue>, String> {
+        self.locks.check(key)?;
+        self.storage.read_version(&DbReader::Snapshot(&self.snap), &mut vec![], key, self.ts)
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<PinnedValue>, String> {
+        self.locks.check_range(start, end)?;
+        self.storage.scan_versions(&DbReader::Snapshot(&self.snap), start, end, self.ts)
+    }
+}
+
+// The value of the write record `data`, pinned in it, or None if it is a deletion.
+fn pin_value(data: DBVector) -> Result<Option<PinnedValue>, String> {
+    let range = match decode_data_from_value(&data)? {
+        Some(value) => PinnedValue::range_in(&data, value)?,
+        None => return Ok(None),
+    };
+    Ok(Some(PinnedValue::Engine(data, range)))
 }
 
-fn decode_commit_ts_from_value(value: &Value) -> u64 {
-    let l = value.len();
-    bytes_to_u64(&value[l-8..])
+fn encode_write(value: Option<&[u8]>, start_ts: u64, commit_ts: u64) -> Value {
+    let write_type = if value.is_some() { WriteType::Put } else { WriteType::Delete };
+    let write = WriteRef {
+        write_type,
+        start_ts,
+        commit_ts,
+        short_value: value,
+    };
+    write.to_bytes()
+}
+
+// Return None if the version is a deletion.
+fn decode_data_from_value(value: &[u8]) -> Result<Option<&[u8]>, String> {
+    let write = WriteRef::parse(value)?;
+    match write.write_type {
+        WriteType::Put => Ok(write.short_value),
+        _ => Ok(None),
+    }
 }
 
 pub fn create_storage(options: DBOptions, path: &str) -> Result<Arc<dyn MvccStorage>, String> {
-    let mut option = options;
-    option.set_user_timestamp_comparator(8);
-    let db = DB::open_opt(option, path)?;
-    let storage = Storage::new(db);
-    return Ok(Arc::new(storage));
+    let cfds = vec![(CF_DEFAULT, ColumnFamilyOptions::new()), (CF_OLD, ColumnFamilyOptions::new())];
+    create_storage_cf(options, path, cfds, &MemStoreConfig::default(), &ReadConfig::default())
+}
+
+pub fn create_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>, memstore: &MemStoreConfig, read: &ReadConfig) -> Result<Arc<dyn MvccStorage>, String> {
+    let storage = open_storage_cf(options, path, cfds, memstore, read)?;
+    Ok(Arc::new(storage))
 }
 
-pub fn create_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>) -> Result<Arc<dyn MvccStorage>, String> {
-    let mut cfds = cfds;
-    for (_, cf) in cfds.iter_mut() {
+fn open_storage_cf(options: DBOptions, path: &str, cfds: Vec<(&str, ColumnFamilyOptions)>, memstore: &MemStoreConfig, read: &ReadConfig) -> Result<Storage, String> {
+    let mut cfs_opts = vec![];
+    let mut cf_opts = vec![];
+    for (name, mut cf) in cfds {
         cf.set_timestamp_comparator(8);
+        cf_opts.push((name.to_owned(), cf.clone()));
+        cfs_opts.push(CFOptions::new(name, cf));
+    }
+    let db = new_engine_opt(path, options, cfs_opts)?;
+    Storage::open(db, path, cf_opts, memstore, read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::super::config::{CompressionType, Config};
+    use super::super::super::super::util::engine::get_cf_handle;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_bulk_load_cf_options() {
+        let path = TempDir::new("_user_timestamp_bulk_load_cf_options").unwrap();
+        let mut config = Config::default();
+        config.rocksdb.defaultcf.compression = CompressionType::No;
+        let cfds = config.rocksdb.build_cf_opts(StorageType::UserTimestampStorage.name(), &[CF_DEFAULT, CF_OLD]).unwrap();
+        let storage = open_storage_cf(config.rocksdb.build_opt(), path.path().to_str().unwrap(), cfds, &config.memstore, &config.rocksdb.read).unwrap();
+        storage.bulk_load(&[(b"k".to_vec(), b"v".to_vec())], 1, 2).unwrap();
+        let tables = storage.db.get_properties_of_all_tables_cf(get_cf_handle(&storage.db, CF_DEFAULT).unwrap()).unwrap();
+        assert_eq!(tables.len(), 1);
+        for (_, props) in tables.iter() {
+            assert_eq!(props.compression_name(), "NoCompression");
+            assert_eq!(props.filter_policy_name(), "rocksdb.BuiltinBloomFilter");
+        }
+        assert_eq!(storage.get(b"k", 2).unwrap().unwrap(), b"v".to_vec());
+    }
+
+    #[test]
+    fn test_scan_pinned() {
+        let path = TempDir::new("_user_timestamp_scan_pinned").unwrap();
+        let storage = create_storage(DBOptions::new(), path.path().to_str().unwrap()).unwrap();
+        storage.prewrite(b"a", b"v1", 1).unwrap();
+        storage.commit(b"a", 1, 2).unwrap();
+        storage.prewrite(b"b", b"v2", 1).unwrap();
+        storage.commit(b"b", 1, 2).unwrap();
+        storage.prewrite(b"b", b"v3", 3).unwrap();
+        storage.rollback(b"b", 3).unwrap();
+        let values = storage.scan(b"a", b"z", 5).unwrap();
+        assert_eq!(values, vec![b"v1".to_vec(), b"v2".to_vec()]);
+        for v in values.iter() {
+            if let PinnedValue::Owned(_) = v {
+                panic!("value is not pinned: {:?}", v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_drop_unrequested_cf() {
+        let path = TempDir::new("_user_timestamp_drop_unrequested_cf").unwrap();
+        let path = path.path().to_str().unwrap();
+        let cfds = vec![(CF_DEFAULT, ColumnFamilyOptions::new()), (CF_OLD, ColumnFamilyOptions::new()), ("unrequested", ColumnFamilyOptions::new())];
+        {
+            let storage = open_storage_cf(DBOptions::new(), path, cfds, &MemStoreConfig::default(), &ReadConfig::default()).unwrap();
+            storage.prewrite(b"k", b"v", 1).unwrap();
+            storage.commit(b"k", 1, 2).unwrap();
+        }
+        // The column family was created with the timestamp comparator, it has to be
+        // opened with it again before it is dropped.
+        let cfds = vec![(CF_DEFAULT, ColumnFamilyOptions::new()), (CF_OLD, ColumnFamilyOptions::new())];
+        let storage = open_storage_cf(DBOptions::new(), path, cfds, &MemStoreConfig::default(), &ReadConfig::default()).unwrap();
+        let mut cfs = DB::list_column_families(&DBOptions::new(), path).unwrap();
+        cfs.sort();
+        assert_eq!(cfs, vec![CF_DEFAULT.to_owned(), CF_OLD.to_owned()]);
+        assert_eq!(storage.get(b"k", 2).unwrap().unwrap(), b"v".to_vec());
     }
-    let db = DB::open_cf(options, path, cfds)?;
-    let storage = Storage::new(db);
-    return Ok(Arc::new(storage));
 }
@@ -0,0 +1,237 @@
+///
+/// Write ahead log of the mem-store, so that prewritten locks survive a restart.
+///
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use rocksdb::rocksdb_options::{bytes_to_u64, u64_to_bytes};
+
+use super::super::util::collection::HashMap;
+#[cfg(test)]
+use super::super::util::{fail_point, fault_fs};
+use super::txn_types::{Lock, LockType};
+use super::{Key, Value};
+
+pub const WAL_FILE_NAME: &str = "MEMSTORE.wal";
+
+// A lock is logged as a `Lock` record, an unlock as its start ts.
+const TAG_LOCK: u8 = b'L';
+const TAG_UNLOCK: u8 = b'U';
+
+// Locks by key, with their start ts and short value.
+type Locks = HashMap<Key, (u64, Option<Value>)>;
+
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    // Sync the file after every append.
+    sync: bool,
+}
+
+impl Wal {
+    // Open the log in `dir` and return the locks which are not unlocked yet. The
+    // log is rewritten to only contain those locks.
+    pub fn open(dir: &Path) -> Result<(Wal, Locks), String> {
+        let path = dir.join(WAL_FILE_NAME);
+        let mut locks = HashMap::default();
+        if path.exists() {
+            let mut data = vec![];
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .map_err(|e| format!("read {:?}: {:?}", path, e))?;
+            replay(&data, &mut locks).map_err(|e| format!("replay {:?}: {}", path, e))?;
+        }
+        save(dir, locks.iter())?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("open {:?}: {:?}", path, e))?;
+        Ok((Wal { path, file, sync: false }, locks))
+    }
+
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    pub fn append_lock(&mut self, key: &[u8], value: Option<&Value>, ts: u64) -> Result<(), String> {
+        let mut data = vec![];
+        encode_lock(&mut data, key, value, ts);
+        self.append(&data)
+    }
+
+    pub fn append_unlock(&mut self, key: &[u8], ts: u64) -> Result<(), String> {
+        let mut data = vec![TAG_UNLOCK];
+        encode_bytes(&mut data, key);
+        data.append(&mut u64_to_bytes(ts));
+        self.append(&data)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), String> {
+        #[cfg(test)]
+        fail_point::eval("wal_before_append")?;
+        self.file
+            .write_all(data)
+            .map_err(|e| format!("append {:?}: {:?}", self.path, e))?;
+        if self.sync {
+            self.file
+                .sync_data()
+                .map_err(|e| format!("sync {:?}: {:?}", self.path, e))?;
+            #[cfg(test)]
+            fault_fs::synced(&self.path);
+        }
+        #[cfg(test)]
+        fail_point::eval("wal_after_append")?;
+        Ok(())
+    }
+}
+
+// Atomically replace the log in `dir` by one which only contains `locks`. The new
+// log is synced before it replaces the old one, so that a power failure leaves
+// either of them.
+pub fn save<'a, I>(dir: &Path, locks: I) -> Result<(), String>
+where
+    I: Iterator<Item = (&'a Key, &'a (u64, Option<Value>))>,
+{
+    let path = dir.join(WAL_FILE_NAME);
+    let tmp = dir.join(format!("{}.tmp", WAL_FILE_NAME));
+    let mut data = vec![];
+    for (key, (ts, value)) in locks {
+        encode_lock(&mut data, key, value.as_ref(), *ts);
+    }
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, &path))
+        .and_then(|_| File::open(dir).and_then(|d| d.sync_all()))
+        .map_err(|e| format!("rewrite {:?}: {:?}", path, e))?;
+    #[cfg(test)]
+    fault_fs::synced(&path);
+    Ok(())
+}
+
+fn encode_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.append(&mut u64_to_bytes(bytes.len() as u64));
+    data.extend_from_slice(bytes);
+}
+
+fn encode_lock(data: &mut Vec<u8>, key: &[u8], value: Option<&Value>, ts: u64) {
+    let lock_type = if value.is_some() { LockType::Put } else { LockType::Delete };
+    let lock = Lock::new(lock_type, ts, value.cloned());
+    data.push(TAG_LOCK);
+    encode_bytes(data, key);
+    encode_bytes(data, &lock.to_bytes());
+}
+
+fn decode_bytes<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = decode_u64(data, pos)? as usize;
+    if data.len() - *pos < len {
+        return None;
+    }
+    *pos += len;
+    Some(&data[*pos - len..*pos])
+}
+
+fn decode_u64(data: &[u8], pos: &mut usize) -> Option<u64> {
+    if data.len() - *pos < 8 {
+        return None;
+    }
+    *pos += 8;
+    Some(bytes_to_u64(&data[*pos - 8..*pos]))
+}
+
+// Only the last record can be torn by a crash, it is dropped when the log ends
+// inside of it. Any other record which can not be decoded is a corruption.
+fn replay(data: &[u8], locks: &mut Locks) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        let tag = data[pos];
+        pos += 1;
+        if tag != TAG_LOCK && tag != TAG_UNLOCK {
+            return Err(format!("corrupt record at offset {}: unknown tag {}", start, tag));
+        }
+        let key = match decode_bytes(data, &mut pos) {
+            Some(key) => key.to_vec(),
+            None => return Ok(()),
+        };
+        if tag == TAG_UNLOCK {
+            let ts = match decode_u64(data, &mut pos) {
+                Some(ts) => ts,
+                None => return Ok(()),
+            };
+            if locks.get(&key).map_or(false, |(start_ts, _)| *start_ts == ts) {
+                locks.remove(&key);
+            }
+        } else {
+            let lock = match decode_bytes(data, &mut pos) {
+                Some(lock) => Lock::parse(lock)
+                    .map_err(|e| format!("corrupt record at offset {}: {}", start, e))?,
+                None => return Ok(()),
+            };
+            locks.insert(key, (lock.start_ts, lock.short_value));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_wal_replay() {
+        let dir = TempDir::new("_wal_replay").unwrap();
+        {
+            let (mut wal, locks) = Wal::open(dir.path()).unwrap();
+            assert!(locks.is_empty());
+            wal.append_lock(&b"k1".to_vec(), Some(&b"v1".to_vec()), 1).unwrap();
+            wal.append_lock(&b"k2".to_vec(), None, 1).unwrap();
+            wal.append_lock(&b"k3".to_vec(), Some(&b"v3".to_vec()), 2).unwrap();
+            wal.append_unlock(&b"k1".to_vec(), 1).unwrap();
+        }
+        let path = dir.path().join(WAL_FILE_NAME);
+        // Simulate a torn write of the last record.
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&[TAG_LOCK, 2, 0]);
+        fs::write(&path, &data).unwrap();
+        for _ in 0..2 {
+            let (_, locks) = Wal::open(dir.path()).unwrap();
+            assert_eq!(locks.len(), 2);
+            assert_eq!(locks[&b"k2".to_vec()], (1, None));
+            assert_eq!(locks[&b"k3".to_vec()], (2, Some(b"v3".to_vec())));
+        }
+    }
+
+    #[test]
+    fn test_wal_corruption() {
+        let dir = TempDir::new("_wal_corruption").unwrap();
+        {
+            let (mut wal, _) = Wal::open(dir.path()).unwrap();
+            wal.append_lock(&b"k1".to_vec(), Some(&b"v1".to_vec()), 1).unwrap();
+            wal.append_lock(&b"k2".to_vec(), Some(&b"v2".to_vec()), 1).unwrap();
+        }
+        let path = dir.path().join(WAL_FILE_NAME);
+        let data = fs::read(&path).unwrap();
+
+        // A broken tag in front of a complete record.
+        let mut broken = data.clone();
+        broken[0] = b'X';
+        fs::write(&path, &broken).unwrap();
+        assert!(Wal::open(dir.path()).err().unwrap().contains("corrupt record at offset 0"));
+
+        // A broken lock which is followed by more records.
+        let mut broken = data.clone();
+        let lock_at = 1 + 8 + 2 + 8;
+        broken[lock_at] = 0;
+        fs::write(&path, &broken).unwrap();
+        assert!(Wal::open(dir.path()).err().unwrap().contains("corrupt record at offset 0"));
+        // The log is not rewritten when it can not be replayed.
+        assert_eq!(fs::read(&path).unwrap(), broken);
+
+        fs::write(&path, &data).unwrap();
+        let (_, locks) = Wal::open(dir.path()).unwrap();
+        assert_eq!(locks.len(), 2);
+    }
+}
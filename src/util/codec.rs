@@ -0,0 +1,226 @@
+///
+/// Order-preserving encoding of keys and timestamps.
+///
+/// Bytes are encoded in groups of 8, each followed by a marker telling how many
+/// bytes of the group are padding, so that no encoded key is a prefix of another
+/// one and the order of encoded keys is the order of the raw keys. Timestamps are
+/// bitwise inverted and in big endian, newer versions of a key sort first.
+/// Varints are for values which need no order, e.g. fields of records.
+///
+
+const ENC_GROUP_SIZE: usize = 8;
+const ENC_MARKER: u8 = 0xFF;
+const ENC_PAD: u8 = 0;
+
+pub const TS_LEN: usize = 8;
+
+pub fn corrupted(what: &str, data: &[u8]) -> String {
+    format!("corrupted {}: {:?}", what, data)
+}
+
+/// The length of `len` bytes once encoded.
+pub fn encoded_bytes_len(len: usize) -> usize {
+    (len / ENC_GROUP_SIZE + 1) * (ENC_GROUP_SIZE + 1)
+}
+
+pub fn encode_bytes(key: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(encoded_bytes_len(key.len()) + TS_LEN);
+    encode_bytes_to(&mut res, key);
+    res
+}
+
+/// Append the encoded `key` to `buf`, a buffer can be cleared and reused for many keys.
+pub fn encode_bytes_to(buf: &mut Vec<u8>, key: &[u8]) {
+    buf.reserve(encoded_bytes_len(key.len()));
+    for chunk in key.chunks(ENC_GROUP_SIZE) {
+        buf.extend_from_slice(chunk);
+        let pad = ENC_GROUP_SIZE - chunk.len();
+        buf.extend((0..pad).map(|_| ENC_PAD));
+        buf.push(ENC_MARKER - pad as u8);
+    }
+    // A full last group, or an empty key, is followed by a group of padding only.
+    if key.len() % ENC_GROUP_SIZE == 0 {
+        buf.extend((0..ENC_GROUP_SIZE).map(|_| ENC_PAD));
+        buf.push(ENC_MARKER - ENC_GROUP_SIZE as u8);
+    }
+}
+
+/// Decode bytes at the beginning of `data`, return them and the length of their encoding.
+pub fn decode_bytes(data: &[u8]) -> Result<(Vec<u8>, usize), String> {
+    let mut key = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    loop {
+        if data.len() - pos < ENC_GROUP_SIZE + 1 {
+            return Err(corrupted("bytes", data));
+        }
+        let group = &data[pos..pos + ENC_GROUP_SIZE];
+        let marker = data[pos + ENC_GROUP_SIZE];
+        pos += ENC_GROUP_SIZE + 1;
+        let pad = (ENC_MARKER - marker) as usize;
+        if pad > ENC_GROUP_SIZE {
+            return Err(corrupted("bytes", data));
+        }
+        let (bytes, padding) = group.split_at(ENC_GROUP_SIZE - pad);
+        key.extend_from_slice(bytes);
+        if pad > 0 {
+            if padding.iter().any(|b| *b != ENC_PAD) {
+                return Err(corrupted("bytes", data));
+            }
+            return Ok((key, pos));
+        }
+    }
+}
+
+pub fn encode_u64_desc(v: u64) -> [u8; 8] {
+    (!v).to_be_bytes()
+}
+
+pub fn decode_u64_desc(data: &[u8]) -> Result<u64, String> {
+    if data.len() != 8 {
+        return Err(corrupted("u64", data));
+    }
+    let mut buf = [0; 8];
+    buf.copy_from_slice(data);
+    Ok(!u64::from_be_bytes(buf))
+}
+
+/// Append `v` in 7 bits groups, the lowest first, the highest bit of every byte but
+/// the last is set.
+pub fn encode_var_u64(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Decode a varint at the beginning of `data`, return it and the length of its encoding.
+pub fn decode_var_u64(data: &[u8]) -> Result<(u64, usize), String> {
+    let mut v = 0;
+    for (i, b) in data.iter().enumerate().take(10) {
+        // The 10th byte only holds the highest bit.
+        if i == 9 && *b > 1 {
+            break;
+        }
+        v |= u64::from(b & 0x7F) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((v, i + 1));
+        }
+    }
+    Err(corrupted("varint", data))
+}
+
+/// The encoded key followed by `ts`, versions of a key are sorted by ts in
+/// descending order, right after the encoded key itself.
+pub fn encode_versioned_key(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut res = Vec::with_capacity(encoded_bytes_len(key.len()) + TS_LEN);
+    encode_versioned_key_to(&mut res, key, ts);
+    res
+}
+
+pub fn encode_versioned_key_to(buf: &mut Vec<u8>, key: &[u8], ts: u64) {
+    encode_bytes_to(buf, key);
+    buf.extend_from_slice(&encode_u64_desc(ts));
+}
+
+/// The smallest key after every versioned key of `key`, an exclusive upper bound of
+/// its versions.
+pub fn versioned_key_end(key: &[u8]) -> Vec<u8> {
+    let mut res = encode_bytes(key);
+    // The last group always has padding, so its marker is never 0xFF.
+    *res.last_mut().unwrap() += 1;
+    res
+}
+
+/// Split a versioned key into the encoded key and the ts.
+pub fn split_versioned_key(key: &[u8]) -> Result<(&[u8], u64), String> {
+    if key.len() < TS_LEN {
+        return Err(corrupted("versioned key", key));
+    }
+    let (encoded, ts) = key.split_at(key.len() - TS_LEN);
+    Ok((encoded, decode_u64_desc(ts)?))
+}
+
+pub fn decode_versioned_key(key: &[u8]) -> Result<(Vec<u8>, u64), String> {
+    let (encoded, ts) = split_versioned_key(key)?;
+    let (raw, len) = decode_bytes(encoded)?;
+    if len != encoded.len() {
+        return Err(corrupted("versioned key", key));
+    }
+    Ok((raw, ts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::u64;
+
+    #[test]
+    fn test_bytes_codec() {
+        let keys: Vec<&[u8]> = vec![b"", b"a", b"ab", b"abcdefg", b"abcdefgh", b"abcdefghi", &[0, 0, 0, 0, 0, 0, 0, 0, 0xFF]];
+        let mut buf = b"stale".to_vec();
+        for key in keys.iter() {
+            let encoded = encode_bytes(key);
+            assert_eq!(encoded.len(), encoded_bytes_len(key.len()));
+            assert_eq!(decode_bytes(&encoded).unwrap(), (key.to_vec(), encoded.len()));
+            let mut with_suffix = encoded.clone();
+            with_suffix.extend_from_slice(b"suffix");
+            assert_eq!(decode_bytes(&with_suffix).unwrap(), (key.to_vec(), encoded.len()));
+            assert!(decode_bytes(&encoded[..encoded.len() - 1]).is_err());
+            // A reused buffer only keeps what is appended after clearing it.
+            buf.clear();
+            encode_bytes_to(&mut buf, key);
+            assert_eq!(buf, encoded);
+        }
+        // Bad marker and non-zero padding.
+        assert!(decode_bytes(&[b'a', 0, 0, 0, 0, 0, 0, 0, 0xF0]).is_err());
+        assert!(decode_bytes(&[b'a', 1, 0, 0, 0, 0, 0, 0, 0xF8]).is_err());
+    }
+
+    #[test]
+    fn test_versioned_key_order() {
+        let keys: Vec<&[u8]> = vec![b"", b"\x00", b"\x00\x00", b"a", b"a\x00", b"ab", b"abcdefgh", b"abcdefgh\x00", b"b"];
+        let tss = [u64::MAX, 10, 2, 1, 0];
+        let mut encoded = vec![];
+        for key in keys.iter() {
+            for ts in tss.iter() {
+                let k = encode_versioned_key(key, *ts);
+                assert_eq!(decode_versioned_key(&k).unwrap(), (key.to_vec(), *ts));
+                assert_eq!(split_versioned_key(&k).unwrap(), (encode_bytes(key).as_slice(), *ts));
+                encoded.push(k);
+            }
+        }
+        // Keys are in order, and all versions of a key are adjacent with the newest first.
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(sorted, encoded);
+        // The end of the versions of a key is at most the first version of the next key.
+        for (i, key) in keys.iter().enumerate() {
+            let end = versioned_key_end(key);
+            assert!(encode_versioned_key(key, 0) < end);
+            if let Some(next) = keys.get(i + 1) {
+                assert!(end <= encode_versioned_key(next, u64::MAX));
+            }
+        }
+
+        assert_eq!(decode_u64_desc(&encode_u64_desc(7)).unwrap(), 7);
+        assert!(encode_u64_desc(8) < encode_u64_desc(7));
+        assert!(split_versioned_key(b"short").is_err());
+        assert!(decode_versioned_key(&encode_bytes(b"no ts")).is_err());
+    }
+
+    #[test]
+    fn test_var_u64() {
+        for v in [0, 1, 0x7F, 0x80, 300, 1 << 35, u64::MAX - 1, u64::MAX].iter() {
+            let mut buf = vec![];
+            encode_var_u64(&mut buf, *v);
+            assert_eq!(decode_var_u64(&buf).unwrap(), (*v, buf.len()));
+            buf.push(0xFF);
+            assert_eq!(decode_var_u64(&buf).unwrap(), (*v, buf.len() - 1));
+            buf.pop();
+            assert!(decode_var_u64(&buf[..buf.len() - 1]).is_err());
+        }
+        assert_eq!(decode_var_u64(&[0xFF; 10]).unwrap_err(), corrupted("varint", &[0xFF; 10]));
+        assert!(decode_var_u64(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02]).is_err());
+    }
+}
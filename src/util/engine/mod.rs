@@ -1,12 +1,13 @@
 use std::fs::{self};
 use std::path::Path;
-use std::sync::Arc;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rocksdb::{
-    ColumnFamilyOptions, DBCompressionType, DBOptions, SliceTransform, DB,
+    supported_compression, ColumnFamilyOptions, DBCompressionType, DBIterator, DBOptions, DBVector,
+    EnvOptions, IngestExternalFileOptions, ReadOptions, SliceTransform, Snapshot, SstFileWriter, DB,
 };
 use super::super::mvcc::CF_DEFAULT;
-// use util::file::{calc_crc32, copy_and_sync};
 
 pub use rocksdb::CFHandle;
 
@@ -22,18 +23,148 @@ const COMPRESSION_PRIORITY: [DBCompressionType; 3] = [
     DBCompressionType::Zstd,
 ];
 
+// Window bits, level and strategy of zstd, as the defaults of rocksdb.
+const ZSTD_WINDOW_BITS: i32 = -14;
+const ZSTD_LEVEL: i32 = 32767;
+const ZSTD_STRATEGY: i32 = 0;
+// Zstd trains the dictionary on up to this many times of its size.
+const ZSTD_TRAIN_BYTES_RATIO: i32 = 100;
+
+pub fn is_compression_supported(compression: DBCompressionType) -> bool {
+    compression == DBCompressionType::No || supported_compression().contains(&compression)
+}
+
+/// The first algorithm of `COMPRESSION_PRIORITY` supported by rocksdb, or no compression.
+pub fn get_fastest_supported_compression_type() -> DBCompressionType {
+    let all = supported_compression();
+    COMPRESSION_PRIORITY
+        .iter()
+        .find(|c| all.contains(c))
+        .cloned()
+        .unwrap_or(DBCompressionType::No)
+}
+
+/// Compression of each of `num_levels` levels: none for the first `uncompressed_levels`
+/// ones, which are rewritten soon, and `compression` for the others.
+pub fn get_per_level_compression(compression: DBCompressionType, num_levels: usize, uncompressed_levels: usize) -> Vec<DBCompressionType> {
+    (0..num_levels)
+        .map(|level| if level < uncompressed_levels { DBCompressionType::No } else { compression })
+        .collect()
+}
+
+/// Set up compression of a column family per level. If `bottommost_zstd_dict_size` is
+/// given, the last level is compressed by zstd with a dictionary of that size.
+pub fn set_cf_compression(
+    cf_opts: &mut ColumnFamilyOptions,
+    compression: DBCompressionType,
+    num_levels: usize,
+    uncompressed_levels: usize,
+    bottommost_zstd_dict_size: Option<i32>,
+) -> Result<(), String> {
+    if !is_compression_supported(compression) {
+        return Err(format!("compression {:?} is not supported", compression));
+    }
+    cf_opts.set_num_levels(num_levels as i32);
+    cf_opts.compression_per_level(&get_per_level_compression(compression, num_levels, uncompressed_levels));
+    if let Some(dict_size) = bottommost_zstd_dict_size {
+        if !is_compression_supported(DBCompressionType::Zstd) {
+            return Err(String::from("bottommost compression zstd is not supported"));
+        }
+        cf_opts.bottommost_compression(DBCompressionType::Zstd);
+        cf_opts.set_bottommost_compression_options(
+            ZSTD_WINDOW_BITS,
+            ZSTD_LEVEL,
+            ZSTD_STRATEGY,
+            dict_size,
+            dict_size * ZSTD_TRAIN_BYTES_RATIO,
+        );
+    }
+    Ok(())
+}
+
 pub fn get_cf_handle<'a>(db: &'a DB, cf: &str) -> Result<&'a CFHandle, String> {
     db.cf_handle(cf)
         .ok_or_else(|| format!("cf {} not found.", cf))
 }
 
-pub fn open_opt(
-    opts: DBOptions,
-    path: &str,
-    cfs: Vec<&str>,
-    cfs_opts: Vec<ColumnFamilyOptions>,
-) -> Result<DB, String> {
-    DB::open_cf(opts, path, cfs.into_iter().zip(cfs_opts).collect())
+/// Reads of the latest data of a db, or of a snapshot of it, so that a read path
+/// can serve both.
+pub enum DbReader<'a> {
+    Db(&'a DB),
+    Snapshot(&'a Snapshot<&'a DB>),
+}
+
+impl<'a> DbReader<'a> {
+    pub fn get_opt(&self, key: &[u8], opt: ReadOptions) -> Result<Option<DBVector>, String> {
+        match self {
+            DbReader::Db(db) => db.get_opt(key, &opt),
+            DbReader::Snapshot(snap) => snap.get_opt(key, opt),
+        }
+    }
+
+    pub fn get_cf(&self, cf: &CFHandle, key: &[u8]) -> Result<Option<DBVector>, String> {
+        match self {
+            DbReader::Db(db) => db.get_cf(cf, key),
+            DbReader::Snapshot(snap) => snap.get_cf(cf, key),
+        }
+    }
+
+    pub fn iter_opt(&self, opt: ReadOptions) -> DBIterator<&'a DB> {
+        match *self {
+            DbReader::Db(db) => db.iter_opt(opt),
+            DbReader::Snapshot(snap) => snap.iter_opt(opt),
+        }
+    }
+
+    pub fn iter_cf_opt(&self, cf: &CFHandle, opt: ReadOptions) -> DBIterator<&'a DB> {
+        match *self {
+            DbReader::Db(db) => db.iter_cf_opt(cf, opt),
+            DbReader::Snapshot(snap) => snap.iter_cf(cf, opt),
+        }
+    }
+}
+
+// Makes names of the SST files written for ingestion unique in the process.
+static SST_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `kvs` into an SST file in the directory of `db` and move it into column
+/// family `cf`. Keys must be in the order of the comparator of `cf_opts`.
+pub fn ingest_sorted<I>(db: &DB, cf: &str, cf_opts: ColumnFamilyOptions, kvs: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    let handle = get_cf_handle(db, cf)?;
+    let id = SST_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    let path = Path::new(db.path()).join(format!("ingest_{}_{}.sst", process::id(), id));
+    let path = path.to_str().unwrap();
+    let mut writer = SstFileWriter::new(EnvOptions::new(), cf_opts);
+    let ret = writer.open(path).and_then(|_| {
+        let mut entries = 0;
+        for (key, value) in kvs {
+            writer.put(&key, &value)?;
+            entries += 1;
+        }
+        // Rocksdb refuses to finish an empty file.
+        if entries == 0 {
+            return Ok(false);
+        }
+        writer.finish()?;
+        Ok(true)
+    });
+    let ret = match ret {
+        Ok(true) => {
+            let mut opt = IngestExternalFileOptions::new();
+            opt.move_files(true);
+            db.ingest_external_file_cf(handle, &opt, &[path])
+        }
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+    };
+    // The file is left behind if it is empty, or it failed to be written or ingested.
+    if Path::new(path).exists() {
+        let _ = fs::remove_file(path);
+    }
+    ret
 }
 
 pub struct CFOptions<'a> {
@@ -72,25 +203,45 @@ fn check_and_open(
 
         return Ok(db);
     }
-//    db_opt.create_if_missing(false);
-//
-//    // Open db.
-    let mut cfs_v = Vec::new();
-//    let mut cfs_opts_v: Vec<ColumnFamilyOptions> = Vec::new();
-    for cf in cfs_opts.iter() {
-        cfs_v.push((cf.cf, cf.options.clone()));
-//        match cfs_opts.iter().find(|x| x.cf == *cf) {
-//            Some(x) => {
-//                let mut tmp = CFOptions::new(x.cf, x.options.clone());
-//                cfs_opts_v.push(tmp.options);
-//            }
-//            None => {
-//                cfs_opts_v.push(ColumnFamilyOptions::new());
-//            }
-//        }
-    }
-    // let cfds = cfs_v.into_iter().zip(cfs_opts_v).collect();
-    DB::open_cf(db_opt, path, cfs_v)
+    db_opt.create_if_missing(false);
+
+    // List all column families in current db.
+    let cfs_list = DB::list_column_families(&db_opt, path)?;
+    let existed: Vec<&str> = cfs_list.iter().map(|v| v.as_str()).collect();
+    let needed: Vec<&str> = cfs_opts.iter().map(|x| x.cf).collect();
+
+    // All existing column families have to be opened, with the requested options
+    // if they are still needed. Rocksdb refuses to open one with another comparator
+    // than it was created with, so the discarded ones take the options of the default
+    // column family, which carry the comparator of the storage.
+    let default_opts = cfs_opts
+        .iter()
+        .find(|x| x.cf == CF_DEFAULT)
+        .map(|x| x.options.clone())
+        .unwrap_or_else(ColumnFamilyOptions::new);
+    let mut cfs_v = vec![];
+    for cf in existed.iter() {
+        match cfs_opts.iter().find(|x| x.cf == *cf) {
+            Some(x) => cfs_v.push((x.cf, x.options.clone())),
+            None => cfs_v.push((*cf, default_opts.clone())),
+        }
+    }
+    let mut db = DB::open_cf(db_opt, path, cfs_v)?;
+
+    // Drop discarded column families, but never the default one.
+    for cf in existed.iter() {
+        if !needed.contains(cf) && *cf != CF_DEFAULT {
+            db.drop_cf(cf)?;
+        }
+    }
+
+    // Create newly requested column families.
+    for x in cfs_opts {
+        if !existed.contains(&x.cf) {
+            db.create_cf_opt(x.cf, x.options)?;
+        }
+    }
+    Ok(db)
 }
 
 pub fn new_engine_opt(path: &str, opts: DBOptions, cfs_opts: Vec<CFOptions>) -> Result<DB, String> {
@@ -178,14 +329,9 @@ impl SliceTransform for NoopSliceTransform {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rocksdb::{
-        ColumnFamilyOptions, DBOptions, EnvOptions, IngestExternalFileOptions, SstFileWriter,
-        Writable, DB,
-    };
+    use rocksdb::{ColumnFamilyOptions, DBOptions, DB};
     use super::CF_DEFAULT;
     use tempdir::TempDir;
-    use std::str::FromStr;
-    use std::cmp;
 
     #[test]
     fn test_check_and_open() {
@@ -198,7 +344,7 @@ mod tests {
         opts.set_level_compaction_dynamic_level_bytes(true);
         cfs_opts.push(CFOptions::new("cf_dynamic_level_bytes", opts.clone()));
         {
-            let mut db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
+            let _db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
             column_families_must_eq(path_str, vec![CF_DEFAULT, "cf_dynamic_level_bytes"]);
         }
 
@@ -209,7 +355,7 @@ mod tests {
             CFOptions::new("cf1", opts.clone()),
         ];
         {
-            let mut db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
+            let _db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
             column_families_must_eq(path_str, vec![CF_DEFAULT, "cf_dynamic_level_bytes", "cf1"]);
         }
 
@@ -219,7 +365,7 @@ mod tests {
             CFOptions::new("cf_dynamic_level_bytes", ColumnFamilyOptions::new()),
         ];
         {
-            let db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
+            let _db = check_and_open(path_str, DBOptions::new(), cfs_opts).unwrap();
             column_families_must_eq(path_str, vec![CF_DEFAULT, "cf_dynamic_level_bytes"]);
         }
 
@@ -229,12 +375,29 @@ mod tests {
         column_families_must_eq(path_str, vec![CF_DEFAULT]);
     }
 
+    #[test]
+    fn test_per_level_compression() {
+        assert!(is_compression_supported(get_fastest_supported_compression_type()));
+        assert!(is_compression_supported(DBCompressionType::No));
+        assert_eq!(
+            get_per_level_compression(DBCompressionType::Lz4, 4, 2),
+            vec![DBCompressionType::No, DBCompressionType::No, DBCompressionType::Lz4, DBCompressionType::Lz4]
+        );
+        assert_eq!(get_per_level_compression(DBCompressionType::Zstd, 2, 3), vec![DBCompressionType::No; 2]);
+
+        let mut opts = ColumnFamilyOptions::new();
+        set_cf_compression(&mut opts, get_fastest_supported_compression_type(), 7, 2, None).unwrap();
+        if !is_compression_supported(DBCompressionType::Bz2) {
+            assert!(set_cf_compression(&mut opts, DBCompressionType::Bz2, 7, 2, None).is_err());
+        }
+    }
+
     fn column_families_must_eq(path: &str, excepted: Vec<&str>) {
         let opts = DBOptions::new();
         let cfs_list = DB::list_column_families(&opts, path).unwrap();
 
         let mut cfs_existed: Vec<&str> = cfs_list.iter().map(|v| v.as_str()).collect();
-        let mut cfs_excepted: Vec<&str> = excepted.iter().map(|v| *v).collect();
+        let mut cfs_excepted: Vec<&str> = excepted.to_vec();
         cfs_existed.sort();
         cfs_excepted.sort();
         assert_eq!(cfs_existed, cfs_excepted);
@@ -0,0 +1,42 @@
+///
+/// Named points where a failure can be injected, used to simulate crashes in tests.
+/// Fail points are configured per thread, so that tests running in parallel
+/// do not trigger each other's failures.
+///
+
+use std::cell::RefCell;
+
+use super::collection::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // Abort the current operation as if the process was killed.
+    Panic,
+    // Return an error from the fail point.
+    Error,
+}
+
+thread_local! {
+    static FAIL_POINTS: RefCell<HashMap<String, Action>> = RefCell::new(HashMap::default());
+}
+
+pub fn set(name: &str, action: Action) {
+    FAIL_POINTS.with(|points| points.borrow_mut().insert(name.to_string(), action));
+}
+
+pub fn remove(name: &str) {
+    FAIL_POINTS.with(|points| points.borrow_mut().remove(name));
+}
+
+pub fn clear() {
+    FAIL_POINTS.with(|points| points.borrow_mut().clear());
+}
+
+pub fn eval(name: &str) -> Result<(), String> {
+    let action = FAIL_POINTS.with(|points| points.borrow().get(name).cloned());
+    match action {
+        Some(Action::Panic) => panic!("fail point {} is triggered", name),
+        Some(Action::Error) => Err(format!("fail point {} is triggered", name)),
+        None => Ok(()),
+    }
+}
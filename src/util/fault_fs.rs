@@ -0,0 +1,73 @@
+///
+/// Simulate a power failure in tests: the logs in a directory lose everything
+/// which was written to them after their last sync. The lengths of synced files
+/// are tracked per thread, like fail points.
+///
+/// Only files named `*.log` or `*.wal` are truncated, i.e. the rocksdb WAL and the
+/// log of the mem-store. Files rocksdb writes through its own Env, such as SST
+/// files, the MANIFEST and CURRENT, are kept as they are, so a test only covers
+/// data which is durable once the logs are synced, not a torn flush or compaction.
+///
+
+use std::cell::RefCell;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use super::collection::HashMap;
+
+thread_local! {
+    static SYNCED: RefCell<HashMap<PathBuf, u64>> = RefCell::new(HashMap::default());
+}
+
+fn is_log(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("log") | Some("wal") => true,
+        _ => false,
+    }
+}
+
+fn logs(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("read {:?}: {:?}", dir, e))?;
+    let mut logs = vec![];
+    for entry in entries {
+        let path = entry.map_err(|e| format!("read {:?}: {:?}", dir, e))?.path();
+        if is_log(&path) {
+            logs.push(path);
+        }
+    }
+    Ok(logs)
+}
+
+// The same file may be named by a relative path and by the one of its directory.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Everything in the file at `path` is synced.
+pub fn synced(path: &Path) {
+    if let Ok(meta) = fs::metadata(path) {
+        SYNCED.with(|synced| synced.borrow_mut().insert(canonical(path), meta.len()));
+    }
+}
+
+// Everything in the logs of the db at `dir` is synced, after a write to rocksdb
+// with sync set.
+pub fn synced_logs(dir: &Path) {
+    for path in logs(dir).unwrap_or_default() {
+        synced(&path);
+    }
+}
+
+// Truncate the logs in `dir` to their length at the last sync, a log which has
+// never been synced loses all of its content.
+pub fn power_off(dir: &Path) -> Result<(), String> {
+    for path in logs(dir)? {
+        let len = SYNCED.with(|synced| synced.borrow().get(&canonical(&path)).cloned().unwrap_or(0));
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_len(len))
+            .map_err(|e| format!("truncate {:?}: {:?}", path, e))?;
+    }
+    Ok(())
+}
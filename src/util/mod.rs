@@ -1,3 +1,7 @@
-
+pub mod codec;
 pub mod collection;
-pub mod engine;
\ No newline at end of file
+pub mod engine;
+#[cfg(test)]
+pub mod fail_point;
+#[cfg(test)]
+pub mod fault_fs;
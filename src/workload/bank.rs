@@ -0,0 +1,374 @@
+///
+/// Bank transfer workload. Workers move money between accounts in two-key
+/// transactions, while a checker reads every account at one snapshot timestamp
+/// and asserts that the total never changes.
+///
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+use super::super::mvcc::{MvccStorage, ERR_KEY_LOCKED};
+use super::stats::{OpStats, Report, ThroughputMeter, REPORT_INTERVAL};
+use super::TimestampOracle;
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+// A lock which outlives this many retries is left by a transfer which failed.
+const LOCK_RETRY_LIMIT: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct BankConfig {
+    pub accounts: u64,
+    pub initial_balance: u64,
+    pub threads: usize,
+    pub duration: Duration,
+    pub check_interval: Duration,
+}
+
+impl Default for BankConfig {
+    fn default() -> Self {
+        Self {
+            accounts: 100,
+            initial_balance: 1000,
+            threads: 4,
+            duration: Duration::from_secs(10),
+            check_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl BankConfig {
+    /// The parameters of the workload, as saved with the benchmark results.
+    pub fn params(&self) -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert("accounts".to_owned(), self.accounts.to_string());
+        params.insert("initial_balance".to_owned(), self.initial_balance.to_string());
+        params.insert("duration".to_owned(), self.duration.as_secs().to_string());
+        params.insert("check_interval_ms".to_owned(), self.check_interval.as_millis().to_string());
+        params
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub from: u64,
+    pub to: u64,
+    pub amount: u64,
+    pub start_ts: u64,
+    pub commit_ts: u64,
+}
+
+/// A snapshot whose total differs from the expected one.
+#[derive(Clone, Debug)]
+pub struct Anomaly {
+    pub read_ts: u64,
+    pub expected: u64,
+    pub actual: u64,
+    // Committed transfers which were in flight when the snapshot was taken.
+    pub concurrent: Vec<Transfer>,
+}
+
+pub struct BankReport {
+    pub transfers: u64,
+    pub aborts: u64,
+    pub checks: u64,
+    pub anomalies: Vec<Anomaly>,
+    // Latency of transfers and checks, an aborted transfer counts as an error.
+    pub stats: Report,
+}
+
+impl BankReport {
+    pub fn print(&self) {
+        self.stats.print();
+        println!(
+            "[BANK] transfers: {}, aborts: {}, checks: {}, anomalies: {}",
+            self.transfers,
+            self.aborts,
+            self.checks,
+            self.anomalies.len()
+        );
+        for anomaly in self.anomalies.iter() {
+            println!(
+                "[ANOMALY] read_ts: {}, expected total: {}, actual total: {}",
+                anomaly.read_ts, anomaly.expected, anomaly.actual
+            );
+            for t in anomaly.concurrent.iter() {
+                println!(
+                    "    transfer {} -> {} amount {} start_ts {} commit_ts {}",
+                    t.from, t.to, t.amount, t.start_ts, t.commit_ts
+                );
+            }
+        }
+    }
+}
+
+pub fn account_key(id: u64) -> Vec<u8> {
+    format!("account{:08}", id).into_bytes()
+}
+
+fn encode_balance(balance: u64) -> Vec<u8> {
+    balance.to_be_bytes().to_vec()
+}
+
+fn decode_balance(value: &[u8]) -> Result<u64, String> {
+    if value.len() != 8 {
+        return Err(format!("bad balance length {}", value.len()));
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(value);
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read an account at `ts`, waiting for locks left by in-flight transfers.
+fn read_balance(storage: &Arc<dyn MvccStorage>, id: u64, ts: u64) -> Result<u64, String> {
+    let key = account_key(id);
+    for _ in 0..LOCK_RETRY_LIMIT {
+        match storage.get(&key, ts) {
+            Ok(Some(value)) => return decode_balance(&value),
+            Ok(None) => return Err(format!("account {} not found at {}", id, ts)),
+            Err(ref e) if e == ERR_KEY_LOCKED => thread::sleep(LOCK_RETRY_INTERVAL),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(format!("account {} is still locked at {} after {} retries", id, ts, LOCK_RETRY_LIMIT))
+}
+
+pub fn load(storage: &Arc<dyn MvccStorage>, config: &BankConfig, tso: &TimestampOracle) -> Result<(), String> {
+    let start_ts = tso.get_ts();
+    let value = encode_balance(config.initial_balance);
+    for id in 0..config.accounts {
+        storage.prewrite(&account_key(id), &value, start_ts)?;
+    }
+    let commit_ts = tso.get_ts();
+    for id in 0..config.accounts {
+        storage.commit(&account_key(id), start_ts, commit_ts)?;
+    }
+    Ok(())
+}
+
+/// Move a random amount between two random accounts. Returns `Ok(Err(e))` if the
+/// transaction was aborted because of `e`.
+fn transfer<R: Rng>(storage: &Arc<dyn MvccStorage>, tso: &TimestampOracle, accounts: u64, rng: &mut R) -> Result<Result<Transfer, String>, String> {
+    let from = rng.gen_range(0, accounts);
+    let mut to = rng.gen_range(0, accounts - 1);
+    if to >= from {
+        to += 1;
+    }
+    let start_ts = tso.get_ts();
+    let from_balance = match storage.get(&account_key(from), start_ts) {
+        Ok(Some(v)) => decode_balance(&v)?,
+        Ok(None) => return Err(format!("account {} not found at {}", from, start_ts)),
+        Err(e) => return Ok(Err(e)),
+    };
+    let to_balance = match storage.get(&account_key(to), start_ts) {
+        Ok(Some(v)) => decode_balance(&v)?,
+        Ok(None) => return Err(format!("account {} not found at {}", to, start_ts)),
+        Err(e) => return Ok(Err(e)),
+    };
+    let amount = rng.gen_range(0, from_balance + 1);
+    let mut mutations = vec![
+        (account_key(from), encode_balance(from_balance - amount)),
+        (account_key(to), encode_balance(to_balance + amount)),
+    ];
+    mutations.sort();
+    for i in 0..mutations.len() {
+        let (ref key, ref value) = mutations[i];
+        if let Err(e) = storage.prewrite(key, value, start_ts) {
+            for (key, _) in mutations[..i].iter() {
+                storage.rollback(key, start_ts)?;
+            }
+            return Ok(Err(e));
+        }
+    }
+    let commit_ts = tso.get_ts();
+    // The first key is the primary, once it is committed the transfer is committed.
+    let primary = &mutations[0].0;
+    if let Err(e) = storage.commit(primary, start_ts, commit_ts) {
+        // The primary may be committed although the commit failed, then it can not be
+        // rolled back and a retried commit finds it committed.
+        if let Err(err) = storage.rollback(primary, start_ts) {
+            storage
+                .commit(primary, start_ts, commit_ts)
+                .map_err(|_| format!("rollback of {:?} after commit failed by {}: {}", primary, e, err))?;
+        } else {
+            for (key, _) in mutations[1..].iter() {
+                storage
+                    .rollback(key, start_ts)
+                    .map_err(|err| format!("rollback of {:?} after commit failed by {}: {}", key, e, err))?;
+            }
+            return Ok(Err(e));
+        }
+    }
+    for (key, _) in mutations[1..].iter() {
+        storage
+            .resolve_lock(key, start_ts, commit_ts)
+            .map_err(|e| format!("transfer committed at {} left {:?} locked: {}", commit_ts, key, e))?;
+    }
+    Ok(Ok(Transfer {
+        from,
+        to,
+        amount,
+        start_ts,
+        commit_ts,
+    }))
+}
+
+fn check(storage: &Arc<dyn MvccStorage>, config: &BankConfig, tso: &TimestampOracle, history: &Mutex<Vec<Transfer>>) -> Result<Option<Anomaly>, String> {
+    let read_ts = tso.get_ts();
+    let mut total = 0;
+    for id in 0..config.accounts {
+        total += read_balance(storage, id, read_ts)?;
+    }
+    let expected = config.accounts * config.initial_balance;
+    if total == expected {
+        return Ok(None);
+    }
+    let concurrent = history
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|t| t.start_ts <= read_ts && read_ts <= t.commit_ts)
+        .cloned()
+        .collect();
+    Ok(Some(Anomaly {
+        read_ts,
+        expected,
+        actual: total,
+        concurrent,
+    }))
+}
+
+/// Run transfers for `config.duration` and check the total balance every
+/// `config.check_interval`. Accounts must have been created by `load`.
+pub fn run(storage: &Arc<dyn MvccStorage>, config: &BankConfig, tso: &Arc<TimestampOracle>) -> Result<BankReport, String> {
+    if config.accounts < 2 {
+        return Err(String::from("bank workload needs at least 2 accounts"));
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let history = Arc::new(Mutex::new(Vec::new()));
+    let aborts = Arc::new(AtomicU64::new(0));
+    let meter = ThroughputMeter::start(REPORT_INTERVAL);
+    let mut handlers = Vec::new();
+    for _ in 0..config.threads.max(1) {
+        let store = storage.clone();
+        let tso = tso.clone();
+        let stop = stop.clone();
+        let history = history.clone();
+        let aborts = aborts.clone();
+        let accounts = config.accounts;
+        let done = meter.counter();
+        let handle = thread::spawn(move || -> Result<OpStats, String> {
+            let mut rng = thread_rng();
+            let mut stats = OpStats::default();
+            while !stop.load(Ordering::SeqCst) {
+                let begin = Instant::now();
+                let ret = transfer(&store, &tso, accounts, &mut rng)?;
+                stats.record(begin.elapsed(), &ret);
+                done.fetch_add(1, Ordering::Relaxed);
+                match ret {
+                    Ok(t) => history.lock().unwrap().push(t),
+                    Err(_) => {
+                        aborts.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }
+            Ok(stats)
+        });
+        handlers.push(handle);
+    }
+
+    let mut checks = 0;
+    let mut anomalies = vec![];
+    let mut check_stats = OpStats::default();
+    let begin = Instant::now();
+    let mut result = Ok(());
+    while begin.elapsed() < config.duration {
+        thread::sleep(config.check_interval);
+        let check_begin = Instant::now();
+        let ret = check(storage, config, tso, &history);
+        check_stats.record(check_begin.elapsed(), &ret);
+        match ret {
+            Ok(anomaly) => {
+                checks += 1;
+                anomalies.extend(anomaly);
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    let mut transfer_stats = OpStats::default();
+    for h in handlers.into_iter() {
+        let ret = h.join().map_err(|_| String::from("transfer thread panicked"))?;
+        match ret {
+            Ok(stats) => transfer_stats.merge(&stats),
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    let elapsed = begin.elapsed();
+    let (interval, windows) = meter.finish();
+    result?;
+
+    // One more check after all transfers have finished.
+    anomalies.extend(check(storage, config, tso, &history)?);
+    let transfers = history.lock().unwrap().len() as u64;
+    Ok(BankReport {
+        transfers,
+        aborts: aborts.load(Ordering::SeqCst),
+        checks: checks + 1,
+        anomalies,
+        stats: Report {
+            name: String::from("bank"),
+            threads: config.threads.max(1),
+            elapsed,
+            ops: vec![("TRANSFER", transfer_stats), ("CHECK", check_stats)],
+            interval,
+            windows,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::mvcc::storage::create_storage;
+    use super::super::super::mvcc::StorageType;
+    use tempdir::TempDir;
+
+    fn inner_test_bank(storage_type: StorageType) {
+        let path = TempDir::new("_bank").expect("");
+        let storage = create_storage(path.path().to_str().unwrap(), storage_type).unwrap();
+        let config = BankConfig {
+            accounts: 10,
+            duration: Duration::from_secs(1),
+            check_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let tso = Arc::new(TimestampOracle::new(0));
+        load(&storage, &config, &tso).unwrap();
+        let report = run(&storage, &config, &tso).unwrap();
+        report.print();
+        assert!(report.transfers > 0);
+        assert!(report.anomalies.is_empty());
+    }
+
+    #[test]
+    fn test_bank_user_timestamp() {
+        inner_test_bank(StorageType::UserTimestampStorage);
+    }
+
+    #[test]
+    fn test_bank_tikv() {
+        inner_test_bank(StorageType::TiKVStorage);
+    }
+}
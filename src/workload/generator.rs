@@ -0,0 +1,149 @@
+///
+/// Key choosers used by the YCSB workloads.
+///
+
+use rand::Rng;
+
+const ZIPFIAN_CONSTANT: f64 = 0.99;
+const FNV_OFFSET_BASIS_64: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME_64: u64 = 1_099_511_628_211;
+
+pub fn fnv_hash64(val: u64) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS_64;
+    let mut val = val;
+    for _ in 0..8 {
+        let octet = val & 0x00ff;
+        val >>= 8;
+        hash ^= octet;
+        hash = hash.wrapping_mul(FNV_PRIME_64);
+    }
+    hash
+}
+
+/// Zipfian distribution over `[base, base + items)`, popular items are the small ones.
+pub struct ZipfianGenerator {
+    base: u64,
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl ZipfianGenerator {
+    pub fn new(base: u64, items: u64) -> Self {
+        Self::with_theta(base, items, ZIPFIAN_CONSTANT)
+    }
+
+    pub fn with_theta(base: u64, items: u64, theta: f64) -> Self {
+        let zeta2theta = zeta(2, theta);
+        let zetan = zeta(items, theta);
+        let alpha = 1.0 / (1.0 - theta);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2theta / zetan);
+        Self {
+            base,
+            items,
+            theta,
+            alpha,
+            zetan,
+            eta,
+        }
+    }
+
+    pub fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return self.base;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return self.base + 1;
+        }
+        let ret = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        self.base + ret.min(self.items - 1)
+    }
+}
+
+fn zeta(n: u64, theta: f64) -> f64 {
+    let mut sum = 0.0;
+    for i in 0..n {
+        sum += 1.0 / ((i + 1) as f64).powf(theta);
+    }
+    sum
+}
+
+/// Zipfian distribution whose popular items are spread over the whole key space.
+pub struct ScrambledZipfianGenerator {
+    base: u64,
+    items: u64,
+    gen: ZipfianGenerator,
+}
+
+impl ScrambledZipfianGenerator {
+    pub fn new(base: u64, items: u64) -> Self {
+        Self {
+            base,
+            items,
+            gen: ZipfianGenerator::new(0, items),
+        }
+    }
+
+    pub fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let ret = self.gen.next(rng);
+        self.base + fnv_hash64(ret) % self.items
+    }
+}
+
+pub enum KeyChooser {
+    Uniform(u64),
+    Zipfian(ScrambledZipfianGenerator),
+    // Skewed to the most recently inserted keys.
+    Latest(ZipfianGenerator),
+}
+
+impl KeyChooser {
+    /// `max` is the number of keys which have been inserted so far.
+    pub fn next<R: Rng>(&self, rng: &mut R, max: u64) -> u64 {
+        match self {
+            KeyChooser::Uniform(items) => rng.gen_range(0, max.min(*items).max(1)),
+            KeyChooser::Zipfian(gen) => gen.next(rng).min(max.max(1) - 1),
+            KeyChooser::Latest(gen) => {
+                let offset = gen.next(rng);
+                max.saturating_sub(offset + 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_zipfian_in_range() {
+        let mut rng = thread_rng();
+        let gen = ZipfianGenerator::new(10, 100);
+        let mut hits = vec![0; 100];
+        for _ in 0..10000 {
+            let v = gen.next(&mut rng);
+            assert!(v >= 10 && v < 110);
+            hits[(v - 10) as usize] += 1;
+        }
+        assert!(hits[0] > hits[50]);
+
+        let gen = ScrambledZipfianGenerator::new(0, 100);
+        for _ in 0..10000 {
+            assert!(gen.next(&mut rng) < 100);
+        }
+    }
+
+    #[test]
+    fn test_latest_chooser() {
+        let mut rng = thread_rng();
+        let chooser = KeyChooser::Latest(ZipfianGenerator::new(0, 100));
+        for _ in 0..1000 {
+            assert!(chooser.next(&mut rng, 50) < 50);
+        }
+    }
+}
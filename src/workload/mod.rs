@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub mod bank;
+pub mod generator;
+pub mod result;
+pub mod stats;
+pub mod ycsb;
+
+/// Hands out strictly increasing timestamps to the transactions of a workload.
+pub struct TimestampOracle {
+    ts: AtomicU64,
+}
+
+impl TimestampOracle {
+    pub fn new(start: u64) -> Self {
+        Self {
+            ts: AtomicU64::new(start),
+        }
+    }
+
+    pub fn get_ts(&self) -> u64 {
+        self.ts.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn current(&self) -> u64 {
+        self.ts.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,308 @@
+///
+/// Machine readable benchmark results, and a side-by-side comparison of the
+/// results of different storage models.
+///
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::super::mvcc::stats::StorageStats;
+use super::stats::{duration_secs, OpStats, Report};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OpResult {
+    pub name: String,
+    pub ops: u64,
+    pub locked_errors: u64,
+    pub conflict_errors: u64,
+    pub engine_errors: u64,
+    pub throughput: f64,
+    pub avg_us: f64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+impl OpResult {
+    fn new(name: &str, stats: &OpStats, secs: f64) -> Self {
+        let h = &stats.latency;
+        Self {
+            name: name.to_owned(),
+            ops: stats.count(),
+            locked_errors: stats.errors[0],
+            conflict_errors: stats.errors[1],
+            engine_errors: stats.errors[2],
+            throughput: if secs > 0.0 { stats.count() as f64 / secs } else { 0.0 },
+            avg_us: h.mean(),
+            p50_us: h.percentile(50.0),
+            p95_us: h.percentile(95.0),
+            p99_us: h.percentile(99.0),
+            p999_us: h.percentile(99.9),
+            max_us: h.max(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub storage_type: String,
+    pub workload: String,
+    pub threads: usize,
+    pub params: BTreeMap<String, String>,
+    pub rocksdb_options: BTreeMap<String, String>,
+    pub elapsed_secs: f64,
+    // All operations together.
+    pub total: OpResult,
+    pub ops: Vec<OpResult>,
+    // Throughput of every report interval.
+    pub windows: Vec<f64>,
+    // Size of all files in the db directory at the end of the run.
+    pub space_bytes: u64,
+    // Space and write amplification at the end of the run.
+    #[serde(default)]
+    pub storage: Option<StorageStats>,
+}
+
+const CSV_HEADER: &str = "storage_type,workload,threads,elapsed_secs,op,ops,locked_errors,conflict_errors,engine_errors,\
+throughput,avg_us,p50_us,p95_us,p99_us,p999_us,max_us,space_bytes,logical_bytes_written,rocksdb_bytes_written,\
+sst_size,live_versions,total_versions,params,rocksdb_options";
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn join_map(map: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = map.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    pairs.join(";")
+}
+
+impl BenchResult {
+    pub fn new(storage_type: &str, report: &Report, params: BTreeMap<String, String>, rocksdb_options: BTreeMap<String, String>, space_bytes: u64, storage: Option<StorageStats>) -> Self {
+        let secs = duration_secs(report.elapsed);
+        let mut total = OpStats::default();
+        for (_, stats) in report.ops.iter() {
+            total.merge(stats);
+        }
+        let window_secs = duration_secs(report.interval);
+        Self {
+            storage_type: storage_type.to_owned(),
+            workload: report.name.clone(),
+            threads: report.threads,
+            params,
+            rocksdb_options,
+            elapsed_secs: secs,
+            total: OpResult::new("TOTAL", &total, secs),
+            ops: report
+                .ops
+                .iter()
+                .filter(|(_, s)| s.count() > 0)
+                .map(|(name, s)| OpResult::new(name, s, secs))
+                .collect(),
+            windows: report.windows.iter().map(|n| *n as f64 / window_secs).collect(),
+            space_bytes,
+            storage,
+        }
+    }
+
+    pub fn write_amplification(&self) -> f64 {
+        self.storage.as_ref().map_or(0.0, |s| s.write_amplification())
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("{}", e))
+    }
+
+    pub fn from_json(data: &str) -> Result<BenchResult, String> {
+        serde_json::from_str(data).map_err(|e| format!("{}", e))
+    }
+
+    /// One row for every operation, and a last one named `TOTAL` for all of them.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(CSV_HEADER);
+        out.push('\n');
+        let storage = self.storage.clone().unwrap_or_default();
+        for op in self.ops.iter().chain(Some(&self.total)) {
+            let fields = [
+                csv_field(&self.storage_type),
+                csv_field(&self.workload),
+                self.threads.to_string(),
+                format!("{:.3}", self.elapsed_secs),
+                csv_field(&op.name),
+                op.ops.to_string(),
+                op.locked_errors.to_string(),
+                op.conflict_errors.to_string(),
+                op.engine_errors.to_string(),
+                format!("{:.1}", op.throughput),
+                format!("{:.1}", op.avg_us),
+                op.p50_us.to_string(),
+                op.p95_us.to_string(),
+                op.p99_us.to_string(),
+                op.p999_us.to_string(),
+                op.max_us.to_string(),
+                self.space_bytes.to_string(),
+                storage.logical_bytes_written.to_string(),
+                storage.rocksdb_bytes_written().to_string(),
+                storage.total_sst_size().to_string(),
+                storage.live_versions.to_string(),
+                storage.total_versions.to_string(),
+                csv_field(&join_map(&self.params)),
+                csv_field(&join_map(&self.rocksdb_options)),
+            ];
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn save(&self, path: &str, format: Format) -> Result<(), String> {
+        let data = match format {
+            Format::Json => self.to_json()?,
+            Format::Csv => self.to_csv(),
+        };
+        fs::write(path, data).map_err(|e| format!("write {} failed: {}", path, e))
+    }
+
+    /// Load a result saved in JSON.
+    pub fn load(path: &str) -> Result<BenchResult, String> {
+        let data = fs::read_to_string(path).map_err(|e| format!("read {} failed: {}", path, e))?;
+        Self::from_json(&data).map_err(|e| format!("parse {} failed: {}", path, e))
+    }
+}
+
+/// The total size of the files under `path`.
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut size = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            size += dir_size(&entry.path());
+        } else {
+            size += meta.len();
+        }
+    }
+    size
+}
+
+fn relative(value: f64, base: f64) -> String {
+    if base > 0.0 {
+        format!("({:.2}x)", value / base)
+    } else {
+        String::from("(-)")
+    }
+}
+
+/// Print a table of throughput, latency, space and write amplification of every
+/// result, relative to the first result of the same workload.
+pub fn compare(results: &[BenchResult]) -> String {
+    let mut out = format!(
+        "{:<10} {:<16} {:>24} {:>22} {:>22} {:>22} {:>22}\n",
+        "workload", "storage_type", "throughput (ops/sec)", "avg (us)", "p99 (us)", "space (MB)", "write amp"
+    );
+    for r in results.iter() {
+        let base = results.iter().find(|b| b.workload == r.workload).unwrap();
+        let space = r.space_bytes as f64 / 1024.0 / 1024.0;
+        let base_space = base.space_bytes as f64 / 1024.0 / 1024.0;
+        out.push_str(&format!(
+            "{:<10} {:<16} {:>24} {:>22} {:>22} {:>22} {:>22}\n",
+            r.workload,
+            r.storage_type,
+            format!("{:.1} {}", r.total.throughput, relative(r.total.throughput, base.total.throughput)),
+            format!("{:.1} {}", r.total.avg_us, relative(r.total.avg_us, base.total.avg_us)),
+            format!("{} {}", r.total.p99_us, relative(r.total.p99_us as f64, base.total.p99_us as f64)),
+            format!("{:.1} {}", space, relative(space, base_space)),
+            format!("{:.2} {}", r.write_amplification(), relative(r.write_amplification(), base.write_amplification())),
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn result(storage_type: &str, latency: u64, space_bytes: u64) -> BenchResult {
+        let mut stats = OpStats::default();
+        for _ in 0..100 {
+            stats.record(Duration::from_micros(latency), &Ok(()));
+        }
+        stats.record(Duration::from_micros(latency), &Err::<(), _>(String::from("engine")));
+        let report = Report {
+            name: String::from("a"),
+            threads: 1,
+            elapsed: Duration::from_secs(1),
+            ops: vec![("READ", stats), ("UPDATE", OpStats::default())],
+            interval: Duration::from_secs(1),
+            windows: vec![101],
+        };
+        let mut params = BTreeMap::new();
+        params.insert(String::from("record_count"), String::from("100"));
+        let storage = StorageStats {
+            logical_bytes_written: 1000,
+            wal_bytes_written: latency * 10,
+            ..Default::default()
+        };
+        BenchResult::new(storage_type, &report, params, BTreeMap::new(), space_bytes, Some(storage))
+    }
+
+    #[test]
+    fn test_result_format() {
+        let r = result("tikv", 100, 1024);
+        assert_eq!(r.ops.len(), 1);
+        assert_eq!(r.total.ops, 101);
+        assert_eq!(r.total.engine_errors, 1);
+        assert_eq!(r.windows, vec![101.0]);
+        assert_eq!(BenchResult::from_json(&r.to_json().unwrap()).unwrap(), r);
+
+        let csv = r.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("tikv,a,1,1.000,READ,101,0,0,1,101.0,"));
+        assert!(lines[2].contains(",TOTAL,"));
+        assert!(lines[2].ends_with(",1024,1000,1000,0,0,0,record_count=100,"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let results = vec![result("user_timestamp", 100, 1 << 20), result("tikv", 200, 2 << 20)];
+        let table = compare(&results);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("user_timestamp") && lines[1].contains("1.0 (1.00x)"));
+        assert!(lines[2].contains("tikv") && lines[2].contains("2.0 (2.00x)"));
+        assert!(lines[2].ends_with("2.00 (2.00x)"));
+    }
+}
@@ -0,0 +1,353 @@
+///
+/// Latency histograms, error counts and throughput of benchmark runs.
+///
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::u64;
+
+use super::super::mvcc::{ERR_KEY_LOCKED, ERR_KEY_VERSION};
+
+// Values below `1 << (SUB_BUCKET_BITS + 1)` have a bucket each, larger ones are
+// kept with `SUB_BUCKET_BITS` bits of precision.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const LINEAR_BUCKETS: u64 = SUB_BUCKETS * 2;
+const BUCKETS: usize = (LINEAR_BUCKETS + (64 - SUB_BUCKET_BITS as u64 - 1) * SUB_BUCKETS) as usize;
+
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A histogram of latencies in microseconds, with a relative error of about 3%.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+fn bucket_index(v: u64) -> usize {
+    if v < LINEAR_BUCKETS {
+        return v as usize;
+    }
+    let exp = 63 - u64::from(v.leading_zeros());
+    let shift = exp - u64::from(SUB_BUCKET_BITS);
+    let sub = (v >> shift) - SUB_BUCKETS;
+    (LINEAR_BUCKETS + (exp - u64::from(SUB_BUCKET_BITS) - 1) * SUB_BUCKETS + sub) as usize
+}
+
+// The largest value of a bucket.
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < LINEAR_BUCKETS {
+        return index;
+    }
+    let shift = (index - LINEAR_BUCKETS) / SUB_BUCKETS + 1;
+    let sub = (index - LINEAR_BUCKETS) % SUB_BUCKETS + SUB_BUCKETS;
+    ((sub + 1) << shift).wrapping_sub(1)
+}
+
+impl Histogram {
+    pub fn record(&mut self, us: u64) {
+        self.buckets[bucket_index(us)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(us);
+        self.min = self.min.min(us);
+        self.max = self.max.max(us);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (b, o) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *b += *o;
+        }
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// The value below which `p` percent of the recorded values fall.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = ((p / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n;
+            if seen >= target {
+                return bucket_value(i).min(self.max).max(self.min);
+            }
+        }
+        self.max
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    // The key is locked by another transaction.
+    Locked,
+    // A write conflict, or the transaction was resolved by someone else.
+    Conflict,
+    // Any other error, mostly returned by the engine.
+    Engine,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 3] = [ErrorKind::Locked, ErrorKind::Conflict, ErrorKind::Engine];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Locked => "locked",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Engine => "engine",
+        }
+    }
+
+    pub fn classify(err: &str) -> ErrorKind {
+        if err == ERR_KEY_LOCKED {
+            ErrorKind::Locked
+        } else if err == ERR_KEY_VERSION
+            || err.contains("by other txn")
+            || err.contains("by other transaction")
+        {
+            ErrorKind::Conflict
+        } else {
+            ErrorKind::Engine
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OpStats {
+    pub latency: Histogram,
+    // Indexed by the position in `ErrorKind::ALL`.
+    pub errors: [u64; 3],
+}
+
+impl OpStats {
+    pub fn record<T>(&mut self, latency: Duration, ret: &Result<T, String>) {
+        self.latency.record(latency.as_micros() as u64);
+        if let Err(e) = ret {
+            self.record_error(ErrorKind::classify(e));
+        }
+    }
+
+    pub fn record_error(&mut self, kind: ErrorKind) {
+        let i = ErrorKind::ALL.iter().position(|k| *k == kind).unwrap();
+        self.errors[i] += 1;
+    }
+
+    pub fn merge(&mut self, other: &OpStats) {
+        self.latency.merge(&other.latency);
+        for (e, o) in self.errors.iter_mut().zip(other.errors.iter()) {
+            *e += *o;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.latency.count()
+    }
+
+    pub fn total_errors(&self) -> u64 {
+        self.errors.iter().sum()
+    }
+}
+
+/// Counts finished operations, and samples the count every `interval` in a
+/// background thread.
+pub struct ThroughputMeter {
+    done: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    interval: Duration,
+    handle: JoinHandle<Vec<u64>>,
+}
+
+impl ThroughputMeter {
+    pub fn start(interval: Duration) -> Self {
+        let done = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (d, s) = (done.clone(), stop.clone());
+        let handle = thread::spawn(move || {
+            let mut windows = vec![];
+            let mut last = 0;
+            let mut next = Instant::now() + interval;
+            while !s.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now < next {
+                    thread::sleep((next - now).min(Duration::from_millis(10)));
+                    continue;
+                }
+                let current = d.load(Ordering::SeqCst);
+                windows.push(current - last);
+                last = current;
+                next += interval;
+            }
+            windows
+        });
+        Self {
+            done,
+            stop,
+            interval,
+            handle,
+        }
+    }
+
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.done.clone()
+    }
+
+    /// Stop sampling and return the number of operations finished in each full window.
+    pub fn finish(self) -> (Duration, Vec<u64>) {
+        self.stop.store(true, Ordering::SeqCst);
+        (self.interval, self.handle.join().unwrap())
+    }
+}
+
+pub struct Report {
+    pub name: String,
+    pub threads: usize,
+    pub elapsed: Duration,
+    pub ops: Vec<(&'static str, OpStats)>,
+    pub interval: Duration,
+    // Operations finished in each window of `interval`.
+    pub windows: Vec<u64>,
+}
+
+impl Report {
+    pub fn total_ops(&self) -> u64 {
+        self.ops.iter().map(|(_, s)| s.count()).sum()
+    }
+
+    pub fn print(&self) {
+        let secs = duration_secs(self.elapsed);
+        println!(
+            "[OVERALL] workload: {}, threads: {}, runtime: {:.3} s, throughput: {:.1} ops/sec",
+            self.name,
+            self.threads,
+            secs,
+            self.total_ops() as f64 / secs
+        );
+        for (name, stats) in self.ops.iter() {
+            if stats.count() == 0 {
+                continue;
+            }
+            let errors: Vec<String> = ErrorKind::ALL
+                .iter()
+                .zip(stats.errors.iter())
+                .map(|(k, n)| format!("{}: {}", k.name(), n))
+                .collect();
+            let h = &stats.latency;
+            println!(
+                "[{}] ops: {}, errors: {} ({}), throughput: {:.1} ops/sec, avg: {:.1} us, p50: {} us, p95: {} us, p99: {} us, p999: {} us, max: {} us",
+                name,
+                stats.count(),
+                stats.total_errors(),
+                errors.join(", "),
+                stats.count() as f64 / secs,
+                h.mean(),
+                h.percentile(50.0),
+                h.percentile(95.0),
+                h.percentile(99.0),
+                h.percentile(99.9),
+                h.max()
+            );
+        }
+        let window_secs = duration_secs(self.interval);
+        for (i, n) in self.windows.iter().enumerate() {
+            println!(
+                "[WINDOW] {:.1}-{:.1} s: {:.1} ops/sec",
+                window_secs * i as f64,
+                window_secs * (i + 1) as f64,
+                *n as f64 / window_secs
+            );
+        }
+    }
+}
+
+pub fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentile() {
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(99.0), 0);
+        for v in 1..=10000 {
+            h.record(v);
+        }
+        assert_eq!(h.count(), 10000);
+        assert_eq!(h.min(), 1);
+        assert_eq!(h.max(), 10000);
+        for p in [50.0, 95.0, 99.0, 99.9].iter() {
+            let expected = (*p / 100.0 * 10000.0) as u64;
+            let actual = h.percentile(*p);
+            assert!(actual >= expected && actual <= expected + expected / 16, "p{}: {}", p, actual);
+        }
+        assert_eq!(h.percentile(100.0), 10000);
+
+        let mut other = Histogram::default();
+        other.record(u64::MAX);
+        h.merge(&other);
+        assert_eq!(h.max(), u64::MAX);
+        assert_eq!(h.percentile(100.0), u64::MAX);
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        let mut values: Vec<u64> = (0..20).collect();
+        for s in 5..64 {
+            values.push((1u64 << s) - 1);
+            values.push(1u64 << s);
+        }
+        let mut last = 0;
+        for v in values {
+            let i = bucket_index(v);
+            assert!(i >= last && i < BUCKETS);
+            assert!(bucket_value(i) >= v, "{}", v);
+            last = i;
+        }
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+}
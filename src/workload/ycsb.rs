@@ -0,0 +1,472 @@
+///
+/// YCSB core workloads A-F.
+///
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::u64;
+
+use rand::{thread_rng, Rng};
+
+use super::super::mvcc::MvccStorage;
+use super::generator::{KeyChooser, ScrambledZipfianGenerator, ZipfianGenerator};
+use super::stats::{OpStats, Report, ThroughputMeter, REPORT_INTERVAL};
+use super::TimestampOracle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    Uniform,
+    Zipfian,
+    Latest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 5] = [
+        Operation::Read,
+        Operation::Update,
+        Operation::Insert,
+        Operation::Scan,
+        Operation::ReadModifyWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Read => "READ",
+            Operation::Update => "UPDATE",
+            Operation::Insert => "INSERT",
+            Operation::Scan => "SCAN",
+            Operation::ReadModifyWrite => "READ-MODIFY-WRITE",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Operation::Read => 0,
+            Operation::Update => 1,
+            Operation::Insert => 2,
+            Operation::Scan => 3,
+            Operation::ReadModifyWrite => 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CoreWorkload {
+    pub name: String,
+    pub record_count: u64,
+    pub operation_count: u64,
+    pub field_count: usize,
+    pub field_length: usize,
+    pub read_proportion: f64,
+    pub update_proportion: f64,
+    pub insert_proportion: f64,
+    pub scan_proportion: f64,
+    pub read_modify_write_proportion: f64,
+    pub max_scan_length: u64,
+    // Keys read by a read operation, more than one are read by a single `batch_get`.
+    pub batch_read_size: usize,
+    pub request_distribution: Distribution,
+    // Stop before `operation_count` operations are done once the time is up.
+    pub max_execution_time: Option<Duration>,
+}
+
+impl Default for CoreWorkload {
+    fn default() -> Self {
+        Self {
+            name: String::from("core"),
+            record_count: 1000,
+            operation_count: 1000,
+            field_count: 10,
+            field_length: 100,
+            read_proportion: 0.95,
+            update_proportion: 0.05,
+            insert_proportion: 0.0,
+            scan_proportion: 0.0,
+            read_modify_write_proportion: 0.0,
+            max_scan_length: 1000,
+            batch_read_size: 1,
+            request_distribution: Distribution::Zipfian,
+            max_execution_time: None,
+        }
+    }
+}
+
+impl CoreWorkload {
+    /// Update heavy: 50% reads, 50% updates.
+    pub fn workload_a() -> Self {
+        Self {
+            name: String::from("a"),
+            read_proportion: 0.5,
+            update_proportion: 0.5,
+            ..Default::default()
+        }
+    }
+
+    /// Read mostly: 95% reads, 5% updates.
+    pub fn workload_b() -> Self {
+        Self {
+            name: String::from("b"),
+            read_proportion: 0.95,
+            update_proportion: 0.05,
+            ..Default::default()
+        }
+    }
+
+    /// Read only.
+    pub fn workload_c() -> Self {
+        Self {
+            name: String::from("c"),
+            read_proportion: 1.0,
+            update_proportion: 0.0,
+            ..Default::default()
+        }
+    }
+
+    /// Read latest: 95% reads of recently inserted records, 5% inserts.
+    pub fn workload_d() -> Self {
+        Self {
+            name: String::from("d"),
+            read_proportion: 0.95,
+            update_proportion: 0.0,
+            insert_proportion: 0.05,
+            request_distribution: Distribution::Latest,
+            ..Default::default()
+        }
+    }
+
+    /// Short ranges: 95% scans, 5% inserts.
+    pub fn workload_e() -> Self {
+        Self {
+            name: String::from("e"),
+            read_proportion: 0.0,
+            update_proportion: 0.0,
+            insert_proportion: 0.05,
+            scan_proportion: 0.95,
+            max_scan_length: 100,
+            ..Default::default()
+        }
+    }
+
+    /// Read-modify-write: 50% reads, 50% read-modify-writes.
+    pub fn workload_f() -> Self {
+        Self {
+            name: String::from("f"),
+            read_proportion: 0.5,
+            update_proportion: 0.0,
+            read_modify_write_proportion: 0.5,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "a" => Some(Self::workload_a()),
+            "b" => Some(Self::workload_b()),
+            "c" => Some(Self::workload_c()),
+            "d" => Some(Self::workload_d()),
+            "e" => Some(Self::workload_e()),
+            "f" => Some(Self::workload_f()),
+            _ => None,
+        }
+    }
+
+    /// The parameters of the workload, as saved with the benchmark results.
+    pub fn params(&self) -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert("record_count".to_owned(), self.record_count.to_string());
+        params.insert("operation_count".to_owned(), self.operation_count.to_string());
+        params.insert("field_count".to_owned(), self.field_count.to_string());
+        params.insert("field_length".to_owned(), self.field_length.to_string());
+        params.insert("read_proportion".to_owned(), self.read_proportion.to_string());
+        params.insert("update_proportion".to_owned(), self.update_proportion.to_string());
+        params.insert("insert_proportion".to_owned(), self.insert_proportion.to_string());
+        params.insert("scan_proportion".to_owned(), self.scan_proportion.to_string());
+        params.insert("read_modify_write_proportion".to_owned(), self.read_modify_write_proportion.to_string());
+        params.insert("max_scan_length".to_owned(), self.max_scan_length.to_string());
+        params.insert("batch_read_size".to_owned(), self.batch_read_size.to_string());
+        params.insert("request_distribution".to_owned(), format!("{:?}", self.request_distribution).to_lowercase());
+        if let Some(d) = self.max_execution_time {
+            params.insert("max_execution_time".to_owned(), d.as_secs().to_string());
+        }
+        params
+    }
+
+    /// The storages have no columns, so a record is a single value holding all of its
+    /// fields, and an update rewrites the whole record instead of one field.
+    pub fn value_size(&self) -> usize {
+        self.field_count * self.field_length
+    }
+
+    fn key_chooser(&self) -> KeyChooser {
+        match self.request_distribution {
+            Distribution::Uniform => KeyChooser::Uniform(u64::MAX),
+            Distribution::Zipfian => {
+                KeyChooser::Zipfian(ScrambledZipfianGenerator::new(0, self.record_count.max(1)))
+            }
+            Distribution::Latest => KeyChooser::Latest(ZipfianGenerator::new(0, self.record_count.max(1))),
+        }
+    }
+
+    fn choose_operation<R: Rng>(&self, rng: &mut R) -> Operation {
+        let total = self.read_proportion
+            + self.update_proportion
+            + self.insert_proportion
+            + self.scan_proportion
+            + self.read_modify_write_proportion;
+        let mut p = rng.gen::<f64>() * total;
+        let proportions = [
+            (Operation::Read, self.read_proportion),
+            (Operation::Update, self.update_proportion),
+            (Operation::Insert, self.insert_proportion),
+            (Operation::Scan, self.scan_proportion),
+            (Operation::ReadModifyWrite, self.read_modify_write_proportion),
+        ];
+        for (op, proportion) in proportions.iter() {
+            if p < *proportion {
+                return *op;
+            }
+            p -= *proportion;
+        }
+        Operation::Read
+    }
+}
+
+/// Keys are inserted in order, so that a scan of `n` records starting at `keynum`
+/// ends right before `build_key(keynum + n)`.
+pub fn build_key(keynum: u64) -> Vec<u8> {
+    format!("user{:020}", keynum).into_bytes()
+}
+
+fn write_key(storage: &Arc<dyn MvccStorage>, tso: &TimestampOracle, key: &[u8], value: &[u8]) -> Result<(), String> {
+    let start_ts = tso.get_ts();
+    storage.prewrite(key, value, start_ts)?;
+    commit_key(storage, key, start_ts, tso.get_ts())
+}
+
+// A lock left by a failed commit is rolled back, or it fails every later read of the
+// key in the run. The rollback fails too if the key was committed after all.
+fn commit_key(storage: &Arc<dyn MvccStorage>, key: &[u8], start_ts: u64, commit_ts: u64) -> Result<(), String> {
+    let ret = storage.commit(key, start_ts, commit_ts);
+    if ret.is_err() {
+        let _ = storage.rollback(key, start_ts);
+    }
+    ret
+}
+
+// Records whose existence is checked by one `batch_get` when loading.
+const LOAD_CHECK_BATCH: u64 = 1000;
+
+/// Insert `record_count` records, split among `threads` workers. Records which already
+/// exist are kept, so that a loaded dataset can be reused. Returns the number of
+/// records written.
+pub fn load(storage: &Arc<dyn MvccStorage>, workload: &CoreWorkload, threads: usize, tso: &Arc<TimestampOracle>) -> Result<u64, String> {
+    let threads = threads.max(1) as u64;
+    let per_thread = workload.record_count / threads;
+    let mut handlers = Vec::new();
+    for i in 0..threads {
+        let begin = i * per_thread;
+        let end = if i + 1 == threads {
+            workload.record_count
+        } else {
+            begin + per_thread
+        };
+        let store = storage.clone();
+        let tso = tso.clone();
+        let value = vec![b'x'; workload.value_size()];
+        let handle = thread::spawn(move || -> Result<u64, String> {
+            let mut written = 0;
+            let mut batch_begin = begin;
+            while batch_begin < end {
+                let batch_end = (batch_begin + LOAD_CHECK_BATCH).min(end);
+                let keys: Vec<Vec<u8>> = (batch_begin..batch_end).map(build_key).collect();
+                let key_refs: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+                let existing = store.batch_get(&key_refs, tso.get_ts())?;
+                for ((keynum, key), ret) in (batch_begin..batch_end).zip(keys.iter()).zip(existing) {
+                    if ret.map_err(|e| format!("check record {} failed: {}", keynum, e))?.is_some() {
+                        continue;
+                    }
+                    write_key(&store, &tso, key, &value)
+                        .map_err(|e| format!("load record {} failed: {}", keynum, e))?;
+                    written += 1;
+                }
+                batch_begin = batch_end;
+            }
+            Ok(written)
+        });
+        handlers.push(handle);
+    }
+    let mut written = 0;
+    for h in handlers.into_iter() {
+        written += h.join().map_err(|_| String::from("load thread panicked"))??;
+    }
+    Ok(written)
+}
+
+struct Worker {
+    storage: Arc<dyn MvccStorage>,
+    workload: CoreWorkload,
+    tso: Arc<TimestampOracle>,
+    insert_key: Arc<AtomicU64>,
+    done: Arc<AtomicU64>,
+    chooser: KeyChooser,
+    value: Vec<u8>,
+}
+
+impl Worker {
+    fn next_keynum<R: Rng>(&self, rng: &mut R) -> u64 {
+        let max = self.insert_key.load(Ordering::SeqCst);
+        self.chooser.next(rng, max)
+    }
+
+    fn do_operation<R: Rng>(&self, op: Operation, rng: &mut R) -> Result<(), String> {
+        match op {
+            Operation::Read if self.workload.batch_read_size > 1 => {
+                let keys: Vec<Vec<u8>> = (0..self.workload.batch_read_size)
+                    .map(|_| build_key(self.next_keynum(rng)))
+                    .collect();
+                let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+                for ret in self.storage.batch_get(&keys, self.tso.get_ts())? {
+                    ret?;
+                }
+                Ok(())
+            }
+            Operation::Read => {
+                let key = build_key(self.next_keynum(rng));
+                self.storage.get(&key, self.tso.get_ts())?;
+                Ok(())
+            }
+            Operation::Update => {
+                let key = build_key(self.next_keynum(rng));
+                write_key(&self.storage, &self.tso, &key, &self.value)
+            }
+            Operation::Insert => {
+                let keynum = self.insert_key.fetch_add(1, Ordering::SeqCst);
+                write_key(&self.storage, &self.tso, &build_key(keynum), &self.value)
+            }
+            Operation::Scan => {
+                let keynum = self.next_keynum(rng);
+                let len = rng.gen_range(1, self.workload.max_scan_length.max(1) + 1);
+                let start = build_key(keynum);
+                let end = build_key(keynum + len);
+                self.storage.scan(&start, &end, self.tso.get_ts())?;
+                Ok(())
+            }
+            Operation::ReadModifyWrite => {
+                let key = build_key(self.next_keynum(rng));
+                let start_ts = self.tso.get_ts();
+                self.storage.get(&key, start_ts)?;
+                self.storage.prewrite(&key, &self.value, start_ts)?;
+                commit_key(&self.storage, &key, start_ts, self.tso.get_ts())
+            }
+        }
+    }
+
+    fn run(&self, operation_count: u64, begin: Instant) -> Vec<OpStats> {
+        let mut rng = thread_rng();
+        let mut stats = vec![OpStats::default(); Operation::ALL.len()];
+        for _ in 0..operation_count {
+            if self.workload.max_execution_time.map_or(false, |d| begin.elapsed() >= d) {
+                break;
+            }
+            let op = self.workload.choose_operation(&mut rng);
+            let begin = Instant::now();
+            let ret = self.do_operation(op, &mut rng);
+            stats[op.index()].record(begin.elapsed(), &ret);
+            self.done.fetch_add(1, Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
+/// Run `operation_count` operations of the workload, split among `threads` workers.
+/// The run ends early once `max_execution_time` has elapsed.
+pub fn run(storage: &Arc<dyn MvccStorage>, workload: &CoreWorkload, threads: usize, tso: &Arc<TimestampOracle>) -> Report {
+    let threads = threads.max(1);
+    let insert_key = Arc::new(AtomicU64::new(workload.record_count));
+    let per_thread = workload.operation_count / threads as u64;
+    let meter = ThroughputMeter::start(REPORT_INTERVAL);
+    let begin = Instant::now();
+    let mut handlers = Vec::new();
+    for i in 0..threads {
+        let worker = Worker {
+            storage: storage.clone(),
+            workload: workload.clone(),
+            tso: tso.clone(),
+            insert_key: insert_key.clone(),
+            done: meter.counter(),
+            chooser: workload.key_chooser(),
+            value: vec![b'y'; workload.value_size()],
+        };
+        let count = if i + 1 == threads {
+            workload.operation_count - per_thread * (threads as u64 - 1)
+        } else {
+            per_thread
+        };
+        handlers.push(thread::spawn(move || worker.run(count, begin)));
+    }
+    let mut total = vec![OpStats::default(); Operation::ALL.len()];
+    for h in handlers.into_iter() {
+        let stats = h.join().unwrap();
+        for (t, s) in total.iter_mut().zip(stats.iter()) {
+            t.merge(s);
+        }
+    }
+    let elapsed = begin.elapsed();
+    let (interval, windows) = meter.finish();
+    Report {
+        name: workload.name.clone(),
+        threads,
+        elapsed,
+        ops: Operation::ALL.iter().map(|op| op.name()).zip(total.into_iter()).collect(),
+        interval,
+        windows,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::mvcc::storage::create_storage;
+    use super::super::super::mvcc::StorageType;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_existing_records() {
+        let path = TempDir::new("_ycsb_load").expect("");
+        let storage = create_storage(path.path().to_str().unwrap(), StorageType::TiKVStorage).unwrap();
+        let tso = Arc::new(TimestampOracle::new(0));
+        // Records written before the load keep their value.
+        for keynum in [3, 25].iter() {
+            write_key(&storage, &tso, &build_key(*keynum), b"old").unwrap();
+        }
+        let workload = CoreWorkload {
+            record_count: 20,
+            ..CoreWorkload::workload_a()
+        };
+        assert_eq!(load(&storage, &workload, 2, &tso).unwrap(), 19);
+        assert_eq!(load(&storage, &workload, 2, &tso).unwrap(), 0);
+        let more = CoreWorkload {
+            record_count: 30,
+            ..workload
+        };
+        assert_eq!(load(&storage, &more, 2, &tso).unwrap(), 9);
+        let value = vec![b'x'; more.value_size()];
+        for keynum in 0..30 {
+            let expected = if keynum == 3 || keynum == 25 { b"old".to_vec() } else { value.clone() };
+            let v = storage.get(&build_key(keynum), tso.get_ts()).unwrap().unwrap();
+            assert_eq!(v, expected, "record {}", keynum);
+        }
+    }
+}